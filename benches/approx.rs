@@ -0,0 +1,41 @@
+//! Times filtering a noisy image with and without `Options::approximate`.
+//!
+//! Run with `cargo bench --bench approx`.
+use image::{ImageBuffer, Rgba};
+use pixel_filter::filter::{run_with_options, Options};
+use std::time::{Duration, Instant};
+
+const SIZE: u32 = 512;
+const RUNS: u32 = 5;
+
+fn time(img: &ImageBuffer<Rgba<u8>, Vec<u8>>, options: &Options) -> Duration {
+    // the first run also builds the fixed-point tables
+    run_with_options(img, options).unwrap();
+    let start = Instant::now();
+    for _ in 0..RUNS {
+        run_with_options(img, options).unwrap();
+    }
+    start.elapsed() / RUNS
+}
+
+fn main() {
+    let mut state = 0x2545_f491_u32;
+    let img = ImageBuffer::from_fn(SIZE, SIZE, |_, _| {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        let [r, g, b, _] = state.to_le_bytes();
+        Rgba([r, g, b, 255])
+    });
+    let exact = time(&img, &Options::default());
+    let approximate = time(
+        &img,
+        &Options {
+            approximate: true,
+            ..Options::default()
+        },
+    );
+    println!("{SIZE}x{SIZE} image, mean of {RUNS} runs");
+    println!("exact:       {exact:?}");
+    println!("approximate: {approximate:?}");
+}
@@ -0,0 +1,391 @@
+use crate::filter::{
+    effective_palette, hex_to_rgb8, palette_as_oklab, run_with_options, IndexedImage, Options,
+    MAX_COLOR_DITHER,
+};
+use image::{
+    error::{ParameterError, ParameterErrorKind},
+    imageops::{self, FilterType},
+    ImageBuffer, ImageError, Rgba,
+};
+use palette::{IntoColor, Oklab, Srgb};
+use std::{collections::HashMap, ops::Range};
+
+/// `(palette index, pixel count)` pairs, most used first.
+pub type Usage = Vec<(usize, usize)>;
+
+/// Mean Oklab lightness difference between neighboring pixels, a rough
+/// measure of how noisy (high) or smooth (low) an image is.
+pub fn mean_gradient(img: &ImageBuffer<Rgba<u8>, Vec<u8>>) -> f32 {
+    let lightness = |x, y| {
+        let [r, g, b, _] = img.get_pixel(x, y).0;
+        let oklab: Oklab = Srgb::new(r, g, b).into_format::<f32>().into_color();
+        oklab.l
+    };
+
+    let mut sum = 0.0;
+    let mut count = 0;
+    for y in 0..img.height() {
+        for x in 0..img.width() {
+            let l = lightness(x, y);
+            if x + 1 < img.width() {
+                sum += (lightness(x + 1, y) - l).abs();
+                count += 1;
+            }
+            if y + 1 < img.height() {
+                sum += (lightness(x, y + 1) - l).abs();
+                count += 1;
+            }
+        }
+    }
+
+    if count == 0 {
+        0.0
+    } else {
+        sum / count as f32
+    }
+}
+
+/// Suggest a Bayer order for [`bayer_matrix`](crate::filter::bayer_matrix).
+///
+/// Larger and smoother images get larger maps, whose finer tonal steps pay
+/// off on gradients; small or noisy images get smaller ones. This is only a
+/// heuristic.
+pub fn suggest_bayer_order(img: &ImageBuffer<Rgba<u8>, Vec<u8>>) -> u32 {
+    let order: u32 = match img.width().min(img.height()) {
+        0..=31 => 1,
+        32..=127 => 2,
+        _ => 3,
+    };
+
+    let gradient = mean_gradient(img);
+    if gradient < 0.01 {
+        (order + 1).min(4)
+    } else if gradient > 0.1 {
+        (order - 1).max(1)
+    } else {
+        order
+    }
+}
+
+/// How often each palette index is used. Fully transparent pixels aren't
+/// counted, since their color is invisible.
+pub fn palette_usage(indexed: &IndexedImage) -> Usage {
+    usage(indexed, 0..indexed.width, 0..indexed.height)
+}
+
+/// How many visible pixels of a finished output have each palette color, as
+/// written by the filter, and how many have none of them, such as after
+/// blending or with preserved colors. Pixels of a color listed more than once
+/// count toward its first index.
+pub fn output_usage(
+    output: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    palette_hex: &[&str],
+) -> (Vec<usize>, usize) {
+    let mut indices: HashMap<[u8; 3], usize> = HashMap::new();
+    for (index, hex) in effective_palette(palette_hex).iter().enumerate() {
+        indices.entry(hex_to_rgb8(hex).unwrap()).or_insert(index);
+    }
+    let (mut counts, mut other) = (vec![0; palette_hex.len()], 0);
+    for pixel in output.pixels() {
+        let [r, g, b, a] = pixel.0;
+        if a == 0 {
+            continue;
+        }
+        match indices.get(&[r, g, b]) {
+            Some(&index) => counts[index] += 1,
+            None => other += 1,
+        }
+    }
+    (counts, other)
+}
+
+/// [`palette_usage`] of each `tile_size`×`tile_size` tile of an atlas, as rows
+/// of tiles.
+pub fn tile_usage(indexed: &IndexedImage, tile_size: u32) -> Vec<Vec<Usage>> {
+    let tile_size = tile_size.max(1);
+    (0..indexed.height)
+        .step_by(tile_size as usize)
+        .map(|tile_y| {
+            (0..indexed.width)
+                .step_by(tile_size as usize)
+                .map(|tile_x| {
+                    usage(
+                        indexed,
+                        tile_x..(tile_x + tile_size).min(indexed.width),
+                        tile_y..(tile_y + tile_size).min(indexed.height),
+                    )
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Mean and largest Oklab distance between the colors of an image and its
+/// filtered version, over the pixels visible in the original.
+pub fn color_error(
+    original: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    filtered: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+) -> Result<(f32, f32), ImageError> {
+    if original.dimensions() != filtered.dimensions() {
+        return Err(ImageError::Parameter(ParameterError::from_kind(
+            ParameterErrorKind::DimensionMismatch,
+        )));
+    }
+
+    let oklab = |pixel: &Rgba<u8>| -> Oklab {
+        let [r, g, b, _] = pixel.0;
+        Srgb::new(r, g, b).into_format::<f32>().into_color()
+    };
+    let (mut sum, mut max, mut count) = (0.0, 0.0f32, 0);
+    for (a, b) in original.pixels().zip(filtered.pixels()) {
+        if a.0[3] == 0 {
+            continue;
+        }
+        let (a, b) = (oklab(a), oklab(b));
+        let distance = ((a.l - b.l).powi(2) + (a.a - b.a).powi(2) + (a.b - b.b).powi(2)).sqrt();
+        sum += distance;
+        max = max.max(distance);
+        count += 1;
+    }
+    Ok((if count == 0 { 0.0 } else { sum / count as f32 }, max))
+}
+
+/// Largest width or height [`auto_dither`] measures at; larger images are
+/// downscaled to a proxy first.
+pub const AUTO_DITHER_PROXY_SIZE: u32 = 256;
+/// Halvings of the search range in [`auto_dither`].
+const AUTO_DITHER_STEPS: usize = 12;
+
+/// The `color_dither` from 0 to [`MAX_COLOR_DITHER`] whose output has a mean
+/// [`color_error`] closest to `target_error`, found by binary search, with
+/// the other options as given.
+///
+/// More dithering picks colors farther from each pixel, so the error grows
+/// with it, and the error without dithering is the lowest reachable. Images
+/// larger than [`AUTO_DITHER_PROXY_SIZE`] are measured on a downscaled copy,
+/// without `dither_map` and `wrap`, which are tied to the full size.
+pub fn auto_dither(
+    img: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    options: &Options,
+    target_error: f32,
+) -> Result<f32, ImageError> {
+    let longest = img.width().max(img.height());
+    let mut options = options.clone();
+    let proxy = if longest > AUTO_DITHER_PROXY_SIZE {
+        let scale = |side: u32| {
+            ((side as u64 * AUTO_DITHER_PROXY_SIZE as u64 / longest as u64) as u32).max(1)
+        };
+        options.dither_map = None;
+        options.wrap = false;
+        imageops::resize(
+            img,
+            scale(img.width()),
+            scale(img.height()),
+            FilterType::Triangle,
+        )
+    } else {
+        img.clone()
+    };
+
+    let mut error_at = |color_dither: f32| {
+        options.color_dither = color_dither;
+        Ok::<_, ImageError>(color_error(&proxy, &run_with_options(&proxy, &options)?)?.0)
+    };
+    let (mut low, mut high) = (0.0, MAX_COLOR_DITHER);
+    if error_at(low)? >= target_error {
+        return Ok(low);
+    }
+    if error_at(high)? <= target_error {
+        return Ok(high);
+    }
+    for _ in 0..AUTO_DITHER_STEPS {
+        let middle = (low + high) / 2.0;
+        if error_at(middle)? < target_error {
+            low = middle;
+        } else {
+            high = middle;
+        }
+    }
+    Ok((low + high) / 2.0)
+}
+
+/// Gaps larger than this many times the even step are flagged as uneven.
+pub const UNEVEN_GAP_FACTOR: f32 = 1.5;
+
+/// Step in Oklab lightness between two palette colors that are next to each
+/// other in lightness.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LightnessGap {
+    /// Palette index of the darker color.
+    pub darker: usize,
+    /// Palette index of the lighter color.
+    pub lighter: usize,
+    pub size: f32,
+    /// Whether the gap is more than [`UNEVEN_GAP_FACTOR`] times the step of
+    /// a palette with evenly spread lightness, so that gradients would show
+    /// a visible jump there and adding a color in between would help.
+    pub uneven: bool,
+}
+
+/// Lightness gaps between the palette colors sorted by lightness, largest
+/// first.
+pub fn lightness_gaps(palette_hex: &[&str]) -> Vec<LightnessGap> {
+    let colors = palette_as_oklab(palette_hex);
+    let mut order: Vec<usize> = (0..colors.len()).collect();
+    order.sort_by(|&i, &j| colors[i].l.total_cmp(&colors[j].l));
+    if order.len() < 2 {
+        return vec![];
+    }
+
+    let range = colors[order[order.len() - 1]].l - colors[order[0]].l;
+    let even_step = range / (order.len() - 1) as f32;
+    let mut gaps: Vec<LightnessGap> = order
+        .windows(2)
+        .map(|pair| {
+            let size = colors[pair[1]].l - colors[pair[0]].l;
+            LightnessGap {
+                darker: pair[0],
+                lighter: pair[1],
+                size,
+                uneven: size > UNEVEN_GAP_FACTOR * even_step,
+            }
+        })
+        .collect();
+    gaps.sort_by(|a, b| b.size.total_cmp(&a.size));
+    gaps
+}
+
+fn usage(indexed: &IndexedImage, xs: Range<u32>, ys: Range<u32>) -> Usage {
+    let mut counts: HashMap<usize, usize> = HashMap::new();
+    for y in ys {
+        for x in xs.clone() {
+            let i = (y * indexed.width + x) as usize;
+            if indexed.alpha[i] != 0 {
+                *counts.entry(indexed.indices[i]).or_default() += 1;
+            }
+        }
+    }
+
+    let mut counts: Usage = counts.into_iter().collect();
+    counts.sort_by(|(i1, c1), (i2, c2)| c2.cmp(c1).then(i1.cmp(i2)));
+    counts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn checkerboard(size: u32) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+        ImageBuffer::from_fn(size, size, |x, y| {
+            let c = if (x + y) % 2 == 0 { 0 } else { 255 };
+            Rgba([c, c, c, 255])
+        })
+    }
+
+    #[test]
+    fn mean_gradient_of_flat_and_noisy_images() {
+        let flat = ImageBuffer::from_pixel(8, 8, Rgba([90, 90, 90, 255]));
+        assert_eq!(mean_gradient(&flat), 0.0);
+        assert_eq!(mean_gradient(&ImageBuffer::new(1, 1)), 0.0);
+        assert!((mean_gradient(&checkerboard(8)) - 1.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn bayer_order_grows_with_size_and_smoothness() {
+        let flat = |size| ImageBuffer::from_pixel(size, size, Rgba([90, 90, 90, 255]));
+        assert_eq!(suggest_bayer_order(&flat(16)), 2);
+        assert_eq!(suggest_bayer_order(&flat(64)), 3);
+        assert_eq!(suggest_bayer_order(&flat(256)), 4);
+        // noise needs no fine tonal steps
+        assert_eq!(suggest_bayer_order(&checkerboard(16)), 1);
+        assert_eq!(suggest_bayer_order(&checkerboard(256)), 2);
+    }
+
+    fn indexed(width: u32, indices: &[usize], alpha: &[u8]) -> IndexedImage {
+        IndexedImage {
+            width,
+            height: indices.len() as u32 / width,
+            indices: indices.to_vec(),
+            alpha: alpha.to_vec(),
+        }
+    }
+
+    #[test]
+    fn usage_counts_visible_pixels_most_used_first() {
+        let indexed = indexed(3, &[2, 0, 2, 1, 1, 1], &[255, 255, 255, 255, 0, 255]);
+        assert_eq!(palette_usage(&indexed), [(1, 2), (2, 2), (0, 1)]);
+    }
+
+    #[test]
+    fn tile_usage_splits_into_partial_tiles() {
+        let indexed = indexed(3, &[0, 0, 1, 0, 0, 1], &[255; 6]);
+        assert_eq!(tile_usage(&indexed, 2), [vec![vec![(0, 4)], vec![(1, 2)]]]);
+    }
+
+    #[test]
+    fn lightness_gaps_flag_a_big_jump() {
+        // a dark cluster and white, with a big jump in between
+        let gaps = lightness_gaps(&["ffffff", "000000", "202020", "101010"]);
+        assert_eq!(gaps.len(), 3);
+        assert_eq!((gaps[0].darker, gaps[0].lighter), (2, 0));
+        assert!(gaps[0].uneven);
+        assert!(gaps[1..].iter().all(|gap| !gap.uneven));
+        assert!(gaps.windows(2).all(|pair| pair[0].size >= pair[1].size));
+
+        let even = lightness_gaps(&["000000", "777777", "ffffff", "3b3b3b", "b9b9b9"]);
+        assert!(even.iter().all(|gap| !gap.uneven), "{:?}", even);
+        assert!(lightness_gaps(&["123456"]).is_empty());
+    }
+
+    #[test]
+    fn output_usage_counts_the_colors_written() {
+        let output = ImageBuffer::from_fn(3, 2, |x, y| match (x, y) {
+            (0, _) => Rgba([255, 255, 255, 255]),
+            (1, 0) => Rgba([0, 0, 0, 255]),
+            // invisible, and a blended color
+            (1, 1) => Rgba([0, 0, 0, 0]),
+            _ => Rgba([128, 128, 128, 255]),
+        });
+        let (counts, other) = output_usage(&output, &["000000", "ffffff", "000000"]);
+        assert_eq!(counts, [1, 2, 0]);
+        assert_eq!(other, 2);
+    }
+
+    #[test]
+    fn auto_dither_reaches_the_target_error() {
+        let img = ImageBuffer::from_fn(32, 32, |x, y| Rgba([x as u8 * 8, y as u8 * 8, 96, 255]));
+        let options = Options {
+            palette_hex: &crate::filter::PALETTE_HEX_2,
+            ..Default::default()
+        };
+        let error_at = |color_dither| {
+            let options = Options {
+                color_dither,
+                ..options.clone()
+            };
+            color_error(&img, &run_with_options(&img, &options).unwrap())
+                .unwrap()
+                .0
+        };
+        let (lowest, highest) = (error_at(0.0), error_at(MAX_COLOR_DITHER));
+        assert!(lowest < highest);
+
+        let target = (lowest + highest) / 2.0;
+        let color_dither = auto_dither(&img, &options, target).unwrap();
+        assert!(color_dither > 0.0 && color_dither < MAX_COLOR_DITHER);
+        let error = error_at(color_dither);
+        assert!(
+            (error - target).abs() < (highest - lowest) / 10.0,
+            "{} for {}",
+            error,
+            target
+        );
+
+        // targets out of reach give the ends of the range
+        assert_eq!(auto_dither(&img, &options, lowest / 2.0).unwrap(), 0.0);
+        assert_eq!(
+            auto_dither(&img, &options, highest * 2.0).unwrap(),
+            MAX_COLOR_DITHER
+        );
+    }
+}
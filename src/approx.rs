@@ -0,0 +1,225 @@
+//! Fixed-point approximation of sRGB to Oklab conversion and distance.
+//!
+//! The gamma curve and cube root come from tables built once, and the rest is
+//! integer arithmetic, so converting a pixel needs no float math. Measured
+//! against the float conversion over all 2^24 sRGB colors, every component
+//! is within 0.0017 of the exact value for colors with L of at least 0.1,
+//! and linear interpolation of the cube root near zero lets darker colors be
+//! off by up to 0.037. The closest of the 48 default palette colors then
+//! differs from the float match for 4806 of those colors, about 1 in 3500.
+use palette::Oklab;
+use std::sync::OnceLock;
+
+/// Fractional bits of linear sRGB and LMS values.
+const VALUE_BITS: u32 = 16;
+/// Fractional bits of the matrix coefficients.
+const COEFFICIENT_BITS: u32 = 14;
+/// Input bits dropped by the cube root table, which interpolates between
+/// its entries.
+const CBRT_STEP_BITS: u32 = 4;
+
+const ONE: i64 = 1 << VALUE_BITS;
+
+/// Linear sRGB to LMS.
+const M1: [[f64; 3]; 3] = [
+    [0.4122214708, 0.5363325363, 0.0514459929],
+    [0.2119034982, 0.6806995451, 0.1073969566],
+    [0.0883024619, 0.2817188376, 0.6299787005],
+];
+
+/// Cube rooted LMS to Oklab.
+const M2: [[f64; 3]; 3] = [
+    [0.2104542553, 0.7936177850, -0.0040720468],
+    [1.9779984951, -2.4285922050, 0.4505937099],
+    [0.0259040371, 0.7827717662, -0.8086757660],
+];
+
+struct Tables {
+    linear: [i64; 256],
+    cbrt: Vec<i64>,
+    m1: [[i64; 3]; 3],
+    m2: [[i64; 3]; 3],
+}
+
+fn tables() -> &'static Tables {
+    static TABLES: OnceLock<Tables> = OnceLock::new();
+    TABLES.get_or_init(|| {
+        let fixed = |value: f64, bits: u32| (value * (1u64 << bits) as f64).round() as i64;
+        let mut linear = [0; 256];
+        for (value, entry) in linear.iter_mut().enumerate() {
+            let encoded = value as f64 / 255.0;
+            let decoded = if encoded <= 0.04045 {
+                encoded / 12.92
+            } else {
+                ((encoded + 0.055) / 1.055).powf(2.4)
+            };
+            *entry = fixed(decoded, VALUE_BITS);
+        }
+        let steps = (ONE >> CBRT_STEP_BITS) as usize;
+        let cbrt = (0..=steps)
+            .map(|step| fixed((step as f64 / steps as f64).cbrt(), VALUE_BITS))
+            .collect();
+        let matrix = |m: [[f64; 3]; 3]| m.map(|row| row.map(|c| fixed(c, COEFFICIENT_BITS)));
+        Tables {
+            linear,
+            cbrt,
+            m1: matrix(M1),
+            m2: matrix(M2),
+        }
+    })
+}
+
+fn multiply(m: &[[i64; 3]; 3], v: [i64; 3]) -> [i64; 3] {
+    m.map(|row| (row[0] * v[0] + row[1] * v[1] + row[2] * v[2]) >> COEFFICIENT_BITS)
+}
+
+/// Oklab color with components in units of 2^-16.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FixedOklab(pub [i64; 3]);
+
+impl FixedOklab {
+    pub fn from_srgb([r, g, b]: [u8; 3]) -> Self {
+        let tables = tables();
+        let linear = [r, g, b].map(|c| tables.linear[c as usize]);
+        let lms = multiply(&tables.m1, linear).map(|c| {
+            let c = c.clamp(0, ONE);
+            let (step, fraction) = (
+                (c >> CBRT_STEP_BITS) as usize,
+                c & ((1 << CBRT_STEP_BITS) - 1),
+            );
+            let (low, high) = (
+                tables.cbrt[step],
+                tables.cbrt[(step + 1).min(tables.cbrt.len() - 1)],
+            );
+            low + (((high - low) * fraction) >> CBRT_STEP_BITS)
+        });
+        Self(multiply(&tables.m2, lms))
+    }
+
+    pub fn from_oklab(color: Oklab) -> Self {
+        Self([color.l, color.a, color.b].map(|c| (c as f64 * ONE as f64).round() as i64))
+    }
+
+    pub fn to_oklab(self) -> Oklab {
+        let [l, a, b] = self.0.map(|c| c as f32 / ONE as f32);
+        Oklab::new(l, a, b)
+    }
+
+    /// Lightness as a float.
+    pub fn lightness(self) -> f32 {
+        self.0[0] as f32 / ONE as f32
+    }
+
+    /// Components multiplied by `factor`, given in units of 2^-16.
+    pub fn scale(self, factor: i64) -> Self {
+        Self(self.0.map(|c| (c * factor) >> VALUE_BITS))
+    }
+
+    /// Squared Euclidean distance, in units of 2^-32.
+    pub fn distance_squared(self, other: Self) -> i64 {
+        self.0
+            .iter()
+            .zip(&other.0)
+            .map(|(a, b)| (a - b).pow(2))
+            .sum()
+    }
+}
+
+/// Converts a float to units of 2^-16, as taken by [`FixedOklab::scale`].
+pub fn to_fixed(value: f32) -> i64 {
+    (value as f64 * ONE as f64).round() as i64
+}
+
+impl std::ops::Add for FixedOklab {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self([0, 1, 2].map(|i| self.0[i] + other.0[i]))
+    }
+}
+
+impl std::ops::Sub for FixedOklab {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self {
+        Self([0, 1, 2].map(|i| self.0[i] - other.0[i]))
+    }
+}
+
+impl std::ops::Div<i64> for FixedOklab {
+    type Output = Self;
+
+    fn div(self, divisor: i64) -> Self {
+        Self(self.0.map(|c| c / divisor))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::{hex_to_rgb8, PALETTE_HEX};
+    use palette::{IntoColor, Srgb};
+
+    fn exact(rgb: [u8; 3]) -> Oklab {
+        Srgb::from(rgb).into_format::<f32>().into_color()
+    }
+
+    /// Every fifth value of each channel, plus 255.
+    fn sampled_colors() -> impl Iterator<Item = [u8; 3]> {
+        let values = || (0..=255u8).step_by(5);
+        values().flat_map(move |r| values().flat_map(move |g| values().map(move |b| [r, g, b])))
+    }
+
+    #[test]
+    fn conversion_stays_within_the_stated_error() {
+        for rgb in sampled_colors() {
+            let (exact, approx) = (exact(rgb), FixedOklab::from_srgb(rgb).to_oklab());
+            let error = [exact.l - approx.l, exact.a - approx.a, exact.b - approx.b]
+                .map(f32::abs)
+                .into_iter()
+                .fold(0.0, f32::max);
+            let bound = if exact.l >= 0.1 { 0.0017 } else { 0.037 };
+            assert!(error <= bound, "{rgb:?} is off by {error}");
+        }
+    }
+
+    #[test]
+    fn closest_palette_colors_mostly_agree() {
+        let palette: Vec<Oklab> = PALETTE_HEX
+            .iter()
+            .map(|hex| exact(hex_to_rgb8(hex).unwrap()))
+            .collect();
+        let fixed: Vec<FixedOklab> = palette.iter().map(|&c| FixedOklab::from_oklab(c)).collect();
+        let (mut total, mut differ) = (0, 0);
+        for rgb in sampled_colors() {
+            let color = exact(rgb);
+            let distance = |c: Oklab| {
+                (c.l - color.l).powi(2) + (c.a - color.a).powi(2) + (c.b - color.b).powi(2)
+            };
+            let float_closest = (0..palette.len())
+                .min_by(|&i, &j| distance(palette[i]).total_cmp(&distance(palette[j])))
+                .unwrap();
+            let approx = FixedOklab::from_srgb(rgb);
+            let fixed_closest = (0..fixed.len())
+                .min_by_key(|&i| approx.distance_squared(fixed[i]))
+                .unwrap();
+            total += 1;
+            differ += (float_closest != fixed_closest) as usize;
+        }
+        assert!(differ * 1000 < total, "{differ} of {total} colors differ");
+    }
+
+    #[test]
+    fn arithmetic_matches_the_float_components() {
+        let (a, b) = (
+            Oklab::new(0.5, -0.125, 0.25),
+            Oklab::new(0.25, 0.0625, -0.0625),
+        );
+        let (fa, fb) = (FixedOklab::from_oklab(a), FixedOklab::from_oklab(b));
+        assert_eq!(fa + fb, FixedOklab::from_oklab(a + b));
+        assert_eq!(fa - fb, FixedOklab::from_oklab(a - b));
+        assert_eq!(fa.scale(to_fixed(0.5)), FixedOklab::from_oklab(a * 0.5));
+        assert_eq!((fa + fa) / 2, fa);
+        assert_eq!(fa.lightness(), 0.5);
+    }
+}
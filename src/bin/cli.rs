@@ -1,13 +1,2298 @@
-use anyhow::Result;
-use image::io::Reader;
-use pixel_filter::filter::*;
+use anyhow::{bail, Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use clap::{parser::ValueSource, CommandFactory, FromArgMatches, Parser, ValueEnum};
+use image::{
+    codecs::{
+        gif::{GifEncoder, Repeat},
+        png::{CompressionType, FilterType as PngFilterType, PngEncoder},
+        webp::WebPEncoder,
+    },
+    imageops::{self, FilterType},
+    io::Reader,
+    ColorType, Delay, DynamicImage, Frame, ImageBuffer, ImageEncoder, ImageFormat, Rgba,
+};
+use palette::{Oklab, Srgb};
+use pixel_filter::{
+    analysis::{auto_dither, color_error, lightness_gaps, output_usage, suggest_bayer_order},
+    blend::{blend, composite_over, BackgroundFit},
+    diff::{diff, CHANGED_COLOR},
+    export::{
+        export_lut, indexed_to_4bpp, indexed_to_csv, indexed_to_oklab, indexed_to_rust,
+        lut_to_cube, render_swatches, write_indexed_png, write_png_with_metadata,
+    },
+    extract::{
+        extend_shared_palette, generate_shared_palette_kmeans, generate_shared_palette_median_cut,
+        Quantizer,
+    },
+    filter::*,
+    ico::{decode_ico, encode_ico},
+    preprocess::{
+        apply_alpha_mask, block_average, crop_to_aspect, stretch_lightness, subsample_chroma,
+        tone_map, ToneMap,
+    },
+    sheet::pack_sheet,
+    style::{Style, STYLES},
+};
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    fs::{create_dir_all, read, read_dir, read_to_string, write, File},
+    io::{stdin, stdout, BufWriter, Cursor, Read, Write},
+    path::{Path, PathBuf},
+};
+
+/// Crate features compiled into this build.
+const FEATURES: &[&str] = &[
+    #[cfg(feature = "exr")]
+    "exr",
+    #[cfg(feature = "webp-lossy")]
+    "webp-lossy",
+    #[cfg(feature = "logging")]
+    "logging",
+];
+
+/// How long each frame of --animate is shown.
+const ANIMATION_FRAME_MS: u32 = 100;
 
 const INPUT_PATH: &str = "images/test.png";
 const OUTPUT_PATH: &str = "images/output.png";
 
+#[derive(Parser)]
+#[command(
+    about = "Filter an image with a palette and ordered dithering",
+    disable_version_flag = true
+)]
+struct Args {
+    /// Print the version and the compiled-in features
+    #[arg(long)]
+    version: bool,
+
+    /// Record the version and features in a text chunk of PNG outputs
+    #[arg(long)]
+    tag: bool,
+
+    /// Record this resolution in dots per inch in a pHYs chunk of PNG outputs, for printing
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    dpi: Option<u32>,
+
+    /// Image to filter
+    #[arg(default_value = INPUT_PATH)]
+    input: PathBuf,
+
+    /// Where to save the filtered image
+    #[arg(default_value = OUTPUT_PATH)]
+    output: PathBuf,
+
+    /// Read the palette and dither settings from this TOML file; flags take precedence
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+
+    /// Start from a named style: a built-in one such as gameboy, c64 or pico8,
+    /// or one under [styles.NAME] in the config; the config and flags take precedence
+    #[arg(long, value_name = "NAME")]
+    style: Option<String>,
+
+    /// Filter each of these images, or of the images in these directories, instead of INPUT
+    #[arg(long, value_name = "IMAGE", num_args = 1..)]
+    batch: Vec<PathBuf>,
+
+    /// Directory to save the images filtered with --batch into
+    #[arg(long, value_name = "DIR")]
+    out_dir: Option<PathBuf>,
+
+    /// Name the images saved into --out-dir after this template, with {name}
+    /// and {ext} of the input, {palette} for the style or config name and
+    /// {index} for the input's position, such as `{name}_filtered.{ext}`
+    #[arg(long, value_name = "TEMPLATE", requires = "out_dir")]
+    name_template: Option<String>,
+
+    /// Use this grayscale image as the alpha of the input, replacing its own
+    #[arg(long, value_name = "PATH")]
+    alpha_mask: Option<PathBuf>,
+
+    /// Filter this many times, halving the color dither every pass
+    #[arg(long, value_name = "N", default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    passes: u32,
+
+    /// Dither with white noise instead of the threshold map
+    #[arg(long)]
+    noise: bool,
+
+    /// Seed of the --noise dither, 0 by default; the same seed always gives
+    /// the same output
+    #[arg(long, value_name = "N", requires = "noise")]
+    seed: Option<u64>,
+
+    /// Stretch the lightness of the input to span the palette's lightness range
+    #[arg(long)]
+    stretch_lightness: bool,
+
+    /// Scale color dithering per pixel by this grayscale image, from none at black to full at white
+    #[arg(long, value_name = "PATH")]
+    dither_map: Option<PathBuf>,
+
+    /// Dither alpha to look right over this background color (hex), rather than to match alpha values
+    #[arg(long, value_name = "HEX")]
+    alpha_matte: Option<String>,
+
+    /// Only quantize pixels with an Oklch hue in this range, such as 350:10 for reds
+    #[arg(long, value_name = "FROM:TO", value_parser = parse_hue_range)]
+    hue_range: Option<(f32, f32)>,
+
+    /// Only quantize pixels within TOLERANCE of the HEX key color, such as
+    /// 00ff00:0.2 for a green screen, and leave the rest untouched
+    #[arg(long, value_name = "HEX:TOLERANCE", value_parser = parse_chroma_key)]
+    chroma_key: Option<(Srgb, f32)>,
+
+    /// Darken the first half of every PERIOD rows for a CRT look
+    #[arg(long, value_name = "PERIOD", value_parser = clap::value_parser!(u32).range(2..))]
+    scanlines: Option<u32>,
+
+    /// Oklab lightness taken off the rows darkened by --scanlines
+    #[arg(long, value_name = "L", default_value_t = 0.1)]
+    scanline_strength: f32,
+
+    /// Fail unless the dither pattern repeats evenly, so the output tiles seamlessly
+    #[arg(long)]
+    wrap: bool,
+
+    /// Compress lightness within WIDTH of the palette's darkest and lightest colors
+    #[arg(long, value_name = "WIDTH")]
+    soft_knee: Option<f32>,
+
+    /// Match pixels darker than Oklab LIGHTNESS only to palette colors with
+    /// at most CHROMA, such as 0.25:0.05, to keep saturated specks out of shadows
+    #[arg(long, value_name = "LIGHTNESS:CHROMA", value_parser = parse_shadow_chroma)]
+    shadow_chroma: Option<(f32, f32)>,
+
+    /// Ink edges stronger than THRESHOLD in the darkest palette color, where
+    /// 1 is a hard step from black to white
+    #[arg(long, value_name = "THRESHOLD")]
+    ink_edges: Option<f32>,
+
+    /// Rotate the hue of the input by this many degrees before matching
+    #[arg(
+        long,
+        value_name = "DEGREES",
+        default_value_t = 0.0,
+        allow_negative_numbers = true
+    )]
+    hue_rotate: f32,
+
+    /// Write fully transparent pixels as transparent black without filtering them
+    #[arg(long)]
+    skip_transparent: bool,
+
+    /// Spread candidate colors around the pixel's color instead of leaning toward the closest
+    #[arg(long)]
+    centered_error: bool,
+
+    /// Generate K candidate colors per pixel instead of one per threshold map
+    /// entry, for finer tones from small maps
+    #[arg(long, value_name = "K", value_parser = clap::value_parser!(u64).range(1..))]
+    candidates: Option<u64>,
+
+    /// Match against palette colors within MARGIN of the closest one to some
+    /// image color first, to speed up large palettes without changing the output
+    #[arg(long, value_name = "MARGIN")]
+    palette_subset: Option<f32>,
+
+    /// Color dither from 0 for none to 100, on a perceptual curve; overrides the config's color_dither
+    #[arg(long, value_name = "0-100")]
+    dither_strength: Option<f32>,
+
+    /// Make pixels matched to this palette index fully transparent
+    #[arg(long, value_name = "INDEX")]
+    transparent_index: Option<usize>,
+
+    /// Which palette color wins when several are equally close
+    #[arg(long, value_enum, default_value_t = TieBreak::default())]
+    tie_break: TieBreak,
+
+    /// Multiply output colors by alpha
+    #[arg(long)]
+    premultiplied: bool,
+
+    /// Use faster fixed-point Oklab conversion and matching, slightly off in
+    /// very dark colors
+    #[arg(long)]
+    approximate: bool,
+
+    /// Reduce the image to N colors with median cut, then map those to the
+    /// palette without dithering
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    two_stage: Option<u32>,
+
+    /// How threshold maps are tiled across the image
+    #[arg(long, value_enum, default_value_t = Lattice::default())]
+    lattice: Lattice,
+
+    /// Scale color dithering by alpha, so transparent pixels add no noise
+    #[arg(long)]
+    alpha_weighted_error: bool,
+
+    /// Threshold index alpha is dithered with: `aligned` with color,
+    /// `complement` for the inverted pattern, or a number to shift it by
+    #[arg(long, value_name = "PATTERN", default_value = "aligned", value_parser = parse_alpha_pattern)]
+    alpha_pattern: AlphaPattern,
+
+    /// Save an indexed PNG with the palette embedded in order, for Aseprite
+    #[arg(long, conflicts_with_all = ["diff", "sheet", "format", "rotate", "flip_h", "flip_v"])]
+    indexed: bool,
+
+    /// Dither to pure black and white and save a grayscale image of 0 and
+    /// 255, for e-ink or thermal printers
+    #[arg(long, conflicts_with_all = ["indexed", "diff", "sheet", "format", "rotate", "flip_h", "flip_v"])]
+    bilevel: bool,
+
+    /// Also save the filtered image as Rust `const` arrays of palette indices
+    #[arg(long, value_name = "PATH", conflicts_with = "batch")]
+    rust_out: Option<PathBuf>,
+
+    /// Also save the palette, usage per index, color error and the filtered
+    /// image as base64 PNG in one JSON object
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["batch", "diff", "rotate", "flip_h", "flip_v"])]
+    json_bundle: Option<PathBuf>,
+
+    /// Also save the Oklab color of each pixel's palette entry as floats, to
+    /// an EXR with L, a and b in R, G and B if the path ends in .exr, or else
+    /// as raw little-endian f32 triples row by row
+    #[arg(long, value_name = "PATH", conflicts_with = "batch")]
+    oklab_out: Option<PathBuf>,
+
+    /// Also save the palette indices of the filtered image packed two per
+    /// byte, for palettes of up to 16 colors
+    #[arg(long, value_name = "PATH", conflicts_with = "batch")]
+    packed_4bpp: Option<PathBuf>,
+
+    /// Pad each row of --packed-4bpp to a multiple of this many bytes
+    #[arg(long, value_name = "BYTES", default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+    row_align: u64,
+
+    /// Also save the closest palette color of every input color as a 3D LUT,
+    /// as a .cube file if the path ends in .cube or else as raw RGB bytes
+    #[arg(long, value_name = "PATH")]
+    lut: Option<PathBuf>,
+
+    /// Steps per channel of --lut
+    #[arg(long, value_name = "N", default_value_t = 32, value_parser = clap::value_parser!(u32).range(2..=256))]
+    lut_size: u32,
+
+    /// Also save the palette indices of the filtered image as CSV
+    #[arg(long, value_name = "PATH", conflicts_with = "batch")]
+    csv: Option<PathBuf>,
+
+    /// Composite the filtered image over this background image, as for a mockup
+    #[arg(long, value_name = "PATH")]
+    background: Option<PathBuf>,
+
+    /// How --background is fit to the size of the image
+    #[arg(long, value_enum, default_value_t = BackgroundFit::default())]
+    background_fit: BackgroundFit,
+
+    /// Mix the filtered image with the original, from 0 (original) to 1 (filtered)
+    #[arg(long, value_name = "AMOUNT")]
+    blend: Option<f32>,
+
+    /// Save the output at each of these integer scales, such as 1,2,4
+    #[arg(
+        long,
+        value_name = "SCALES",
+        value_delimiter = ',',
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    scales: Vec<u32>,
+
+    /// Rotate the output clockwise, before any flips
+    #[arg(long, value_name = "DEGREES")]
+    rotate: Option<Rotation>,
+
+    /// Flip the output horizontally
+    #[arg(long)]
+    flip_h: bool,
+
+    /// Flip the output vertically, after any horizontal flip
+    #[arg(long)]
+    flip_v: bool,
+
+    /// Pack the filtered images into a sprite sheet with COLUMNS cells per row
+    #[arg(long, num_args = 2, value_names = ["COLUMNS", "OUTPUT"])]
+    sheet: Option<Vec<String>>,
+
+    /// Transparent pixels between the cells of --sheet
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    sheet_padding: u32,
+
+    /// Generate one palette of N colors from all inputs and filter with it
+    #[arg(long, value_name = "N")]
+    shared_palette: Option<usize>,
+
+    /// Algorithm generating --shared-palette
+    #[arg(long, value_enum, default_value_t = Quantizer::default())]
+    quantizer: Quantizer,
+
+    /// Fail instead of clamping when a palette color leaves sRGB after the
+    /// round trip through Oklab
+    #[arg(long)]
+    strict: bool,
+
+    /// Print the palette as written to the output, one hex code per index
+    #[arg(long)]
+    print_palette: bool,
+
+    /// Print the lightness gaps between palette colors, largest first,
+    /// flagging uneven ones where adding a color would smooth gradients
+    #[arg(long)]
+    equalize_report: bool,
+
+    /// Also save the palette as labeled swatches to this path
+    #[arg(long, value_name = "PATH")]
+    swatches: Option<PathBuf>,
+
+    /// Append up to N colors covering what the palette misses in the inputs
+    #[arg(long, value_name = "N")]
+    extend_palette: Option<usize>,
+
+    /// Leave out colors closer than DISTANCE in Oklab to another when
+    /// generating a palette with k-means or extending one
+    #[arg(long, value_name = "DISTANCE", default_value_t = 0.0)]
+    min_separation: f32,
+
+    /// Print the input color, palette index and palette color chosen at this
+    /// pixel instead of saving anything
+    #[arg(
+        long,
+        value_name = "X,Y",
+        value_parser = parse_coordinates,
+        conflicts_with_all = ["batch", "sheet", "animate", "compare_palettes"]
+    )]
+    query: Option<(u32, u32)>,
+
+    /// Save a looping GIF of N frames instead, stepping the threshold phase
+    /// each frame so every pixel cycles through its candidate colors
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(u64).range(1..),
+        conflicts_with_all = ["batch", "diff", "sheet", "indexed", "bilevel", "compare_palettes"]
+    )]
+    animate: Option<u64>,
+
+    /// Filter with the palettes of these two config files instead, save the
+    /// pixels that differ between the results and print how many there are
+    #[arg(
+        long,
+        num_args = 2,
+        value_names = ["A", "B"],
+        conflicts_with_all = ["batch", "diff", "sheet", "indexed", "bilevel"]
+    )]
+    compare_palettes: Vec<PathBuf>,
+
+    /// Save a diff between the filtered image and this image instead
+    #[arg(long, value_name = "IMAGE")]
+    diff: Option<PathBuf>,
+
+    /// Read raw RGBA bytes of the given size from stdin and write raw RGBA to
+    /// stdout, without the options that change the size or write files
+    #[arg(
+        long,
+        value_name = "WxH",
+        value_parser = parse_dimensions,
+        conflicts_with_all = [
+            "batch", "out_dir", "aspect", "rotate", "flip_h", "flip_v", "format", "lossless",
+            "quality", "compression", "tag", "dpi", "scales", "diff", "sheet", "indexed",
+            "animate", "query", "compare_palettes", "json_bundle", "rust_out", "csv",
+            "oklab_out", "packed_4bpp", "swatches", "lut", "print_palette", "equalize_report",
+            "shared_palette", "extend_palette",
+        ]
+    )]
+    raw: Option<(u32, u32)>,
+
+    /// How pixels are dithered to the palette
+    #[arg(long, value_enum, default_value_t = DitherMode::default())]
+    mode: DitherMode,
+
+    /// Color space in which palette distances are measured
+    #[arg(long, value_enum, default_value_t = MatchSpace::default())]
+    match_space: MatchSpace,
+
+    /// Center-crop the input to this aspect ratio before filtering
+    #[arg(long, value_name = "W:H", value_parser = parse_ratio)]
+    aspect: Option<(u32, u32)>,
+
+    /// Average each NxN block of the input before filtering
+    #[arg(long, value_name = "N")]
+    pixelate: Option<u32>,
+
+    /// Average the chroma of each 2x2 block of the input before filtering,
+    /// keeping the lightness of every pixel
+    #[arg(long)]
+    chroma_subsample: bool,
+
+    /// Use a Bayer threshold map of size 2^ORDER
+    #[arg(
+        long,
+        value_name = "ORDER",
+        conflicts_with = "auto_bayer",
+        value_parser = clap::value_parser!(u32).range(0..=4)
+    )]
+    bayer: Option<u32>,
+
+    /// Pick the Bayer order from the image size and smoothness
+    #[arg(long)]
+    auto_bayer: bool,
+
+    /// Pick the color dither for each image whose output has this mean Oklab
+    /// error from the input
+    #[arg(long, value_name = "ERROR", conflicts_with = "dither_strength")]
+    target_error: Option<f32>,
+
+    /// Make each threshold map cell cover NxN pixels
+    #[arg(long, value_name = "N", default_value_t = 1)]
+    dither_scale: u32,
+
+    /// Only dither pixels farther than this from their closest palette color
+    #[arg(long, value_name = "DISTANCE")]
+    snap_threshold: Option<f32>,
+
+    /// Use the closest palette color when the dithered one is farther by more than this
+    #[arg(long, value_name = "DISTANCE")]
+    snap_if_better: Option<f32>,
+
+    /// Make pixels transparent when their closest palette color is farther than this
+    #[arg(long, value_name = "DISTANCE")]
+    drop_beyond: Option<f32>,
+
+    /// Keep the source alpha instead of dithering it
+    #[arg(long)]
+    preserve_alpha: bool,
+
+    /// Recolor to the closest palette colors without dithering, keeping alpha
+    #[arg(long)]
+    remap: bool,
+
+    /// Quantize the given channels to N levels instead of matching the palette,
+    /// for data such as normal maps
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(2..))]
+    levels: Option<u32>,
+
+    /// Channels quantized by --levels, any of `r`, `g` and `b`
+    #[arg(long, value_name = "CHANNELS", default_value = "rgb", value_parser = parse_channels)]
+    level_channels: [bool; 3],
+
+    /// Channels dithered between the levels of --levels, any of `r`, `g` and `b`
+    #[arg(long, value_name = "CHANNELS", default_value = "rgb", value_parser = parse_channels)]
+    level_dither: [bool; 3],
+
+    /// Don't dither between the levels of --levels
+    #[arg(long, conflicts_with = "level_dither")]
+    no_level_dither: bool,
+
+    /// Copy pixels of this exact color through without quantizing them
+    #[arg(long = "preserve", value_name = "HEX")]
+    preserve_colors: Vec<String>,
+
+    /// How float inputs such as EXR are brought into range before filtering
+    #[arg(long, value_enum, default_value_t = ToneMap::default())]
+    tone_map: ToneMap,
+
+    /// Format of the output image, guessed from its extension by default
+    #[arg(long, value_enum)]
+    format: Option<OutputFormat>,
+
+    /// Encode WebP losslessly, which is also the default without --quality
+    #[arg(long, conflicts_with = "quality")]
+    lossless: bool,
+
+    /// Encode lossy WebP with the given quality from 0 to 100
+    #[arg(long, value_parser = clap::value_parser!(u8).range(0..=100))]
+    quality: Option<u8>,
+
+    /// PNG compression level, trading file size for speed
+    #[arg(long, value_enum)]
+    compression: Option<Compression>,
+}
+
+/// Filter settings read with --config. Everything is optional, and flags
+/// given on the command line take precedence.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Config {
+    palette: Option<Vec<String>>,
+    /// Palette as Oklab `[l, a, b]` triples, matched without rounding to hex.
+    palette_oklab: Option<Vec<[f32; 3]>>,
+    color_dither: Option<f32>,
+    alpha_dither: Option<f32>,
+    threshold_map: Option<Vec<Vec<usize>>>,
+    bayer: Option<u32>,
+    mode: Option<DitherMode>,
+    match_space: Option<MatchSpace>,
+    dither_scale: Option<u32>,
+    /// Named styles for --style, with the same settings as the top level.
+    styles: BTreeMap<String, Config>,
+}
+
+impl Config {
+    /// Parse and check a config file.
+    fn load(path: &Path) -> Result<Config> {
+        let config: Config = toml::from_str(&read_to_string(path)?)
+            .with_context(|| format!("invalid config `{}`", path.display()))?;
+        config
+            .check()
+            .with_context(|| format!("invalid config `{}`", path.display()))?;
+        Ok(config)
+    }
+
+    /// Fail on palettes that can't be used, here or in any style, naming
+    /// the bad entry.
+    fn check(&self) -> Result<()> {
+        if let Some(palette) = &self.palette {
+            let palette_hex: Vec<&str> = palette.iter().map(|s| s.as_str()).collect();
+            check_palette_alpha(&palette_hex)?;
+            check_palette(&palette_hex)?;
+        }
+        if self
+            .palette_oklab
+            .as_ref()
+            .is_some_and(|colors| colors.is_empty())
+        {
+            bail!("empty palette_oklab");
+        }
+        for (name, style) in &self.styles {
+            style
+                .check()
+                .with_context(|| format!("invalid style `{}`", name))?;
+        }
+        Ok(())
+    }
+
+    /// These settings, falling back to `base` for the ones not given.
+    fn or(self, base: Config) -> Config {
+        let map_given = self.threshold_map.is_some() || self.bayer.is_some();
+        let palette_given = self.palette.is_some() || self.palette_oklab.is_some();
+        Config {
+            palette: if palette_given {
+                self.palette
+            } else {
+                base.palette
+            },
+            palette_oklab: if palette_given {
+                self.palette_oklab
+            } else {
+                base.palette_oklab
+            },
+            color_dither: self.color_dither.or(base.color_dither),
+            alpha_dither: self.alpha_dither.or(base.alpha_dither),
+            threshold_map: if map_given {
+                self.threshold_map
+            } else {
+                base.threshold_map
+            },
+            bayer: if map_given { self.bayer } else { base.bayer },
+            mode: self.mode.or(base.mode),
+            match_space: self.match_space.or(base.match_space),
+            dither_scale: self.dither_scale.or(base.dither_scale),
+            styles: self.styles,
+        }
+    }
+}
+
+impl From<&Style> for Config {
+    fn from(style: &Style) -> Self {
+        Config {
+            palette: Some(style.palette_hex.iter().map(|s| s.to_string()).collect()),
+            color_dither: Some(style.color_dither),
+            alpha_dither: Some(style.alpha_dither),
+            bayer: Some(style.bayer),
+            mode: Some(style.dither_mode),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum OutputFormat {
+    Png,
+    Webp,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Compression {
+    Fast,
+    Default,
+    Best,
+}
+
+impl From<Compression> for CompressionType {
+    fn from(compression: Compression) -> Self {
+        match compression {
+            Compression::Fast => CompressionType::Fast,
+            Compression::Default => CompressionType::Default,
+            Compression::Best => CompressionType::Best,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Rotation {
+    #[value(name = "90")]
+    Quarter,
+    #[value(name = "180")]
+    Half,
+    #[value(name = "270")]
+    ThreeQuarters,
+}
+
+fn parse_dimensions(s: &str) -> Result<(u32, u32), String> {
+    let (width, height) = s
+        .split_once('x')
+        .ok_or_else(|| format!("expected WxH, got `{}`", s))?;
+    let width = width
+        .parse()
+        .map_err(|_| format!("invalid width `{}`", width))?;
+    let height = height
+        .parse()
+        .map_err(|_| format!("invalid height `{}`", height))?;
+    Ok((width, height))
+}
+
+/// Seed of the noise dither, if `--noise` is given.
+fn noise_seed(args: &Args) -> Option<u64> {
+    args.noise.then(|| args.seed.unwrap_or(0))
+}
+
+fn parse_coordinates(s: &str) -> Result<(u32, u32), String> {
+    let (x, y) = s
+        .split_once(',')
+        .ok_or_else(|| format!("expected X,Y, got `{}`", s))?;
+    let x = x.parse().map_err(|_| format!("invalid x `{}`", x))?;
+    let y = y.parse().map_err(|_| format!("invalid y `{}`", y))?;
+    Ok((x, y))
+}
+
+fn parse_ratio(s: &str) -> Result<(u32, u32), String> {
+    let (width, height) = s
+        .split_once(':')
+        .ok_or_else(|| format!("expected W:H, got `{}`", s))?;
+    let parse = |n: &str| match n.parse() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(format!("invalid ratio term `{}`", n)),
+    };
+    Ok((parse(width)?, parse(height)?))
+}
+
+fn parse_chroma_key(s: &str) -> Result<(Srgb, f32), String> {
+    let (hex, tolerance) = s
+        .split_once(':')
+        .ok_or_else(|| format!("expected HEX:TOLERANCE, got `{}`", s))?;
+    let key: Srgb<u8> = hex
+        .parse()
+        .map_err(|_| format!("invalid hex color `{}`", hex))?;
+    let tolerance = tolerance
+        .parse()
+        .map_err(|_| format!("invalid tolerance `{}`", tolerance))?;
+    Ok((key.into_format(), tolerance))
+}
+
+fn parse_alpha_pattern(s: &str) -> Result<AlphaPattern, String> {
+    match s {
+        "aligned" => Ok(AlphaPattern::Aligned),
+        "complement" => Ok(AlphaPattern::Complement),
+        _ => s.parse().map(AlphaPattern::Shifted).map_err(|_| {
+            format!(
+                "expected `aligned`, `complement` or a number to shift by, got `{}`",
+                s
+            )
+        }),
+    }
+}
+
+fn parse_shadow_chroma(s: &str) -> Result<(f32, f32), String> {
+    let (lightness, chroma) = s
+        .split_once(':')
+        .ok_or_else(|| format!("expected LIGHTNESS:CHROMA, got `{}`", s))?;
+    let parse = |value: &str| {
+        value
+            .parse()
+            .map_err(|_| format!("invalid number `{}`", value))
+    };
+    Ok((parse(lightness)?, parse(chroma)?))
+}
+
+fn parse_hue_range(s: &str) -> Result<(f32, f32), String> {
+    let (start, end) = s
+        .split_once(':')
+        .ok_or_else(|| format!("expected FROM:TO, got `{}`", s))?;
+    let parse = |angle: &str| {
+        angle
+            .parse()
+            .map_err(|_| format!("invalid angle `{}`", angle))
+    };
+    Ok((parse(start)?, parse(end)?))
+}
+
+fn parse_channels(s: &str) -> Result<[bool; 3], String> {
+    let mut channels = [false; 3];
+    for c in s.chars() {
+        match c {
+            'r' => channels[0] = true,
+            'g' => channels[1] = true,
+            'b' => channels[2] = true,
+            _ => return Err(format!("unknown channel `{}`", c)),
+        }
+    }
+    Ok(channels)
+}
+
 fn main() -> Result<()> {
-    let img = Reader::open(INPUT_PATH)?.decode()?;
-    let output_buffer = run(img.as_rgba8().unwrap())?;
-    output_buffer.save(OUTPUT_PATH)?;
+    #[cfg(all(feature = "logging", not(target_arch = "wasm32")))]
+    env_logger::init();
+    let matches = Args::command().get_matches();
+    let args = Args::from_arg_matches(&matches)?;
+    if args.version {
+        println!("{}", version());
+        return Ok(());
+    }
+    let from_flag = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+    let mut config = match &args.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    if let Some(name) = &args.style {
+        let style = match config.styles.remove(name) {
+            Some(style) => style,
+            None => match Style::find(name) {
+                Some(style) => style.into(),
+                None => {
+                    let names: Vec<&str> = STYLES.iter().map(|style| style.name).collect();
+                    bail!(
+                        "unknown style `{}`, expected one in the config or {}",
+                        name,
+                        names.join(", ")
+                    );
+                }
+            },
+        };
+        config = config.or(style);
+    }
+    if config.palette.is_some() && config.palette_oklab.is_some() {
+        bail!("the config can give palette or palette_oklab, not both");
+    }
+    let palette_oklab: Option<Vec<Oklab>> = config.palette_oklab.as_ref().map(|colors| {
+        colors
+            .iter()
+            .map(|&[l, a, b]| Oklab::new(l, a, b))
+            .collect()
+    });
+    // exports and reports take hex codes, so the Oklab palette also gets
+    // the ones the filter writes
+    let palette_oklab_hex = palette_oklab.as_deref().map(oklab_palette_hex);
+    let config_palette: Vec<&str> = config
+        .palette
+        .iter()
+        .chain(&palette_oklab_hex)
+        .flatten()
+        .map(|s| s.as_str())
+        .collect();
+    let preserve_colors: Vec<&str> = args.preserve_colors.iter().map(|s| s.as_str()).collect();
+    let dither_map = match &args.dither_map {
+        Some(path) => Some(Reader::open(path)?.decode()?.to_luma8()),
+        None => None,
+    };
+    let mut options = Options {
+        dither_mode: args.mode,
+        match_space: args.match_space,
+        snap_threshold: args.snap_threshold,
+        preserve_colors: &preserve_colors,
+        dither_scale: args.dither_scale,
+        snap_if_better: args.snap_if_better,
+        drop_beyond: args.drop_beyond,
+        preserve_alpha: args.preserve_alpha || args.remap,
+        channel_levels: args.levels.map(|levels| ChannelLevels {
+            channels: args.level_channels,
+            levels,
+            dither: if args.no_level_dither {
+                [false; 3]
+            } else {
+                args.level_dither
+            },
+        }),
+        alpha_weighted_error: args.alpha_weighted_error,
+        alpha_pattern: args.alpha_pattern,
+        noise_seed: noise_seed(&args),
+        skip_transparent: args.skip_transparent,
+        hue_rotate: args.hue_rotate,
+        soft_knee: args.soft_knee,
+        shadow_chroma: args.shadow_chroma,
+        ink_edges: args.ink_edges,
+        dither_map: dither_map.as_ref(),
+        wrap: args.wrap,
+        hue_range: args.hue_range,
+        chroma_key: args.chroma_key,
+        alpha_matte: args.alpha_matte.as_deref(),
+        centered_error: args.centered_error,
+        lattice: args.lattice,
+        palette_subset: args.palette_subset,
+        transparent_index: args.transparent_index,
+        tie_break: args.tie_break,
+        approximate: args.approximate,
+        candidates: args.candidates.map(|k| k as usize),
+        output_premultiplied: args.premultiplied,
+        scanlines: args.scanlines.map(|period| ScanlinePattern {
+            period,
+            strength: args.scanline_strength,
+        }),
+        ..Default::default()
+    };
+    if !config_palette.is_empty() {
+        options.palette_hex = &config_palette;
+    }
+    options.palette_oklab = palette_oklab.as_deref();
+    check_palette_alpha(options.palette_hex)?;
+    apply_config(&mut options, &config, &args, &from_flag)?;
+    if args.bilevel {
+        options.palette_hex = &BILEVEL_PALETTE;
+        options.palette_oklab = None;
+    }
+
+    if let Some((width, height)) = args.raw {
+        check_gamut(&options, args.strict)?;
+        pipe_raw(stdin(), stdout(), width, height, &options, &args)?;
+        return Ok(());
+    }
+
+    let inputs = if args.batch.is_empty() {
+        vec![args.input.clone()]
+    } else {
+        expand_directories(&args.batch)?
+    };
+    // every size of an icon is filtered and packed back into one icon
+    let is_ico = |path: &Path| ImageFormat::from_path(path).ok() == Some(ImageFormat::Ico);
+    let ico = args.batch.is_empty() && is_ico(&args.input) && is_ico(&args.output);
+    if ico && (args.indexed || args.bilevel || args.sheet.is_some() || args.alpha_mask.is_some()) {
+        bail!("ICO to ICO filtering can't be combined with --indexed, --bilevel, --sheet or --alpha-mask");
+    }
+    let mut images = if ico {
+        decode_ico(&read(&args.input)?)?
+            .into_iter()
+            .map(|img| prepare(img, &args))
+            .collect()
+    } else {
+        inputs
+            .iter()
+            .map(|path| load(path, &args))
+            .collect::<Result<Vec<_>>>()?
+    };
+
+    let shared_palette: Vec<String>;
+    let shared_palette_hex: Vec<&str>;
+    if let Some(n) = args.shared_palette {
+        shared_palette = match args.quantizer {
+            Quantizer::Kmeans => generate_shared_palette_kmeans(&images, n, args.min_separation),
+            Quantizer::MedianCut => generate_shared_palette_median_cut(&images, n),
+        };
+        shared_palette_hex = shared_palette.iter().map(|s| s.as_str()).collect();
+        options.palette_hex = &shared_palette_hex;
+        options.palette_oklab = None;
+    }
+
+    let extended_palette: Vec<String>;
+    let extended_palette_hex: Vec<&str>;
+    if let Some(n) = args.extend_palette {
+        extended_palette =
+            extend_shared_palette(&images, options.palette_hex, n, args.min_separation);
+        extended_palette_hex = extended_palette.iter().map(|s| s.as_str()).collect();
+        options.palette_hex = &extended_palette_hex;
+        options.palette_oklab = None;
+    }
+
+    check_gamut(&options, args.strict)?;
+
+    if args.stretch_lightness {
+        for img in &mut images {
+            *img = stretch_lightness(img, options.palette_hex);
+        }
+    }
+
+    if let Some(path) = &args.swatches {
+        render_swatches(options.palette_hex).save(path)?;
+    }
+    if let Some(path) = &args.lut {
+        let lut = export_lut(options.palette_hex, args.lut_size)?;
+        if path
+            .extension()
+            .is_some_and(|extension| extension == "cube")
+        {
+            write(path, lut_to_cube(&lut, args.lut_size))?;
+        } else {
+            write(path, lut)?;
+        }
+    }
+    if args.print_palette {
+        for hex in effective_palette(options.palette_hex) {
+            println!("{}", hex);
+        }
+    }
+    if args.equalize_report {
+        for gap in lightness_gaps(options.palette_hex) {
+            println!(
+                "{:.3} {} to {}{}",
+                gap.size,
+                options.palette_hex[gap.darker],
+                options.palette_hex[gap.lighter],
+                if gap.uneven {
+                    " uneven, a color in between would help"
+                } else {
+                    ""
+                }
+            );
+        }
+    }
+
+    if let Some((x, y)) = args.query {
+        println!("{}", query(&images[0], &options, x, y)?);
+        return Ok(());
+    }
+
+    if let Some(frame_count) = args.animate {
+        if ImageFormat::from_path(&args.output).ok() != Some(ImageFormat::Gif) {
+            bail!("--animate needs a .gif output");
+        }
+        let entries = options.threshold_map.len().pow(2);
+        let mut frames = vec![];
+        for frame in 0..frame_count as usize {
+            let options = Options {
+                phase: frame * entries / frame_count as usize,
+                ..options.clone()
+            };
+            let buffer = orient(run_passes(&images[0], &options, args.passes)?, &args);
+            frames.push(Frame::from_parts(
+                buffer,
+                0,
+                0,
+                Delay::from_numer_denom_ms(ANIMATION_FRAME_MS, 1),
+            ));
+        }
+        let mut encoder = GifEncoder::new(BufWriter::new(File::create(&args.output)?));
+        encoder.set_repeat(Repeat::Infinite)?;
+        encoder.encode_frames(frames)?;
+        return Ok(());
+    }
+
+    if !args.compare_palettes.is_empty() {
+        let palettes = args
+            .compare_palettes
+            .iter()
+            .map(|path| read_palette(path))
+            .collect::<Result<Vec<_>>>()?;
+        let highlight = compare_palettes(&images[0], &options, &palettes, &args)?;
+        let changed = highlight.pixels().filter(|&&p| p == CHANGED_COLOR).count();
+        println!("{} pixels differ", changed);
+        save_scaled(&highlight, &args.output, &args)?;
+        return Ok(());
+    }
+
+    let outputs = if args.sheet.is_some() {
+        vec![]
+    } else {
+        output_paths(&args, &inputs)?
+    };
+
+    let background = match &args.background {
+        Some(path) => Some(Reader::open(path)?.decode()?.to_rgba8()),
+        None => None,
+    };
+    let mut filtered = vec![];
+    for (i, img) in images.iter().enumerate() {
+        tune(&mut options, img, &args)?;
+        if args.rust_out.is_some()
+            || args.csv.is_some()
+            || args.oklab_out.is_some()
+            || args.packed_4bpp.is_some()
+        {
+            let indexed = run_indexed(img, &options)?;
+            if let Some(path) = &args.rust_out {
+                let name = const_prefix(path);
+                write(path, indexed_to_rust(&indexed, options.palette_hex, &name))?;
+            }
+            if let Some(path) = &args.csv {
+                write(path, indexed_to_csv(&indexed, options.palette_hex))?;
+            }
+            if let Some(path) = &args.packed_4bpp {
+                let packed =
+                    indexed_to_4bpp(&indexed, options.palette_hex, args.row_align as usize)?;
+                write(path, packed)?;
+            }
+            if let Some(path) = &args.oklab_out {
+                save_oklab(&indexed, &options, path)?;
+            }
+        }
+        if args.bilevel {
+            run_bilevel(img, &options)?.save(&outputs[i])?;
+            continue;
+        }
+        if args.indexed {
+            let indexed = run_indexed(img, &options)?;
+            let writer = BufWriter::new(File::create(&outputs[i])?);
+            write_indexed_png(writer, &indexed, options.palette_hex)?;
+            continue;
+        }
+        let mut output_buffer = filter_image(img, &options, &args, background.as_ref())?;
+        if let Some(path) = &args.json_bundle {
+            write(path, json_bundle(img, &output_buffer, &options)?)?;
+        }
+
+        if let Some(path) = &args.diff {
+            let other = Reader::open(path)?.decode()?.to_rgba8();
+            output_buffer = diff(&output_buffer, &other)?;
+        }
+        filtered.push(orient(output_buffer, &args));
+    }
+
+    if let Some(sheet) = &args.sheet {
+        let columns = sheet[0]
+            .parse()
+            .with_context(|| format!("invalid column count `{}`", sheet[0]))?;
+        let packed = pack_sheet(&filtered, columns, args.sheet_padding);
+        save_scaled(&packed, Path::new(&sheet[1]), &args)?;
+    } else if ico {
+        encode_ico(BufWriter::new(File::create(&outputs[0])?), &filtered)?;
+    } else {
+        for (buffer, output) in filtered.iter().zip(&outputs) {
+            save_scaled(buffer, output, &args)?;
+        }
+    }
+    Ok(())
+}
+
+/// Apply --rotate, --flip-h and --flip-v, in that order.
+fn orient(
+    mut buffer: ImageBuffer<Rgba<u8>, Vec<u8>>,
+    args: &Args,
+) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+    buffer = match args.rotate {
+        Some(Rotation::Quarter) => imageops::rotate90(&buffer),
+        Some(Rotation::Half) => imageops::rotate180(&buffer),
+        Some(Rotation::ThreeQuarters) => imageops::rotate270(&buffer),
+        None => buffer,
+    };
+    if args.flip_h {
+        imageops::flip_horizontal_in_place(&mut buffer);
+    }
+    if args.flip_v {
+        imageops::flip_vertical_in_place(&mut buffer);
+    }
+    buffer
+}
+
+/// Palette in index order, how many visible pixels of the output use each
+/// index and how many no palette color, the color error and the output as
+/// base64 PNG, as one JSON object.
+fn json_bundle(
+    img: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    filtered: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    options: &Options,
+) -> Result<String> {
+    let (usage, other) = output_usage(filtered, options.palette_hex);
+    let (mean_error, max_error) = color_error(img, filtered)?;
+    let mut png = Cursor::new(vec![]);
+    filtered.write_to(&mut png, ImageFormat::Png)?;
+    let bundle = serde_json::json!({
+        "palette": options.palette_hex,
+        "usage": usage,
+        "other_pixels": other,
+        "mean_error": mean_error,
+        "max_error": max_error,
+        "width": filtered.width(),
+        "height": filtered.height(),
+        "png": STANDARD.encode(png.into_inner()),
+    });
+    Ok(serde_json::to_string_pretty(&bundle)?)
+}
+
+/// Uppercase identifier made from the file name, such as `SPRITE` for
+/// `sprite.rs`.
+fn const_prefix(path: &Path) -> String {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let mut name: String = stem
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' => c.to_ascii_uppercase(),
+            _ => '_',
+        })
+        .collect();
+    if !name.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        name.insert(0, 'S');
+    }
+    name
+}
+
+/// Where to save the filtered image of each input when not packing a sheet.
+fn output_paths(args: &Args, inputs: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    if args.batch.is_empty() {
+        return Ok(vec![args.output.clone()]);
+    }
+    let Some(out_dir) = &args.out_dir else {
+        bail!("--batch needs --out-dir or --sheet");
+    };
+    create_dir_all(out_dir)?;
+    let Some(template) = &args.name_template else {
+        return Ok(inputs
+            .iter()
+            .map(|input| out_dir.join(input.file_name().unwrap()))
+            .collect());
+    };
+
+    let palette = match (&args.style, &args.config) {
+        (Some(style), _) => style.clone(),
+        (None, Some(config)) => config
+            .file_stem()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into(),
+        (None, None) => "default".to_string(),
+    };
+    let mut paths = vec![];
+    for (index, input) in inputs.iter().enumerate() {
+        let path = out_dir.join(fill_name_template(template, input, &palette, index)?);
+        if paths.contains(&path) {
+            bail!(
+                "--name-template gives `{}` for more than one input, use {{name}} or {{index}}",
+                path.display()
+            );
+        }
+        paths.push(path);
+    }
+    Ok(paths)
+}
+
+/// Replace the placeholders of --name-template for one input.
+fn fill_name_template(template: &str, input: &Path, palette: &str, index: usize) -> Result<String> {
+    let mut name = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        name.push_str(&rest[..start]);
+        let Some(end) = rest[start..].find('}') else {
+            bail!("unclosed `{{` in --name-template `{}`", template);
+        };
+        let value = match &rest[start + 1..start + end] {
+            "name" => input.file_stem().unwrap_or_default().to_string_lossy(),
+            "ext" => input.extension().unwrap_or_default().to_string_lossy(),
+            "palette" => palette.into(),
+            "index" => index.to_string().into(),
+            placeholder => bail!(
+                "unknown placeholder `{{{}}}` in --name-template, expected {{name}}, {{ext}}, {{palette}} or {{index}}",
+                placeholder
+            ),
+        };
+        name.push_str(&value);
+        rest = &rest[start + end + 1..];
+    }
+    name.push_str(rest);
+    Ok(name)
+}
+
+/// Replace directories with the image files they contain, in name order.
+fn expand_directories(paths: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let mut expanded = vec![];
+    for path in paths {
+        if path.is_dir() {
+            let mut files = read_dir(path)?
+                .map(|entry| Ok(entry?.path()))
+                .collect::<Result<Vec<_>>>()?;
+            files.retain(|file| file.is_file() && ImageFormat::from_path(file).is_ok());
+            files.sort();
+            expanded.extend(files);
+        } else {
+            expanded.push(path.clone());
+        }
+    }
+    Ok(expanded)
+}
+
+/// Decode an input image and apply the pre-passes.
+fn load(path: &Path, args: &Args) -> Result<ImageBuffer<Rgba<u8>, Vec<u8>>> {
+    log::info!("loading {}", path.display());
+    let img = match Reader::open(path)?.decode()? {
+        img @ (DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_)) => {
+            tone_map(&img.to_rgba32f(), args.tone_map)
+        }
+        img => img.to_rgba8(),
+    };
+    Ok(prepare(
+        mask_alpha(img, &path.display().to_string(), args)?,
+        args,
+    ))
+}
+
+/// Replace the alpha of an image read from `name` with --alpha-mask.
+fn mask_alpha(
+    img: ImageBuffer<Rgba<u8>, Vec<u8>>,
+    name: &str,
+    args: &Args,
+) -> Result<ImageBuffer<Rgba<u8>, Vec<u8>>> {
+    let Some(mask_path) = &args.alpha_mask else {
+        return Ok(img);
+    };
+    let mask = Reader::open(mask_path)?.decode()?.to_luma8();
+    apply_alpha_mask(&img, &mask).with_context(|| {
+        format!(
+            "--alpha-mask `{}` is {}x{}, but `{}` is {}x{}",
+            mask_path.display(),
+            mask.width(),
+            mask.height(),
+            name,
+            img.width(),
+            img.height()
+        )
+    })
+}
+
+/// Apply the pre-passes to a decoded image.
+fn prepare(mut img: ImageBuffer<Rgba<u8>, Vec<u8>>, args: &Args) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+    if let Some((ratio_width, ratio_height)) = args.aspect {
+        img = crop_to_aspect(&img, ratio_width, ratio_height);
+    }
+    if let Some(block_size) = args.pixelate {
+        img = block_average(&img, block_size);
+    }
+    if args.chroma_subsample {
+        img = subsample_chroma(&img);
+    }
+    img
+}
+
+/// Apply the filter settings of a config, except its palette, to options
+/// built from the flags. Flags given on the command line win over the config.
+fn apply_config(
+    options: &mut Options,
+    config: &Config,
+    args: &Args,
+    from_flag: &dyn Fn(&str) -> bool,
+) -> Result<()> {
+    if let Some(color_dither) = config.color_dither {
+        options.color_dither = color_dither;
+    }
+    if let Some(strength) = args.dither_strength {
+        options.color_dither = color_dither_from_strength(strength);
+    }
+    if let Some(alpha_dither) = config.alpha_dither {
+        options.alpha_dither = alpha_dither;
+    }
+    if let Some(threshold_map) = &config.threshold_map {
+        options.threshold_map = threshold_map.clone();
+    }
+    if let Some(order) = config.bayer {
+        if order > 4 {
+            bail!("bayer order in the config must be at most 4, got {}", order);
+        }
+        options.threshold_map = bayer_matrix(order);
+    }
+    if let (Some(mode), false) = (config.mode, from_flag("mode")) {
+        options.dither_mode = mode;
+    }
+    if let (Some(match_space), false) = (config.match_space, from_flag("match_space")) {
+        options.match_space = match_space;
+    }
+    if let (Some(dither_scale), false) = (config.dither_scale, from_flag("dither_scale")) {
+        options.dither_scale = dither_scale;
+    }
+    if args.remap {
+        options.snap_threshold = Some(f32::INFINITY);
+    }
+    if let Some(order) = args.bayer {
+        options.threshold_map = bayer_matrix(order);
+    }
+    Ok(())
+}
+
+/// Version and features, such as `pixel-filter 0.1.0 (features: exr)`.
+fn version() -> String {
+    let features = if FEATURES.is_empty() {
+        "none".to_string()
+    } else {
+        FEATURES.join(", ")
+    };
+    format!(
+        "{} {} (features: {})",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"),
+        features
+    )
+}
+
+/// Fail on palette colors that look like they carry alpha, such as
+/// `00000000`, since palette colors are opaque and alpha is dithered apart.
+fn check_palette_alpha(palette_hex: &[&str]) -> Result<()> {
+    if let Some(hex) = palette_hex.iter().find(|hex| hex.len() == 8) {
+        bail!(
+            "palette color `{}` has alpha, but palette colors are opaque; \
+             use --transparent-index to make one of them transparent",
+            hex
+        );
+    }
+    Ok(())
+}
+
+/// Hex palette of a config file.
+fn read_palette(path: &Path) -> Result<Vec<String>> {
+    Config::load(path)?
+        .palette
+        .with_context(|| format!("`{}` has no hex palette", path.display()))
+}
+
+/// Input color, palette index and palette color chosen at a pixel.
+fn query(
+    img: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    options: &Options,
+    x: u32,
+    y: u32,
+) -> Result<String> {
+    if x >= img.width() || y >= img.height() {
+        bail!(
+            "--query {},{} is outside of the {}x{} input",
+            x,
+            y,
+            img.width(),
+            img.height()
+        );
+    }
+    let indexed = run_indexed(img, options)?;
+    let index = indexed.index(x, y);
+    let [r, g, b, a] = img.get_pixel(x, y).0;
+    Ok(format!(
+        "input {:02x}{:02x}{:02x}{:02x}, index {}, palette {}",
+        r, g, b, a, index, options.palette_hex[index]
+    ))
+}
+
+/// Highlight of the pixels that filtering with each of two hex palettes
+/// maps differently.
+fn compare_palettes(
+    img: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    options: &Options,
+    palettes: &[Vec<String>],
+    args: &Args,
+) -> Result<ImageBuffer<Rgba<u8>, Vec<u8>>> {
+    let mut results = vec![];
+    for palette in palettes {
+        let palette_hex: Vec<&str> = palette.iter().map(|s| s.as_str()).collect();
+        let options = Options {
+            palette_hex: &palette_hex,
+            palette_oklab: None,
+            ..options.clone()
+        };
+        check_gamut(&options, args.strict)?;
+        results.push(run_passes(img, &options, args.passes)?);
+    }
+    Ok(diff(&results[0], &results[1])?)
+}
+
+/// List palette colors that get clamped on output, failing under --strict
+/// and warning otherwise.
+fn check_gamut(options: &Options, strict: bool) -> Result<()> {
+    let colors = match options.palette_oklab {
+        Some(colors) => colors.to_vec(),
+        None => palette_as_oklab(options.palette_hex),
+    };
+    // palette_hex may be another palette, such as the --bilevel one
+    let names = oklab_palette_hex(&colors);
+    let entries = out_of_gamut(&colors)
+        .into_iter()
+        .map(|index| format!("{} ({})", index, names[index]))
+        .collect::<Vec<_>>();
+    if entries.is_empty() {
+        return Ok(());
+    }
+    if strict {
+        bail!("palette colors out of gamut: {}", entries.join(", "));
+    }
+    log::warn!(
+        "clamping palette colors out of gamut: {}",
+        entries.join(", ")
+    );
+    Ok(())
+}
+
+/// Save the Oklab color of each pixel's palette entry for --oklab-out.
+fn save_oklab(indexed: &IndexedImage, options: &Options, path: &Path) -> Result<()> {
+    let palette = match options.palette_oklab {
+        Some(colors) => colors.to_vec(),
+        None => palette_as_oklab(options.palette_hex),
+    };
+    let oklab = indexed_to_oklab(indexed, &palette);
+    if ImageFormat::from_path(path).ok() == Some(ImageFormat::OpenExr) {
+        if !cfg!(feature = "exr") {
+            bail!("EXR output needs the `exr` feature");
+        }
+        DynamicImage::ImageRgb32F(oklab).save(path)?;
+    } else {
+        let bytes: Vec<u8> = oklab.iter().flat_map(|v| v.to_le_bytes()).collect();
+        write(path, bytes)?;
+    }
+    Ok(())
+}
+
+/// Save at each of --scales, upscaled with nearest neighbor so the dither
+/// pattern stays identical, adding an `@2x`-style suffix to scales above 1.
+fn save_scaled(buffer: &ImageBuffer<Rgba<u8>, Vec<u8>>, path: &Path, args: &Args) -> Result<()> {
+    if args.scales.is_empty() {
+        return save(buffer, path, args, 1);
+    }
+    for &scale in &args.scales {
+        if scale == 1 {
+            save(buffer, path, args, 1)?;
+            continue;
+        }
+        let scaled = imageops::resize(
+            buffer,
+            buffer.width() * scale,
+            buffer.height() * scale,
+            FilterType::Nearest,
+        );
+        let mut file_name = path.file_stem().unwrap_or_default().to_os_string();
+        file_name.push(format!("@{}x", scale));
+        if let Some(extension) = path.extension() {
+            file_name.push(".");
+            file_name.push(extension);
+        }
+        save(&scaled, &path.with_file_name(file_name), args, scale)?;
+    }
+    Ok(())
+}
+
+/// Save an image upscaled `scale` times, which keeps its printed size by
+/// scaling --dpi too.
+fn save(
+    buffer: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    path: &Path,
+    args: &Args,
+    scale: u32,
+) -> Result<()> {
+    let format = output_format(path, args);
+    let is_png = matches!(format, Some(OutputFormat::Png));
+    if (args.lossless || args.quality.is_some()) && !matches!(format, Some(OutputFormat::Webp)) {
+        bail!("--lossless and --quality need WebP output");
+    }
+    if args.compression.is_some() && !is_png {
+        bail!("--compression needs PNG output");
+    }
+    if args.tag || args.dpi.is_some() {
+        if !is_png {
+            bail!("--tag and --dpi need PNG output");
+        }
+        let version = version();
+        let text: &[(&str, &str)] = if args.tag {
+            &[("Software", &version)]
+        } else {
+            &[]
+        };
+        let writer = BufWriter::new(File::create(path)?);
+        write_png_with_metadata(
+            writer,
+            buffer,
+            text,
+            args.dpi.map(|dpi| dpi * scale),
+            args.compression
+                .map_or(CompressionType::Default, Into::into),
+        )?;
+        return Ok(());
+    }
+
+    if let Some(compression) = args.compression {
+        let writer = BufWriter::new(File::create(path)?);
+        PngEncoder::new_with_quality(writer, compression.into(), PngFilterType::Adaptive)
+            .write_image(
+                buffer.as_raw(),
+                buffer.width(),
+                buffer.height(),
+                ColorType::Rgba8,
+            )?;
+        return Ok(());
+    }
+    match format {
+        Some(OutputFormat::Png) => buffer.save_with_format(path, ImageFormat::Png)?,
+        Some(OutputFormat::Webp) => {
+            let quality = if args.lossless { None } else { args.quality };
+            write_webp(BufWriter::new(File::create(path)?), buffer, quality)?;
+        }
+        None => buffer.save(path)?,
+    }
+    Ok(())
+}
+
+/// --format, or else the format of the output's extension if it has one of
+/// its own settings.
+fn output_format(path: &Path, args: &Args) -> Option<OutputFormat> {
+    args.format.or(match ImageFormat::from_path(path).ok() {
+        Some(ImageFormat::Png) => Some(OutputFormat::Png),
+        Some(ImageFormat::WebP) => Some(OutputFormat::Webp),
+        _ => None,
+    })
+}
+
+/// Encode WebP, lossy with the given quality or else lossless.
+fn write_webp<W: Write>(
+    writer: W,
+    buffer: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    quality: Option<u8>,
+) -> Result<()> {
+    webp_encoder(writer, quality)?.encode(
+        buffer.as_raw(),
+        buffer.width(),
+        buffer.height(),
+        ColorType::Rgba8,
+    )?;
+    Ok(())
+}
+
+#[cfg(feature = "webp-lossy")]
+#[allow(deprecated)]
+fn webp_encoder<W: Write>(writer: W, quality: Option<u8>) -> Result<WebPEncoder<W>> {
+    use image::codecs::webp::WebPQuality;
+
+    Ok(match quality {
+        Some(quality) => WebPEncoder::new_with_quality(writer, WebPQuality::lossy(quality)),
+        None => WebPEncoder::new_lossless(writer),
+    })
+}
+
+#[cfg(not(feature = "webp-lossy"))]
+fn webp_encoder<W: Write>(writer: W, quality: Option<u8>) -> Result<WebPEncoder<W>> {
+    if quality.is_some() {
+        bail!("lossy WebP requires the `webp-lossy` feature");
+    }
+    Ok(WebPEncoder::new_lossless(writer))
+}
+
+/// Filter the RGBA bytes of --raw from `input` to `output`, with the same
+/// passes as an image file, short of those that change its size.
+fn pipe_raw(
+    mut input: impl Read,
+    mut output: impl Write,
+    width: u32,
+    height: u32,
+    options: &Options,
+    args: &Args,
+) -> Result<()> {
+    let mut bytes = vec![];
+    input.read_to_end(&mut bytes)?;
+    output.write_all(&filter_raw(bytes, width, height, options, args)?)?;
     Ok(())
 }
+
+/// Filter the RGBA bytes read by --raw into the RGBA bytes written.
+fn filter_raw(
+    bytes: Vec<u8>,
+    width: u32,
+    height: u32,
+    options: &Options,
+    args: &Args,
+) -> Result<Vec<u8>> {
+    let mut img = prepare(
+        mask_alpha(rgba_buffer(width, height, bytes)?, "stdin", args)?,
+        args,
+    );
+    if args.stretch_lightness {
+        img = stretch_lightness(&img, options.palette_hex);
+    }
+    let mut options = options.clone();
+    tune(&mut options, &img, args)?;
+    if args.bilevel {
+        // opaque black and white, like the saved bilevel image
+        let bilevel = run_bilevel(&img, &options)?;
+        return Ok(DynamicImage::ImageLuma8(bilevel).to_rgba8().into_raw());
+    }
+    let background = match &args.background {
+        Some(path) => Some(Reader::open(path)?.decode()?.to_rgba8()),
+        None => None,
+    };
+    Ok(filter_image(&img, &options, args, background.as_ref())?.into_raw())
+}
+
+/// Filter an image in color, then blend it with the input and put it over
+/// the background as asked.
+fn filter_image(
+    img: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    options: &Options,
+    args: &Args,
+    background: Option<&ImageBuffer<Rgba<u8>, Vec<u8>>>,
+) -> Result<ImageBuffer<Rgba<u8>, Vec<u8>>> {
+    let mut output_buffer = match args.two_stage {
+        Some(colors) => run_two_stage(img, options, colors as usize)?,
+        None => run_passes(img, options, args.passes)?,
+    };
+    if let Some(amount) = args.blend {
+        output_buffer = blend(img, &output_buffer, amount)?;
+    }
+    if let Some(background) = background {
+        output_buffer = composite_over(&output_buffer, background, args.background_fit)?;
+    }
+    Ok(output_buffer)
+}
+
+/// Pick the threshold map and color dither for an image, if asked to.
+fn tune(options: &mut Options, img: &ImageBuffer<Rgba<u8>, Vec<u8>>, args: &Args) -> Result<()> {
+    if args.auto_bayer {
+        options.threshold_map = bayer_matrix(suggest_bayer_order(img));
+    }
+    if let Some(target_error) = args.target_error {
+        options.color_dither = auto_dither(img, options, target_error)?;
+        log::info!(
+            "color dither {} for error {}",
+            options.color_dither,
+            target_error
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_dimensions() {
+        assert_eq!(parse_dimensions("640x480"), Ok((640, 480)));
+        assert!(parse_dimensions("640").is_err());
+        assert!(parse_dimensions("640x-1").is_err());
+    }
+
+    #[test]
+    fn raw_mode_pipes_bytes_through_the_passes() {
+        let img = ImageBuffer::from_fn(4, 4, |x, y| Rgba([x as u8 * 60, y as u8 * 60, 96, 255]));
+        let argv = [
+            "cli",
+            "--raw",
+            "4x4",
+            "--pixelate",
+            "2",
+            "--passes",
+            "2",
+            "--blend",
+            "0.5",
+        ];
+        let args = Args::try_parse_from(argv).unwrap();
+        let options = Options::default();
+        let mut output = vec![];
+        pipe_raw(
+            Cursor::new(img.as_raw()),
+            &mut output,
+            4,
+            4,
+            &options,
+            &args,
+        )
+        .unwrap();
+
+        let prepared = block_average(&img, 2);
+        let filtered = run_passes(&prepared, &options, 2).unwrap();
+        assert_eq!(output, blend(&prepared, &filtered, 0.5).unwrap().into_raw());
+
+        let error = pipe_raw(Cursor::new([0; 15]), vec![], 2, 2, &options, &args).unwrap_err();
+        assert!(error.to_string().contains("expected 16 bytes"), "{}", error);
+        // options that change the size or write files are rejected
+        assert!(Args::try_parse_from(["cli", "--raw", "4x4", "--rotate", "90"]).is_err());
+        assert!(Args::try_parse_from(["cli", "--raw", "4x4", "--aspect", "1:1"]).is_err());
+    }
+
+    #[test]
+    fn output_format_follows_the_extension() {
+        let args = Args::try_parse_from(["cli"]).unwrap();
+        let format = |path: &str| output_format(Path::new(path), &args);
+        assert!(matches!(format("out.webp"), Some(OutputFormat::Webp)));
+        assert!(matches!(format("out.png"), Some(OutputFormat::Png)));
+        assert!(format("out.gif").is_none());
+
+        let args = Args::try_parse_from(["cli", "--format", "png"]).unwrap();
+        assert!(matches!(
+            output_format(Path::new("out.webp"), &args),
+            Some(OutputFormat::Png)
+        ));
+    }
+
+    #[test]
+    fn lossless_webp_keeps_pixels_and_beats_png() {
+        let img = ImageBuffer::from_fn(64, 64, |x, y| {
+            Rgba([
+                (x / 8 * 32) as u8,
+                (y / 8 * 32) as u8,
+                128,
+                (x % 2 * 255) as u8,
+            ])
+        });
+        let filtered = run(&img).unwrap();
+        let mut webp = vec![];
+        write_webp(&mut webp, &filtered, None).unwrap();
+        let mut png = Cursor::new(vec![]);
+        filtered.write_to(&mut png, ImageFormat::Png).unwrap();
+
+        let decoded = image::load_from_memory(&webp).unwrap().to_rgba8();
+        assert_eq!(decoded, filtered);
+        assert!(webp.len() < png.into_inner().len());
+    }
+
+    #[test]
+    fn seed_only_changes_noise_dithering() {
+        // the seed alone doesn't switch away from the threshold map
+        assert!(Args::try_parse_from(["cli", "--seed", "1"]).is_err());
+        let args = Args::try_parse_from(["cli"]).unwrap();
+        assert_eq!(noise_seed(&args), None);
+
+        let img =
+            ImageBuffer::from_fn(32, 32, |x, y| Rgba([(x * 8) as u8, (y * 8) as u8, 96, 255]));
+        let filter = |flags: &[&str]| {
+            let args = Args::try_parse_from(["cli"].iter().chain(flags)).unwrap();
+            let options = Options {
+                noise_seed: noise_seed(&args),
+                ..Default::default()
+            };
+            run_with_options(&img, &options).unwrap()
+        };
+        assert_eq!(filter(&["--noise"]), filter(&["--noise", "--seed", "0"]));
+        assert_eq!(
+            filter(&["--noise", "--seed", "7"]),
+            filter(&["--noise", "--seed", "7"])
+        );
+        assert_ne!(
+            filter(&["--noise", "--seed", "7"]),
+            filter(&["--noise", "--seed", "8"])
+        );
+    }
+
+    #[test]
+    fn lossless_conflicts_with_quality() {
+        assert!(Args::try_parse_from(["cli", "--lossless", "--quality", "50"]).is_err());
+    }
+
+    #[cfg(feature = "exr")]
+    #[test]
+    fn hdr_exr_input_is_tone_mapped_onto_the_palette() {
+        let dir = std::env::temp_dir().join(format!("exr_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("in.exr");
+        // radiance up to 8 times the sRGB white
+        let hdr = image::Rgba32FImage::from_fn(8, 8, |x, y| {
+            image::Rgba([x as f32, y as f32 * 0.5, 8.0 - x as f32, 1.0])
+        });
+        DynamicImage::ImageRgba32F(hdr).save(&path).unwrap();
+
+        let args = Args::try_parse_from(["cli"]).unwrap();
+        let img = load(&path, &args).unwrap();
+        // tone mapping keeps the bright colors apart instead of clipping them
+        assert_ne!(img.get_pixel(4, 4), img.get_pixel(7, 4));
+        let options = Options {
+            palette_hex: &PALETTE_HEX_2,
+            ..Default::default()
+        };
+        let output = run_with_options(&img, &options).unwrap();
+        for pixel in output.pixels() {
+            let [r, g, b, a] = pixel.0;
+            let hex = format!("{:02x}{:02x}{:02x}", r, g, b);
+            assert!(PALETTE_HEX_2.contains(&hex.as_str()), "{}", hex);
+            assert_eq!(a, 255);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn parses_ratios() {
+        assert_eq!(parse_ratio("16:9"), Ok((16, 9)));
+        assert!(parse_ratio("16x9").is_err());
+        assert!(parse_ratio("0:1").is_err());
+    }
+
+    #[test]
+    fn rotation_swaps_dimensions_and_moves_corners() {
+        // red top-left, green top-right, blue bottom-left
+        let img = ImageBuffer::from_fn(3, 2, |x, y| match (x, y) {
+            (0, 0) => Rgba([255, 0, 0, 255]),
+            (2, 0) => Rgba([0, 255, 0, 255]),
+            (0, 1) => Rgba([0, 0, 255, 255]),
+            _ => Rgba([0, 0, 0, 255]),
+        });
+        let oriented = |flags: &[&str]| {
+            let args = Args::try_parse_from(["cli"].iter().chain(flags)).unwrap();
+            orient(img.clone(), &args)
+        };
+
+        let rotated = oriented(&["--rotate", "90"]);
+        assert_eq!(rotated.dimensions(), (2, 3));
+        assert_eq!(rotated.get_pixel(1, 0).0, [255, 0, 0, 255]);
+        assert_eq!(rotated.get_pixel(1, 2).0, [0, 255, 0, 255]);
+        assert_eq!(rotated.get_pixel(0, 0).0, [0, 0, 255, 255]);
+
+        // flips come after the rotation
+        let flipped = oriented(&["--rotate", "90", "--flip-h"]);
+        assert_eq!(flipped.get_pixel(0, 0).0, [255, 0, 0, 255]);
+        assert_eq!(
+            oriented(&["--flip-h", "--flip-v"]),
+            oriented(&["--rotate", "180"])
+        );
+    }
+
+    /// Write a config to a file of its own and load it.
+    fn load_config(name: &str, toml: &str) -> Result<Config> {
+        let path = std::env::temp_dir().join(format!("{}_{}.toml", name, std::process::id()));
+        std::fs::write(&path, toml).unwrap();
+        let config = Config::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        config
+    }
+
+    #[test]
+    fn config_drives_the_filter() {
+        let config = load_config(
+            "drives",
+            "palette = [\"000000\", \"ffffff\"]\ncolor_dither = 0.0\nmode = \"ordered\"\n\
+             dither_scale = 2\n",
+        )
+        .unwrap();
+        let palette: Vec<&str> = config
+            .palette
+            .iter()
+            .flatten()
+            .map(|s| s.as_str())
+            .collect();
+        let options_for = |argv: &[&str]| {
+            let matches = Args::command().try_get_matches_from(argv).unwrap();
+            let args = Args::from_arg_matches(&matches).unwrap();
+            let from_flag = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+            let mut options = Options {
+                palette_hex: &palette,
+                dither_mode: args.mode,
+                dither_scale: args.dither_scale,
+                ..Default::default()
+            };
+            apply_config(&mut options, &config, &args, &from_flag).unwrap();
+            options
+        };
+
+        let options = options_for(&["cli"]);
+        assert_eq!(options.dither_mode, DitherMode::Ordered);
+        assert_eq!(options.dither_scale, 2);
+        let img = ImageBuffer::from_fn(4, 1, |x, _| {
+            let c = x as u8 * 80;
+            Rgba([c, c, c, 255])
+        });
+        let output = run_with_options(&img, &options).unwrap();
+        let reds: Vec<u8> = output.pixels().map(|pixel| pixel.0[0]).collect();
+        assert_eq!(reds, [0, 0, 255, 255]);
+
+        // flags win over the config, even when given their default value
+        let options = options_for(&[
+            "cli",
+            "--mode",
+            "candidate-sort",
+            "--dither-scale",
+            "1",
+            "--dither-strength",
+            "50",
+        ]);
+        assert_eq!(options.dither_mode, DitherMode::CandidateSort);
+        assert_eq!(options.dither_scale, 1);
+        assert_eq!(options.color_dither, color_dither_from_strength(50.0));
+    }
+
+    #[test]
+    fn config_palettes_are_checked_on_load() {
+        let error = load_config("hash", "palette = [\"000000\", \"#ffffff\"]")
+            .err()
+            .unwrap();
+        assert!(
+            format!("{:#}", error).contains("palette color 1 `#ffffff`"),
+            "{:#}",
+            error
+        );
+        assert!(load_config("empty", "palette = []").is_err());
+        assert!(load_config("oklab", "palette_oklab = []").is_err());
+        let error = load_config("style", "[styles.dark]\npalette = [\"zz\"]")
+            .err()
+            .unwrap();
+        assert!(
+            format!("{:#}", error).contains("style `dark`"),
+            "{:#}",
+            error
+        );
+    }
+
+    #[test]
+    fn version_lists_the_features() {
+        let version = version();
+        assert!(version.starts_with(&format!(
+            "pixel-filter {} (features: ",
+            env!("CARGO_PKG_VERSION")
+        )));
+        for feature in FEATURES {
+            assert!(version.contains(feature));
+        }
+        assert!(Args::try_parse_from(["cli", "--version"]).unwrap().version);
+    }
+
+    #[test]
+    fn scales_save_upscaled_copies() {
+        let dir = std::env::temp_dir().join(format!("scales_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let buffer = ImageBuffer::from_fn(3, 2, |x, y| Rgba([x as u8 * 80, y as u8 * 80, 0, 255]));
+        let args = Args::try_parse_from(["cli", "--scales", "1,2,4"]).unwrap();
+        save_scaled(&buffer, &dir.join("out.png"), &args).unwrap();
+
+        for (name, scale) in [("out.png", 1), ("out@2x.png", 2), ("out@4x.png", 4)] {
+            let saved = image::open(dir.join(name)).unwrap().to_rgba8();
+            assert_eq!(saved.dimensions(), (3 * scale, 2 * scale));
+            // every pixel becomes a solid block
+            assert_eq!(
+                saved.get_pixel(3 * scale - 1, 2 * scale - 1),
+                buffer.get_pixel(2, 1)
+            );
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn strict_rejects_oklab_colors_outside_srgb() {
+        // very saturated green, well outside sRGB
+        let palette_oklab = [Oklab::new(0.0, 0.0, 0.0), Oklab::new(0.8, -0.35, 0.2)];
+        let palette = oklab_palette_hex(&palette_oklab);
+        let palette_hex: Vec<&str> = palette.iter().map(|s| s.as_str()).collect();
+        let options = Options {
+            palette_hex: &palette_hex,
+            palette_oklab: Some(&palette_oklab),
+            ..Default::default()
+        };
+        let error = check_gamut(&options, true).unwrap_err().to_string();
+        assert!(
+            error.contains(&format!("1 ({})", palette_hex[1])),
+            "{}",
+            error
+        );
+        assert!(check_gamut(&options, false).is_ok());
+
+        let options = Options {
+            palette_hex: &palette_hex,
+            ..Default::default()
+        };
+        assert!(check_gamut(&options, true).is_ok());
+    }
+
+    #[test]
+    fn gamut_check_names_the_colors_of_the_oklab_palette() {
+        // more Oklab colors than hex codes, the last one outside sRGB
+        let palette_oklab = [
+            Oklab::new(0.0, 0.0, 0.0),
+            Oklab::new(1.0, 0.0, 0.0),
+            Oklab::new(0.7, 0.4, 0.3),
+        ];
+        let options = Options {
+            palette_hex: &BILEVEL_PALETTE,
+            palette_oklab: Some(&palette_oklab),
+            ..Default::default()
+        };
+        let error = check_gamut(&options, true).unwrap_err().to_string();
+        let name = &oklab_palette_hex(&palette_oklab)[2];
+        assert!(error.ends_with(&format!("2 ({})", name)), "{}", error);
+    }
+
+    #[test]
+    fn raw_bilevel_writes_opaque_black_and_white() {
+        let bytes: Vec<u8> = (0..16u8)
+            .flat_map(|i| [i * 16, i * 16, i * 16, 128])
+            .collect();
+        let args = Args::try_parse_from(["cli", "--raw", "4x4", "--bilevel"]).unwrap();
+        // a gray Oklab palette is not what --bilevel writes
+        let palette_oklab = [Oklab::new(0.3, 0.0, 0.0), Oklab::new(0.7, 0.0, 0.0)];
+        let options = Options {
+            palette_oklab: Some(&palette_oklab),
+            ..Default::default()
+        };
+        let output = filter_raw(bytes, 4, 4, &options, &args).unwrap();
+        assert_eq!(output.len(), 64);
+        for pixel in output.chunks(4) {
+            assert!(pixel[0] == 0 || pixel[0] == 255, "{:?}", pixel);
+            assert_eq!(pixel, [pixel[0], pixel[0], pixel[0], 255]);
+        }
+        assert!(output.chunks(4).any(|pixel| pixel[0] == 0));
+        assert!(output.chunks(4).any(|pixel| pixel[0] == 255));
+    }
+
+    #[test]
+    fn config_settings_override_the_style() {
+        let gameboy = Style::find("gameboy").unwrap();
+        let config = load_config("override", "color_dither = 0.5").unwrap();
+        let config = config.or(gameboy.into());
+        assert_eq!(config.color_dither, Some(0.5));
+        assert_eq!(config.bayer, Some(gameboy.bayer));
+        assert_eq!(config.palette.unwrap(), gameboy.palette_hex);
+
+        let config = load_config("override", "palette = [\"000000\"]").unwrap();
+        let config = config.or(gameboy.into());
+        assert_eq!(config.palette.unwrap(), ["000000"]);
+    }
+
+    #[test]
+    fn dpi_scales_with_upscaled_copies() {
+        let dir = std::env::temp_dir().join(format!("dpi_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let buffer = ImageBuffer::from_pixel(2, 2, Rgba([10, 20, 30, 255]));
+        let args = Args::try_parse_from(["cli", "--dpi", "300", "--scales", "1,2"]).unwrap();
+        save_scaled(&buffer, &dir.join("out.png"), &args).unwrap();
+
+        // pHYs is in pixels per meter
+        for (name, pixels_per_meter) in [("out.png", 11811), ("out@2x.png", 23622)] {
+            let decoder = png::Decoder::new(File::open(dir.join(name)).unwrap());
+            let reader = decoder.read_info().unwrap();
+            let dims = reader.info().pixel_dims.unwrap();
+            assert_eq!((dims.xppu, dims.yppu), (pixels_per_meter, pixels_per_meter));
+            // --tag wasn't given
+            assert!(reader.info().uncompressed_latin1_text.is_empty());
+        }
+
+        let error = save(&buffer, &dir.join("out.jpg"), &args, 1).unwrap_err();
+        assert!(error.to_string().contains("need PNG output"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn json_bundle_describes_the_final_output() {
+        let img = ImageBuffer::from_fn(8, 8, |x, y| Rgba([x as u8 * 32, y as u8 * 32, 96, 255]));
+        let options = Options {
+            palette_hex: &PALETTE_HEX_2,
+            ..Default::default()
+        };
+        let bundle = |filtered: &ImageBuffer<Rgba<u8>, Vec<u8>>| {
+            let json = json_bundle(&img, filtered, &options).unwrap();
+            serde_json::from_str::<serde_json::Value>(&json).unwrap()
+        };
+
+        // counts follow the pixels written, not a separate indexed run
+        let filtered = run_passes(&img, &options, 3).unwrap();
+        let value = bundle(&filtered);
+        let mut expected = vec![0; PALETTE_HEX_2.len()];
+        for pixel in filtered.pixels() {
+            let hex = format!("{:02x}{:02x}{:02x}", pixel.0[0], pixel.0[1], pixel.0[2]);
+            expected[PALETTE_HEX_2.iter().position(|&c| c == hex).unwrap()] += 1;
+        }
+        assert_eq!(value["usage"], serde_json::json!(expected));
+        assert_eq!(value["other_pixels"], 0);
+
+        // blended pixels use no palette color
+        let blended = blend(&img, &filtered, 0.5).unwrap();
+        let value = bundle(&blended);
+        let used: u64 = value["usage"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|n| n.as_u64().unwrap())
+            .sum();
+        assert_eq!(used + value["other_pixels"].as_u64().unwrap(), 64);
+        assert!(value["other_pixels"].as_u64().unwrap() > 32);
+    }
+
+    #[test]
+    fn palette_colors_with_alpha_are_rejected() {
+        assert!(check_palette_alpha(&["000000", "ffffff"]).is_ok());
+        let error = check_palette_alpha(&["000000", "ffffff00"]).unwrap_err();
+        assert!(error.to_string().contains("`ffffff00`"));
+        assert!(error.to_string().contains("--transparent-index"));
+    }
+
+    #[test]
+    fn level_dither_takes_channels() {
+        let args = Args::try_parse_from(["cli", "--levels", "4", "--level-dither", "rb"]).unwrap();
+        assert_eq!(args.level_dither, [true, false, true]);
+        let args = Args::try_parse_from(["cli", "--levels", "4"]).unwrap();
+        assert_eq!(args.level_dither, [true; 3]);
+        assert!(Args::try_parse_from(["cli", "--level-dither", "g", "--no-level-dither"]).is_err());
+    }
+
+    #[test]
+    fn name_templates_fill_placeholders() {
+        let input = Path::new("art/sprite.png");
+        let name = fill_name_template("{name}_{palette}_{index}.{ext}", input, "gameboy", 3);
+        assert_eq!(name.unwrap(), "sprite_gameboy_3.png");
+        assert_eq!(
+            fill_name_template("out", input, "default", 0).unwrap(),
+            "out"
+        );
+        let error = fill_name_template("{size}.png", input, "default", 0).unwrap_err();
+        assert!(error.to_string().contains("`{size}`"));
+        assert!(fill_name_template("{name.png", input, "default", 0).is_err());
+    }
+
+    #[test]
+    fn name_templates_must_tell_inputs_apart() {
+        let dir = std::env::temp_dir().join(format!("names_{}", std::process::id()));
+        let out_dir = dir.to_str().unwrap();
+        let inputs = [PathBuf::from("a.png"), PathBuf::from("b.png")];
+        let paths = |template: &str| {
+            let args = Args::try_parse_from([
+                "cli",
+                "--batch",
+                "a.png",
+                "b.png",
+                "--out-dir",
+                out_dir,
+                "--style",
+                "c64",
+                "--name-template",
+                template,
+            ])
+            .unwrap();
+            output_paths(&args, &inputs)
+        };
+        assert_eq!(
+            paths("{name}-{palette}.{ext}").unwrap(),
+            [dir.join("a-c64.png"), dir.join("b-c64.png")]
+        );
+        assert!(paths("{palette}.png").is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn raw_oklab_output_reads_back_as_the_palette_coordinates() {
+        let dir = std::env::temp_dir().join(format!("oklab_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("out.f32");
+        let img =
+            ImageBuffer::from_fn(6, 4, |x, y| Rgba([(x * 50) as u8, (y * 80) as u8, 96, 255]));
+        let options = Options {
+            palette_hex: &PALETTE_HEX_2,
+            ..Default::default()
+        };
+        let indexed = run_indexed(&img, &options).unwrap();
+        save_oklab(&indexed, &options, &path).unwrap();
+
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(bytes.len(), 6 * 4 * 3 * 4);
+        let palette = palette_as_oklab(&PALETTE_HEX_2);
+        for (i, triple) in bytes.chunks_exact(12).enumerate() {
+            let value = |c: usize| f32::from_le_bytes(triple[c * 4..c * 4 + 4].try_into().unwrap());
+            let color = palette[indexed.indices[i]];
+            assert_eq!([value(0), value(1), value(2)], [color.l, color.a, color.b]);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn changing_one_palette_color_flags_the_pixels_that_used_it() {
+        let img = ImageBuffer::from_fn(16, 16, |x, y| Rgba([x as u8 * 16, y as u8 * 16, 96, 255]));
+        let options = Options {
+            palette_hex: &PALETTE_HEX_2,
+            color_dither: 0.0,
+            ..Default::default()
+        };
+        let before = run_with_options(&img, &options).unwrap();
+        let tweaked = PALETTE_HEX_2[4].replace("d0", "d1");
+        let path = std::env::temp_dir().join(format!("compare_{}.toml", std::process::id()));
+        let mut palette: Vec<String> = PALETTE_HEX_2.iter().map(|s| s.to_string()).collect();
+        std::fs::write(&path, format!("palette = {:?}", palette)).unwrap();
+        let original = read_palette(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        palette[4] = tweaked;
+
+        let args = Args::try_parse_from(["cli"]).unwrap();
+        let highlight = compare_palettes(&img, &options, &[original, palette], &args).unwrap();
+        // PALETTE_HEX_2[4]
+        let used = [0xd0, 0x81, 0x59];
+        let expected: Vec<bool> = before.pixels().map(|p| p.0[..3] == used).collect();
+        let flagged: Vec<bool> = highlight.pixels().map(|&p| p == CHANGED_COLOR).collect();
+        assert!(flagged.contains(&true));
+        assert_eq!(flagged, expected);
+    }
+
+    #[test]
+    fn compression_levels_keep_the_pixels() {
+        let dir = std::env::temp_dir().join(format!("compression_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let buffer = ImageBuffer::from_fn(64, 64, |x, y| {
+            Rgba([(x * 4) as u8, (y * 4) as u8, ((x ^ y) * 4) as u8, 255])
+        });
+        let mut sizes = vec![];
+        for (level, tag) in [
+            ("fast", false),
+            ("best", false),
+            ("fast", true),
+            ("best", true),
+        ] {
+            let mut argv = vec!["cli", "--compression", level];
+            if tag {
+                argv.push("--tag");
+            }
+            let args = Args::try_parse_from(argv).unwrap();
+            let path = dir.join(format!("{}_{}.png", level, tag));
+            save(&buffer, &path, &args, 1).unwrap();
+            assert_eq!(image::open(&path).unwrap().to_rgba8(), buffer);
+            sizes.push(std::fs::metadata(&path).unwrap().len());
+        }
+        assert!(sizes[1] < sizes[0], "{:?}", sizes);
+        assert!(sizes[3] < sizes[2], "{:?}", sizes);
+
+        let args = Args::try_parse_from(["cli", "--compression", "best"]).unwrap();
+        let error = save(&buffer, &dir.join("out.webp"), &args, 1).unwrap_err();
+        assert!(error.to_string().contains("--compression needs PNG output"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn query_reports_the_index_chosen_at_a_pixel() {
+        let img = ImageBuffer::from_fn(4, 4, |x, y| Rgba([x as u8 * 80, y as u8 * 80, 96, 255]));
+        let options = Options {
+            palette_hex: &PALETTE_HEX_2,
+            ..Default::default()
+        };
+        let indexed = run_indexed(&img, &options).unwrap();
+        let index = indexed.index(3, 1);
+        assert_eq!(
+            query(&img, &options, 3, 1).unwrap(),
+            format!(
+                "input f05060ff, index {}, palette {}",
+                index, PALETTE_HEX_2[index]
+            )
+        );
+        let error = query(&img, &options, 4, 0).unwrap_err();
+        assert_eq!(error.to_string(), "--query 4,0 is outside of the 4x4 input");
+        assert_eq!(parse_coordinates("3,1"), Ok((3, 1)));
+        assert!(parse_coordinates("3").is_err());
+    }
+
+    /// Messages logged by any test, kept so they can be checked.
+    struct CapturedLog(std::sync::Mutex<Vec<(log::Level, String)>>);
+
+    impl log::Log for CapturedLog {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            let message = (record.level(), record.args().to_string());
+            self.0.lock().unwrap().push(message);
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[test]
+    fn gamut_warnings_are_logged() {
+        static LOG: CapturedLog = CapturedLog(std::sync::Mutex::new(vec![]));
+        log::set_logger(&LOG).unwrap();
+        log::set_max_level(log::LevelFilter::Warn);
+
+        let palette_oklab = [Oklab::new(0.0, 0.0, 0.0), Oklab::new(0.8, -0.35, 0.2)];
+        let palette = oklab_palette_hex(&palette_oklab);
+        let palette_hex: Vec<&str> = palette.iter().map(|s| s.as_str()).collect();
+        let options = Options {
+            palette_hex: &palette_hex,
+            palette_oklab: Some(&palette_oklab),
+            ..Default::default()
+        };
+        check_gamut(&options, false).unwrap();
+        let expected = (
+            log::Level::Warn,
+            format!(
+                "clamping palette colors out of gamut: 1 ({})",
+                palette_hex[1]
+            ),
+        );
+        assert!(LOG.0.lock().unwrap().contains(&expected));
+    }
+}
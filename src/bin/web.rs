@@ -6,6 +6,8 @@ use pixel_filter::layout::*;
 
 fn main() -> Result<(), JsValue> {
     console_error_panic_hook::set_once();
+    #[cfg(all(feature = "logging", target_arch = "wasm32"))]
+    console_log::init_with_level(log::Level::Debug).unwrap();
     Renderer::<App>::new().render();
     Ok(())
 }
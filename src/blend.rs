@@ -0,0 +1,183 @@
+use image::{
+    error::{ParameterError, ParameterErrorKind},
+    imageops::{self, FilterType},
+    ImageBuffer, ImageError, Rgba,
+};
+use palette::{LinSrgb, Srgb};
+
+/// Mix the filtered image back with the original, from `0.0` for the
+/// original to `1.0` for the fully filtered image.
+///
+/// Colors are interpolated in linear light and weighted by alpha, so that a
+/// transparent pixel on either side doesn't tint the mix.
+pub fn blend(
+    original: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    filtered: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    amount: f32,
+) -> Result<ImageBuffer<Rgba<u8>, Vec<u8>>, ImageError> {
+    if original.dimensions() != filtered.dimensions() {
+        return Err(ImageError::Parameter(ParameterError::from_kind(
+            ParameterErrorKind::DimensionMismatch,
+        )));
+    }
+    let amount = amount.clamp(0.0, 1.0);
+
+    Ok(ImageBuffer::from_fn(
+        original.width(),
+        original.height(),
+        |x, y| {
+            let (a, b) = (original.get_pixel(x, y), filtered.get_pixel(x, y));
+            if amount == 0.0 || a == b {
+                return *a;
+            }
+            if amount == 1.0 {
+                return *b;
+            }
+
+            let premultiplied = |pixel: &Rgba<u8>| {
+                let [r, g, b, a] = pixel.0;
+                let alpha = a as f32 / 255.0;
+                let linear: LinSrgb = Srgb::new(r, g, b).into_format::<f32>().into_linear();
+                (linear * alpha, alpha)
+            };
+            let (color_a, alpha_a) = premultiplied(a);
+            let (color_b, alpha_b) = premultiplied(b);
+            let color = color_a * (1.0 - amount) + color_b * amount;
+            let alpha = alpha_a * (1.0 - amount) + alpha_b * amount;
+
+            let mixed: Srgb<u8> = if alpha > 0.0 {
+                Srgb::from_linear(color / alpha)
+            } else {
+                Srgb::new(0, 0, 0)
+            };
+            Rgba([
+                mixed.red,
+                mixed.green,
+                mixed.blue,
+                (alpha * 255.0).round() as u8,
+            ])
+        },
+    ))
+}
+
+/// How a background is fit to the size of the image composited over it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum BackgroundFit {
+    /// Repeat the background from the top-left corner at its own size.
+    #[default]
+    Tile,
+    /// Resize the background to the size of the image.
+    Stretch,
+}
+
+/// Composite an image over a background image in linear light, as for
+/// previewing a sprite in a mockup. Opaque pixels cover the background and
+/// transparent ones show it.
+pub fn composite_over(
+    img: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    background: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    fit: BackgroundFit,
+) -> Result<ImageBuffer<Rgba<u8>, Vec<u8>>, ImageError> {
+    if background.width() == 0 || background.height() == 0 {
+        return Err(ImageError::Parameter(ParameterError::from_kind(
+            ParameterErrorKind::Generic("empty background".to_string()),
+        )));
+    }
+    let stretched;
+    let background = match fit {
+        BackgroundFit::Stretch if background.dimensions() != img.dimensions() => {
+            stretched =
+                imageops::resize(background, img.width(), img.height(), FilterType::Triangle);
+            &stretched
+        }
+        _ => background,
+    };
+
+    Ok(ImageBuffer::from_fn(img.width(), img.height(), |x, y| {
+        let (top, bottom) = (
+            img.get_pixel(x, y),
+            background.get_pixel(x % background.width(), y % background.height()),
+        );
+        let premultiplied = |pixel: &Rgba<u8>| {
+            let [r, g, b, a] = pixel.0;
+            let alpha = a as f32 / 255.0;
+            let linear: LinSrgb = Srgb::new(r, g, b).into_format::<f32>().into_linear();
+            (linear * alpha, alpha)
+        };
+        let (color_top, alpha_top) = premultiplied(top);
+        let (color_bottom, alpha_bottom) = premultiplied(bottom);
+        let color = color_top + color_bottom * (1.0 - alpha_top);
+        let alpha = alpha_top + alpha_bottom * (1.0 - alpha_top);
+
+        let mixed: Srgb<u8> = if alpha > 0.0 {
+            Srgb::from_linear(color / alpha)
+        } else {
+            Srgb::new(0, 0, 0)
+        };
+        Rgba([
+            mixed.red,
+            mixed.green,
+            mixed.blue,
+            (alpha * 255.0).round() as u8,
+        ])
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blend_ends_are_the_original_and_filtered() {
+        let original =
+            ImageBuffer::from_fn(4, 4, |x, y| Rgba([x as u8 * 60, y as u8 * 60, 30, 200]));
+        let filtered = crate::filter::run(&original).unwrap();
+        assert_eq!(blend(&original, &filtered, 0.0).unwrap(), original);
+        assert_eq!(blend(&original, &filtered, 1.0).unwrap(), filtered);
+    }
+
+    #[test]
+    fn blend_mixes_in_linear_light_and_ignores_transparent_colors() {
+        let black = ImageBuffer::from_pixel(1, 1, Rgba([0, 0, 0, 255]));
+        let white = ImageBuffer::from_pixel(1, 1, Rgba([255, 255, 255, 255]));
+        assert_eq!(
+            blend(&black, &white, 0.5).unwrap().get_pixel(0, 0).0,
+            [188, 188, 188, 255]
+        );
+
+        let clear = ImageBuffer::from_pixel(1, 1, Rgba([0, 0, 255, 0]));
+        let red = ImageBuffer::from_pixel(1, 1, Rgba([255, 0, 0, 255]));
+        assert_eq!(
+            blend(&clear, &red, 0.5).unwrap().get_pixel(0, 0).0,
+            [255, 0, 0, 128]
+        );
+    }
+
+    #[test]
+    fn blend_rejects_mismatched_sizes() {
+        let img = ImageBuffer::new(2, 2);
+        assert!(blend(&img, &ImageBuffer::new(2, 3), 0.5).is_err());
+    }
+
+    #[test]
+    fn composite_covers_with_opaque_and_shows_through_transparent() {
+        let img = ImageBuffer::from_fn(4, 1, |x, _| match x {
+            0 => Rgba([255, 0, 0, 255]),
+            1 => Rgba([255, 0, 0, 0]),
+            _ => Rgba([255, 255, 255, 128]),
+        });
+        // a 2×1 background tiles across the image
+        let background = ImageBuffer::from_fn(2, 1, |x, _| Rgba([0, x as u8 * 255, 0, 255]));
+        let output = composite_over(&img, &background, BackgroundFit::Tile).unwrap();
+        assert_eq!(output.get_pixel(0, 0).0, [255, 0, 0, 255]);
+        assert_eq!(output.get_pixel(1, 0).0, [0, 255, 0, 255]);
+        // half white over black mixes in linear light
+        assert_eq!(output.get_pixel(2, 0).0, [188, 188, 188, 255]);
+        assert_eq!(output.get_pixel(3, 0).0, [188, 255, 188, 255]);
+
+        let stretched = composite_over(&img, &background, BackgroundFit::Stretch).unwrap();
+        assert_eq!(stretched.dimensions(), (4, 1));
+        assert_eq!(stretched.get_pixel(1, 0).0[3], 255);
+        assert!(composite_over(&img, &ImageBuffer::new(0, 0), BackgroundFit::Tile).is_err());
+    }
+}
@@ -0,0 +1,60 @@
+use image::{
+    error::{ParameterError, ParameterErrorKind},
+    ImageBuffer, ImageError, Rgba,
+};
+
+pub const CHANGED_COLOR: Rgba<u8> = Rgba([255, 0, 0, 255]);
+pub const UNCHANGED_COLOR: Rgba<u8> = Rgba([0, 0, 0, 0]);
+
+/// Highlight the pixels that differ between two images of the same size.
+pub fn diff(
+    a: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    b: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+) -> Result<ImageBuffer<Rgba<u8>, Vec<u8>>, ImageError> {
+    if a.dimensions() != b.dimensions() {
+        return Err(ImageError::Parameter(ParameterError::from_kind(
+            ParameterErrorKind::DimensionMismatch,
+        )));
+    }
+
+    Ok(ImageBuffer::from_fn(a.width(), a.height(), |x, y| {
+        if a.get_pixel(x, y) == b.get_pixel(x, y) {
+            UNCHANGED_COLOR
+        } else {
+            CHANGED_COLOR
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn marks_only_changed_pixels() {
+        let a = ImageBuffer::from_pixel(3, 2, Rgba([10, 20, 30, 255]));
+        let mut b = a.clone();
+        b.put_pixel(1, 0, Rgba([10, 20, 31, 255]));
+        b.put_pixel(2, 1, Rgba([10, 20, 30, 254]));
+
+        let output = diff(&a, &b).unwrap();
+        for (x, y, pixel) in output.enumerate_pixels() {
+            let changed = (x, y) == (1, 0) || (x, y) == (2, 1);
+            assert_eq!(
+                *pixel,
+                if changed {
+                    CHANGED_COLOR
+                } else {
+                    UNCHANGED_COLOR
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_mismatched_sizes() {
+        let a = ImageBuffer::new(3, 2);
+        let b = ImageBuffer::new(2, 3);
+        assert!(matches!(diff(&a, &b), Err(ImageError::Parameter(_))));
+    }
+}
@@ -0,0 +1,599 @@
+use crate::{
+    filter::{hex_to_rgb8, remap, IndexedImage},
+    sheet::pack_sheet,
+};
+use image::{
+    codecs::png::CompressionType,
+    error::{EncodingError, ParameterError, ParameterErrorKind},
+    ImageBuffer, ImageError, ImageFormat, Rgb, Rgba,
+};
+use palette::Oklab;
+use std::io::Write;
+
+/// Alpha below which an indexed pixel is written as transparent.
+const TRANSPARENT_BELOW: u8 = 128;
+
+/// Write an 8-bit indexed PNG whose PLTE chunk lists `palette_hex` in order,
+/// so that editors such as Aseprite import it with the same palette and the
+/// same index for every pixel.
+///
+/// Indexed PNGs can't store alpha per pixel, so pixels with alpha below 128
+/// use an extra fully transparent entry appended after the palette.
+pub fn write_indexed_png<W: Write>(
+    writer: W,
+    indexed: &IndexedImage,
+    palette_hex: &[&str],
+) -> Result<(), ImageError> {
+    let mut plte: Vec<u8> = palette_hex
+        .iter()
+        .flat_map(|hex| hex_to_rgb8(hex).unwrap())
+        .collect();
+    let mut data: Vec<u8> = indexed.indices.iter().map(|&i| i as u8).collect();
+    let mut trns = vec![];
+
+    let transparent = palette_hex.len();
+    if indexed.alpha.iter().any(|&a| a < TRANSPARENT_BELOW) {
+        plte.extend([0, 0, 0]);
+        trns = vec![255; transparent];
+        trns.push(0);
+        for (index, &a) in data.iter_mut().zip(&indexed.alpha) {
+            if a < TRANSPARENT_BELOW {
+                *index = transparent as u8;
+            }
+        }
+    }
+    if plte.len() / 3 > 256 {
+        return Err(ImageError::Parameter(ParameterError::from_kind(
+            ParameterErrorKind::Generic(format!(
+                "indexed PNGs hold at most 256 colors, got {}",
+                plte.len() / 3
+            )),
+        )));
+    }
+
+    let mut encoder = png::Encoder::new(writer, indexed.width, indexed.height);
+    encoder.set_color(png::ColorType::Indexed);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_palette(plte);
+    if !trns.is_empty() {
+        encoder.set_trns(trns);
+    }
+    encoder
+        .write_header()
+        .and_then(|mut writer| writer.write_image_data(&data))
+        .map_err(png_error)
+}
+
+/// Write an RGBA PNG with a tEXt chunk for each keyword and text pair, such
+/// as `("Software", ...)` to record what produced it, and a pHYs chunk with
+/// the resolution in dots per inch if given, so print tools size it right.
+pub fn write_png_with_metadata<W: Write>(
+    writer: W,
+    buffer: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    text: &[(&str, &str)],
+    dpi: Option<u32>,
+    compression: CompressionType,
+) -> Result<(), ImageError> {
+    let mut encoder = png::Encoder::new(writer, buffer.width(), buffer.height());
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_compression(match compression {
+        CompressionType::Fast => png::Compression::Fast,
+        CompressionType::Best => png::Compression::Best,
+        _ => png::Compression::Default,
+    });
+    if let Some(dpi) = dpi {
+        // pHYs only knows pixels per meter
+        let pixels_per_meter = (dpi as f64 / 0.0254).round() as u32;
+        encoder.set_pixel_dims(Some(png::PixelDimensions {
+            xppu: pixels_per_meter,
+            yppu: pixels_per_meter,
+            unit: png::Unit::Meter,
+        }));
+    }
+    for (keyword, text) in text {
+        encoder
+            .add_text_chunk(keyword.to_string(), text.to_string())
+            .map_err(png_error)?;
+    }
+    encoder
+        .write_header()
+        .and_then(|mut writer| writer.write_image_data(buffer.as_raw()))
+        .map_err(png_error)
+}
+
+fn png_error(e: png::EncodingError) -> ImageError {
+    ImageError::Encoding(EncodingError::new(ImageFormat::Png.into(), e))
+}
+
+/// Width and height in pixels of each color in [`render_swatches`].
+pub const SWATCH_SIZE: u32 = 32;
+/// Height of the label under each swatch: two lines of glyphs with a pixel
+/// of spacing around each.
+pub const LABEL_HEIGHT: u32 = 2 * (GLYPH_HEIGHT + 2);
+const SWATCH_COLUMNS: u32 = 8;
+const SWATCH_PADDING: u32 = 2;
+const LABEL_BACKGROUND: Rgba<u8> = Rgba([255, 255, 255, 255]);
+const LABEL_COLOR: Rgba<u8> = Rgba([0, 0, 0, 255]);
+
+const GLYPH_WIDTH: u32 = 3;
+const GLYPH_HEIGHT: u32 = 5;
+/// 3×5 bitmaps of the hex digits, one row per byte with the leftmost pixel
+/// in the highest bit. Bundled, since there are no system fonts to draw with
+/// in WASM or headless.
+const GLYPHS: [[u8; GLYPH_HEIGHT as usize]; 16] = [
+    [0b111, 0b101, 0b101, 0b101, 0b111],
+    [0b010, 0b110, 0b010, 0b010, 0b111],
+    [0b111, 0b001, 0b111, 0b100, 0b111],
+    [0b111, 0b001, 0b111, 0b001, 0b111],
+    [0b101, 0b101, 0b111, 0b001, 0b001],
+    [0b111, 0b100, 0b111, 0b001, 0b111],
+    [0b111, 0b100, 0b111, 0b101, 0b111],
+    [0b111, 0b001, 0b001, 0b001, 0b001],
+    [0b111, 0b101, 0b111, 0b101, 0b111],
+    [0b111, 0b101, 0b111, 0b001, 0b111],
+    [0b010, 0b101, 0b111, 0b101, 0b101],
+    [0b110, 0b101, 0b110, 0b101, 0b110],
+    [0b011, 0b100, 0b100, 0b100, 0b011],
+    [0b110, 0b101, 0b101, 0b101, 0b110],
+    [0b111, 0b100, 0b111, 0b100, 0b111],
+    [0b111, 0b100, 0b111, 0b100, 0b100],
+];
+
+/// Render a palette as a grid of swatches, each labeled with its index and
+/// hex code, for sharing the palette as a single image.
+pub fn render_swatches(palette_hex: &[&str]) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+    let cells: Vec<_> = palette_hex
+        .iter()
+        .enumerate()
+        .map(|(index, hex)| {
+            let [r, g, b] = hex_to_rgb8(hex).unwrap();
+            let mut cell = ImageBuffer::from_pixel(
+                SWATCH_SIZE,
+                SWATCH_SIZE + LABEL_HEIGHT,
+                Rgba([r, g, b, 255]),
+            );
+            for y in SWATCH_SIZE..cell.height() {
+                for x in 0..cell.width() {
+                    cell.put_pixel(x, y, LABEL_BACKGROUND);
+                }
+            }
+            draw_text(&mut cell, &index.to_string(), 1, SWATCH_SIZE + 1);
+            draw_text(&mut cell, hex, 1, SWATCH_SIZE + GLYPH_HEIGHT + 3);
+            cell
+        })
+        .collect();
+    pack_sheet(&cells, SWATCH_COLUMNS, SWATCH_PADDING)
+}
+
+/// Draw hex digits with their top-left corner at `x`, `y`, clipped to the
+/// image. Other characters are left blank.
+fn draw_text(img: &mut ImageBuffer<Rgba<u8>, Vec<u8>>, text: &str, x: u32, y: u32) {
+    for (i, c) in text.chars().enumerate() {
+        let Some(digit) = c.to_digit(16) else {
+            continue;
+        };
+        let left = x + i as u32 * (GLYPH_WIDTH + 1);
+        for (row, bits) in GLYPHS[digit as usize].iter().enumerate() {
+            for column in 0..GLYPH_WIDTH {
+                let (px, py) = (left + column, y + row as u32);
+                if bits & (1 << (GLYPH_WIDTH - 1 - column)) != 0
+                    && px < img.width()
+                    && py < img.height()
+                {
+                    img.put_pixel(px, py, LABEL_COLOR);
+                }
+            }
+        }
+    }
+}
+
+/// Generate Rust source with an indexed image as `const` arrays, for
+/// embedding sprites in `no_std` programs.
+///
+/// The constants are named with the given prefix, which must itself be a
+/// valid identifier: `{name}_WIDTH`, `{name}_HEIGHT`, `{name}_PALETTE` with
+/// the RGB of each palette index, and `{name}_PIXELS` and `{name}_ALPHA` with
+/// the index and alpha of each pixel, row by row.
+pub fn indexed_to_rust(indexed: &IndexedImage, palette_hex: &[&str], name: &str) -> String {
+    let index_type = if palette_hex.len() <= 256 {
+        "u8"
+    } else {
+        "u16"
+    };
+    let row_len = indexed.width.max(1) as usize;
+    let rows = |values: Vec<String>| -> String {
+        values
+            .chunks(row_len)
+            .map(|row| format!("    {},\n", row.join(", ")))
+            .collect()
+    };
+    let pixel_count = indexed.indices.len();
+
+    let mut source = String::new();
+    source += &format!("pub const {}_WIDTH: usize = {};\n", name, indexed.width);
+    source += &format!("pub const {}_HEIGHT: usize = {};\n", name, indexed.height);
+    source += &format!(
+        "pub const {}_PALETTE: [[u8; 3]; {}] = [\n",
+        name,
+        palette_hex.len()
+    );
+    for hex in palette_hex {
+        let [r, g, b] = hex_to_rgb8(hex).unwrap();
+        source += &format!("    [0x{:02x}, 0x{:02x}, 0x{:02x}],\n", r, g, b);
+    }
+    source += "];\n";
+    source += &format!(
+        "pub const {}_PIXELS: [{}; {}] = [\n{}];\n",
+        name,
+        index_type,
+        pixel_count,
+        rows(indexed.indices.iter().map(|i| i.to_string()).collect())
+    );
+    source += &format!(
+        "pub const {}_ALPHA: [u8; {}] = [\n{}];\n",
+        name,
+        pixel_count,
+        rows(indexed.alpha.iter().map(|a| a.to_string()).collect())
+    );
+    source
+}
+
+/// The Oklab color of each pixel's palette entry as `l`, `a` and `b` floats,
+/// exactly as matched rather than rounded to 8-bit sRGB, for further
+/// processing. Alpha is not included.
+pub fn indexed_to_oklab(
+    indexed: &IndexedImage,
+    palette: &[Oklab],
+) -> ImageBuffer<Rgb<f32>, Vec<f32>> {
+    ImageBuffer::from_fn(indexed.width, indexed.height, |x, y| {
+        let color = palette[indexed.index(x, y)];
+        Rgb([color.l, color.a, color.b])
+    })
+}
+
+/// Pack the palette indices of an indexed image two per byte, the first
+/// pixel of each pair in the high nibble, as in the native 4bpp layout of
+/// 16-color hardware. Rows are padded with zeros to a multiple of
+/// `row_align` bytes, and an odd width leaves the last low nibble zero.
+/// Alpha is not included.
+///
+/// Fails if the palette has more than 16 colors.
+pub fn indexed_to_4bpp(
+    indexed: &IndexedImage,
+    palette_hex: &[&str],
+    row_align: usize,
+) -> Result<Vec<u8>, ImageError> {
+    if palette_hex.len() > 16 {
+        return Err(ImageError::Parameter(ParameterError::from_kind(
+            ParameterErrorKind::Generic(format!(
+                "4bpp holds at most 16 colors, got {}",
+                palette_hex.len()
+            )),
+        )));
+    }
+
+    let row_align = row_align.max(1);
+    let row_len = (indexed.width as usize).div_ceil(2).div_ceil(row_align) * row_align;
+    let mut packed = Vec::with_capacity(row_len * indexed.height as usize);
+    for row in indexed.indices.chunks(indexed.width.max(1) as usize) {
+        let start = packed.len();
+        packed.extend(
+            row.chunks(2)
+                .map(|pair| (pair[0] as u8) << 4 | pair.get(1).map_or(0, |&i| i as u8)),
+        );
+        packed.resize(start + row_len, 0);
+    }
+    Ok(packed)
+}
+
+/// Bake the closest palette color of every input color into a 3D lookup
+/// table of `size` steps per channel, for applying the palette in a shader.
+/// Returns RGB bytes with red changing fastest, then green, then blue, as in
+/// `.cube` files; see [`lut_to_cube`].
+///
+/// Only the mapping without dithering is captured, since dithering depends
+/// on the position of each pixel too.
+pub fn export_lut(palette_hex: &[&str], size: u32) -> Result<Vec<u8>, ImageError> {
+    if size < 2 {
+        return Err(ImageError::Parameter(ParameterError::from_kind(
+            ParameterErrorKind::Generic(format!("a LUT needs at least 2 steps, got {}", size)),
+        )));
+    }
+    let step = |i: u32| ((i * 255 + (size - 1) / 2) / (size - 1)) as u8;
+    // one row per green and blue pair, so that rows follow the LUT order
+    let grid = ImageBuffer::from_fn(size, size * size, |x, y| {
+        Rgba([step(x), step(y % size), step(y / size), 255])
+    });
+    Ok(remap(&grid, palette_hex)?
+        .pixels()
+        .flat_map(|pixel| [pixel.0[0], pixel.0[1], pixel.0[2]])
+        .collect())
+}
+
+/// Format a LUT from [`export_lut`] as an Adobe `.cube` file.
+pub fn lut_to_cube(lut: &[u8], size: u32) -> String {
+    let mut cube = format!("LUT_3D_SIZE {}\n", size);
+    for rgb in lut.chunks(3) {
+        let [r, g, b] = [rgb[0], rgb[1], rgb[2]].map(|c| c as f32 / 255.0);
+        cube += &format!("{:.6} {:.6} {:.6}\n", r, g, b);
+    }
+    cube
+}
+
+/// Write an indexed image as CSV: a `width` row, a `height` row and a
+/// `palette` row of hex codes, each led by its name, followed by one row of
+/// palette indices per image row. Alpha is not included.
+pub fn indexed_to_csv(indexed: &IndexedImage, palette_hex: &[&str]) -> String {
+    let mut csv = format!(
+        "width,{}\nheight,{}\npalette,{}\n",
+        indexed.width,
+        indexed.height,
+        palette_hex.join(",")
+    );
+    for row in indexed.indices.chunks(indexed.width.max(1) as usize) {
+        let row: Vec<_> = row.iter().map(|index| index.to_string()).collect();
+        csv += &row.join(",");
+        csv.push('\n');
+    }
+    csv
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::{run_indexed, Options, PALETTE_HEX_2};
+
+    fn decode_indexed(bytes: &[u8]) -> (Vec<u8>, Option<Vec<u8>>, Vec<u8>) {
+        let mut reader = png::Decoder::new(bytes).read_info().unwrap();
+        let mut data = vec![0; reader.output_buffer_size()];
+        reader.next_frame(&mut data).unwrap();
+        let info = reader.info();
+        assert_eq!(info.color_type, png::ColorType::Indexed);
+        (
+            info.palette.as_ref().unwrap().to_vec(),
+            info.trns.as_ref().map(|trns| trns.to_vec()),
+            data,
+        )
+    }
+
+    #[test]
+    fn indexed_png_embeds_the_palette_in_order() {
+        let palette_hex = ["ff0000", "00ff00", "0000ff"];
+        let indexed = IndexedImage {
+            width: 3,
+            height: 1,
+            indices: vec![2, 0, 1],
+            alpha: vec![255; 3],
+        };
+        let mut bytes = vec![];
+        write_indexed_png(&mut bytes, &indexed, &palette_hex).unwrap();
+        let (plte, trns, data) = decode_indexed(&bytes);
+        assert_eq!(plte, [255, 0, 0, 0, 255, 0, 0, 0, 255]);
+        assert_eq!(trns, None);
+        assert_eq!(data, [2, 0, 1]);
+    }
+
+    #[test]
+    fn indexed_png_appends_a_transparent_entry() {
+        let indexed = IndexedImage {
+            width: 2,
+            height: 1,
+            indices: vec![0, 0],
+            alpha: vec![255, 0],
+        };
+        let mut bytes = vec![];
+        write_indexed_png(&mut bytes, &indexed, &["ffffff"]).unwrap();
+        let (plte, trns, data) = decode_indexed(&bytes);
+        assert_eq!(plte, [255, 255, 255, 0, 0, 0]);
+        assert_eq!(trns, Some(vec![255, 0]));
+        assert_eq!(data, [0, 1]);
+    }
+
+    #[test]
+    fn indexed_png_rejects_more_than_256_colors() {
+        let palette_hex = vec!["000000"; 257];
+        let indexed = IndexedImage {
+            width: 1,
+            height: 1,
+            indices: vec![0],
+            alpha: vec![255],
+        };
+        assert!(write_indexed_png(vec![], &indexed, &palette_hex).is_err());
+    }
+
+    #[test]
+    fn swatches_leave_room_for_labels() {
+        let palette_hex = ["ff0000"; 10];
+        let swatches = render_swatches(&palette_hex);
+        let cell_height = SWATCH_SIZE + LABEL_HEIGHT;
+        assert_eq!(
+            swatches.dimensions(),
+            (
+                8 * SWATCH_SIZE + 7 * SWATCH_PADDING,
+                2 * cell_height + SWATCH_PADDING
+            )
+        );
+        assert_eq!(swatches.get_pixel(0, 0).0, [255, 0, 0, 255]);
+        // each label has some ink on its background
+        let label: Vec<_> = (0..SWATCH_SIZE)
+            .flat_map(|x| (SWATCH_SIZE..cell_height).map(move |y| (x, y)))
+            .map(|(x, y)| *swatches.get_pixel(x, y))
+            .collect();
+        assert!(label.contains(&LABEL_COLOR));
+        assert!(label
+            .iter()
+            .all(|&pixel| pixel == LABEL_COLOR || pixel == LABEL_BACKGROUND));
+    }
+
+    #[test]
+    fn rust_source_compiles_and_reconstructs_the_image() {
+        let palette_hex = ["ff0000", "00ff00", "0000ff"];
+        let indexed = IndexedImage {
+            width: 3,
+            height: 2,
+            indices: vec![0, 1, 2, 2, 1, 0],
+            alpha: vec![255, 255, 0, 255, 128, 255],
+        };
+        let mut source = indexed_to_rust(&indexed, &palette_hex, "SPRITE");
+        source += &format!(
+            "fn main() {{
+                assert_eq!((SPRITE_WIDTH, SPRITE_HEIGHT), (3, 2));
+                assert_eq!(SPRITE_PALETTE, [[255, 0, 0], [0, 255, 0], [0, 0, 255]]);
+                assert_eq!(SPRITE_PIXELS, {:?});
+                assert_eq!(SPRITE_ALPHA, {:?});
+            }}\n",
+            indexed.indices, indexed.alpha
+        );
+
+        let dir = std::env::temp_dir().join(format!("rust_out_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (path, binary) = (dir.join("sprite.rs"), dir.join("sprite"));
+        std::fs::write(&path, source).unwrap();
+        let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+        let compiled = std::process::Command::new(rustc)
+            .arg(&path)
+            .arg("-o")
+            .arg(&binary)
+            .status()
+            .unwrap();
+        assert!(compiled.success());
+        assert!(std::process::Command::new(&binary)
+            .status()
+            .unwrap()
+            .success());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn csv_rereads_as_the_same_index_grid() {
+        let palette_hex = ["000000", "ffffff", "ff0000"];
+        let indexed = IndexedImage {
+            width: 3,
+            height: 2,
+            indices: vec![0, 1, 2, 2, 0, 1],
+            alpha: vec![255; 6],
+        };
+        let csv = indexed_to_csv(&indexed, &palette_hex);
+        let mut lines = csv.lines();
+        assert_eq!(lines.next(), Some("width,3"));
+        assert_eq!(lines.next(), Some("height,2"));
+        assert_eq!(lines.next(), Some("palette,000000,ffffff,ff0000"));
+        let indices: Vec<usize> = lines
+            .flat_map(|line| line.split(','))
+            .map(|index| index.parse().unwrap())
+            .collect();
+        assert_eq!(indices, indexed.indices);
+        assert_eq!(csv.lines().count(), 3 + 2);
+    }
+
+    #[test]
+    fn png_metadata_round_trips() {
+        let buffer = ImageBuffer::from_pixel(2, 2, Rgba([10, 20, 30, 40]));
+        let mut bytes = vec![];
+        write_png_with_metadata(
+            &mut bytes,
+            &buffer,
+            &[("Software", "pixel-filter 0.1.0 (features: none)")],
+            Some(300),
+            CompressionType::Default,
+        )
+        .unwrap();
+
+        let mut reader = png::Decoder::new(bytes.as_slice()).read_info().unwrap();
+        let info = reader.info();
+        let text = &info.uncompressed_latin1_text;
+        assert_eq!(text.len(), 1);
+        assert_eq!(text[0].keyword, "Software");
+        assert_eq!(text[0].text, "pixel-filter 0.1.0 (features: none)");
+        let dims = info.pixel_dims.unwrap();
+        assert_eq!((dims.xppu, dims.unit), (11811, png::Unit::Meter));
+        let mut data = vec![0; reader.output_buffer_size()];
+        reader.next_frame(&mut data).unwrap();
+        assert_eq!(data, buffer.into_raw());
+    }
+
+    #[test]
+    fn oklab_output_keeps_the_exact_palette_values() {
+        // out of gamut, so 8-bit sRGB would clamp it
+        let palette = [Oklab::new(0.25, 0.01, -0.02), Oklab::new(0.8, -0.35, 0.2)];
+        let indexed = IndexedImage {
+            width: 2,
+            height: 2,
+            indices: vec![0, 1, 1, 0],
+            alpha: vec![255, 0, 255, 255],
+        };
+        let oklab = indexed_to_oklab(&indexed, &palette);
+        assert_eq!(oklab.get_pixel(0, 0).0, [0.25, 0.01, -0.02]);
+        assert_eq!(oklab.get_pixel(1, 0).0, [0.8, -0.35, 0.2]);
+        assert_eq!(oklab.get_pixel(0, 1).0, [0.8, -0.35, 0.2]);
+        assert_eq!(oklab.get_pixel(1, 1).0, [0.25, 0.01, -0.02]);
+    }
+
+    #[test]
+    fn unpacking_4bpp_reproduces_the_indices() {
+        let img = ImageBuffer::from_fn(7, 5, |x, y| Rgba([x as u8 * 40, y as u8 * 60, 96, 255]));
+        let options = Options {
+            palette_hex: &PALETTE_HEX_2,
+            ..Default::default()
+        };
+        let indexed = run_indexed(&img, &options).unwrap();
+        for row_align in [1, 3] {
+            let packed = indexed_to_4bpp(&indexed, &PALETTE_HEX_2, row_align).unwrap();
+            let row_len = 4usize.div_ceil(row_align) * row_align;
+            assert_eq!(packed.len(), row_len * 5);
+            let mut unpacked: Vec<usize> = vec![];
+            for row in packed.chunks(row_len) {
+                let nibbles = row
+                    .iter()
+                    .flat_map(|&byte| [byte as usize >> 4, byte as usize & 15]);
+                // the odd width leaves a zero nibble at the end of each row
+                let nibbles: Vec<usize> = nibbles.collect();
+                assert_eq!(nibbles[7], 0);
+                assert!(row[4..].iter().all(|&byte| byte == 0));
+                unpacked.extend(&nibbles[..7]);
+            }
+            assert_eq!(unpacked, indexed.indices);
+        }
+
+        let palette_hex = vec!["000000"; 17];
+        let error = indexed_to_4bpp(&indexed, &palette_hex, 1).unwrap_err();
+        assert!(error.to_string().contains("at most 16 colors, got 17"));
+    }
+
+    #[test]
+    fn lut_maps_palette_colors_to_themselves() {
+        // every palette color lies on the grid of 5 steps: 0, 64, 128, 191, 255
+        let palette_hex = ["000000", "ff0000", "4080bf", "ffffff"];
+        let size = 5;
+        let lut = export_lut(&palette_hex, size).unwrap();
+        assert_eq!(lut.len(), 5 * 5 * 5 * 3);
+        let step = |c: u8| [0, 64, 128, 191, 255].iter().position(|&s| s == c).unwrap();
+        let palette: Vec<[u8; 3]> = palette_hex
+            .iter()
+            .map(|hex| hex_to_rgb8(hex).unwrap())
+            .collect();
+        for rgb in &palette {
+            // red changes fastest
+            let entry =
+                step(rgb[0]) + size as usize * (step(rgb[1]) + size as usize * step(rgb[2]));
+            assert_eq!(lut[entry * 3..entry * 3 + 3], *rgb);
+        }
+        assert!(lut
+            .chunks(3)
+            .all(|rgb| palette.iter().any(|c| c[..] == *rgb)));
+
+        let cube = lut_to_cube(&lut, size);
+        assert_eq!(cube.lines().count(), 1 + 5 * 5 * 5);
+        let mut lines = cube.lines();
+        assert_eq!(lines.next(), Some("LUT_3D_SIZE 5"));
+        for (line, rgb) in lines.zip(lut.chunks(3)) {
+            let values: Vec<u8> = line
+                .split(' ')
+                .map(|value| (value.parse::<f32>().unwrap() * 255.0).round() as u8)
+                .collect();
+            assert_eq!(values, rgb);
+        }
+        assert!(export_lut(&palette_hex, 1).is_err());
+    }
+}
@@ -0,0 +1,482 @@
+use crate::filter::{palette_as_oklab, rgb_to_hex};
+use image::{ImageBuffer, Rgba};
+use palette::{color_difference::EuclideanDistance, IntoColor, Oklab, Srgb};
+use std::collections::HashSet;
+
+/// Collect every distinct color of an image, in order of first appearance.
+///
+/// Fully transparent pixels are skipped. If `max_colors` is given and the
+/// image has more colors than that, the closest ones are merged with
+/// [`simplify_palette`].
+pub fn palette_from_image_exact(
+    img: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    max_colors: Option<usize>,
+) -> Vec<String> {
+    let mut seen = HashSet::new();
+    let mut palette_hex = vec![];
+    for pixel in img.pixels() {
+        let [r, g, b, a] = pixel.0;
+        if a != 0 && seen.insert([r, g, b]) {
+            palette_hex.push(rgb_to_hex(Srgb::new(r, g, b).into_format()));
+        }
+    }
+
+    match max_colors {
+        Some(max_colors) if palette_hex.len() > max_colors => simplify_palette(
+            &palette_hex.iter().map(|s| s.as_str()).collect::<Vec<_>>(),
+            max_colors,
+        ),
+        _ => palette_hex,
+    }
+}
+
+/// Merge the two closest colors in Oklab until at most `max_colors` remain.
+///
+/// Merged colors are averaged, weighted by how many colors went into them.
+pub fn simplify_palette(palette_hex: &[&str], max_colors: usize) -> Vec<String> {
+    let mut colors: Vec<(Oklab, f32)> = palette_as_oklab(palette_hex)
+        .into_iter()
+        .map(|color| (color, 1.0))
+        .collect();
+
+    while colors.len() > max_colors.max(1) {
+        let mut closest = (0, 1);
+        let mut dist_of_closest = f32::MAX;
+        for i in 0..colors.len() {
+            for j in i + 1..colors.len() {
+                let d = colors[i].0.distance_squared(colors[j].0);
+                if d < dist_of_closest {
+                    dist_of_closest = d;
+                    closest = (i, j);
+                }
+            }
+        }
+
+        let (i, j) = closest;
+        let (color_j, weight_j) = colors.remove(j);
+        let (color_i, weight_i) = colors[i];
+        let weight = weight_i + weight_j;
+        colors[i] = ((color_i * weight_i + color_j * weight_j) / weight, weight);
+    }
+
+    colors
+        .into_iter()
+        .map(|(color, _)| rgb_to_hex(color.into_color()))
+        .collect()
+}
+
+/// Most pixels sampled from all images combined when clustering.
+const MAX_SAMPLES: usize = 16384;
+const KMEANS_ITERATIONS: usize = 16;
+
+/// Generate a palette of up to `n` colors by k-means clustering in Oklab.
+///
+/// Colors closer than `min_separation` in Oklab to an earlier one are left
+/// out, so a large separation gives fewer but more distinct colors.
+pub fn generate_palette_kmeans(
+    img: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    n: usize,
+    min_separation: f32,
+) -> Vec<String> {
+    generate_shared_palette_kmeans(std::slice::from_ref(img), n, min_separation)
+}
+
+/// Generate one palette of up to `n` colors from several images combined,
+/// so that a whole set of sprites can be filtered consistently.
+pub fn generate_shared_palette_kmeans(
+    imgs: &[ImageBuffer<Rgba<u8>, Vec<u8>>],
+    n: usize,
+    min_separation: f32,
+) -> Vec<String> {
+    kmeans(&sample_colors(imgs), &[], n, min_separation)
+        .into_iter()
+        .map(|color| rgb_to_hex(color.into_color()))
+        .collect()
+}
+
+/// Algorithm used to generate a palette from images.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Quantizer {
+    /// K-means clustering, slower but fitting clusters more closely.
+    #[default]
+    Kmeans,
+    /// Median cut, fast and classic.
+    MedianCut,
+}
+
+/// Generate a palette of up to `n` colors by median cut in Oklab, splitting
+/// along perceptual axes.
+pub fn generate_palette_median_cut(img: &ImageBuffer<Rgba<u8>, Vec<u8>>, n: usize) -> Vec<String> {
+    generate_shared_palette_median_cut(std::slice::from_ref(img), n)
+}
+
+/// Like [`generate_palette_median_cut`], for several images combined.
+pub fn generate_shared_palette_median_cut(
+    imgs: &[ImageBuffer<Rgba<u8>, Vec<u8>>],
+    n: usize,
+) -> Vec<String> {
+    median_cut(&sample_colors(imgs), n)
+        .0
+        .into_iter()
+        .map(|color| rgb_to_hex(color.into_color()))
+        .collect()
+}
+
+/// Append up to `add_n` colors to a palette that poorly covers an image.
+///
+/// The new colors are cluster centers of the pixels farthest from the
+/// palette, while the existing colors stay unchanged and in order. New
+/// colors closer than `min_separation` to any other are left out.
+pub fn extend_palette(
+    img: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    palette_hex: &[&str],
+    add_n: usize,
+    min_separation: f32,
+) -> Vec<String> {
+    extend_shared_palette(
+        std::slice::from_ref(img),
+        palette_hex,
+        add_n,
+        min_separation,
+    )
+}
+
+/// Like [`extend_palette`], for several images combined.
+pub fn extend_shared_palette(
+    imgs: &[ImageBuffer<Rgba<u8>, Vec<u8>>],
+    palette_hex: &[&str],
+    add_n: usize,
+    min_separation: f32,
+) -> Vec<String> {
+    let added = kmeans(
+        &sample_colors(imgs),
+        &palette_as_oklab(palette_hex),
+        add_n,
+        min_separation,
+    );
+    palette_hex
+        .iter()
+        .map(|hex| hex.to_string())
+        .chain(
+            added
+                .into_iter()
+                .map(|color| rgb_to_hex(color.into_color())),
+        )
+        .collect()
+}
+
+/// Colors of the non-transparent pixels of all images, evenly subsampled.
+fn sample_colors(imgs: &[ImageBuffer<Rgba<u8>, Vec<u8>>]) -> Vec<Oklab> {
+    let total: usize = imgs.iter().map(|img| img.pixels().len()).sum();
+    imgs.iter()
+        .flat_map(|img| img.pixels())
+        .step_by((total / MAX_SAMPLES).max(1))
+        .filter(|pixel| pixel.0[3] != 0)
+        .map(|pixel| {
+            let [r, g, b, _] = pixel.0;
+            Srgb::new(r, g, b).into_format::<f32>().into_color()
+        })
+        .collect()
+}
+
+/// Lloyd's algorithm, seeded by farthest-point traversal so that the result
+/// is deterministic.
+///
+/// The `fixed` centers attract samples but never move, and only the up to
+/// `n` other centers are returned. Centers closer than `min_separation` to
+/// an earlier one are never seeded, and dropped if they drift that close.
+fn kmeans(samples: &[Oklab], fixed: &[Oklab], n: usize, min_separation: f32) -> Vec<Oklab> {
+    if samples.is_empty() || n == 0 {
+        return vec![];
+    }
+
+    let mut centers = fixed.to_vec();
+    if centers.is_empty() {
+        centers.push(samples[0]);
+    }
+    while centers.len() < fixed.len() + n {
+        let (farthest, distance) = samples
+            .iter()
+            .map(|sample| (*sample, nearest(&centers, *sample).1))
+            .max_by(|(_, d1), (_, d2)| d1.partial_cmp(d2).unwrap())
+            .unwrap();
+        // fewer distinct colors than requested
+        if distance == 0.0 || distance < min_separation.powi(2) {
+            break;
+        }
+        centers.push(farthest);
+    }
+
+    for _ in 0..KMEANS_ITERATIONS {
+        let mut sums = vec![(Oklab::new(0.0, 0.0, 0.0), 0); centers.len()];
+        for sample in samples {
+            let (i, _) = nearest(&centers, *sample);
+            sums[i].0 += *sample;
+            sums[i].1 += 1;
+        }
+
+        let previous = centers.clone();
+        for (center, (sum, count)) in centers.iter_mut().zip(sums).skip(fixed.len()) {
+            if count > 0 {
+                *center = sum / count as f32;
+            }
+        }
+        if centers == previous {
+            break;
+        }
+    }
+
+    let mut kept = fixed.to_vec();
+    for center in centers.split_off(fixed.len()) {
+        if kept
+            .iter()
+            .all(|other| other.distance_squared(center) >= min_separation.powi(2))
+        {
+            kept.push(center);
+        }
+    }
+    kept.split_off(fixed.len())
+}
+
+/// Index of the closest center and its squared distance.
+fn nearest(centers: &[Oklab], color: Oklab) -> (usize, f32) {
+    centers
+        .iter()
+        .map(|center| center.distance_squared(color))
+        .enumerate()
+        .min_by(|(_, d1), (_, d2)| d1.partial_cmp(d2).unwrap())
+        .unwrap()
+}
+
+/// Split the samples into up to `n` boxes in Oklab by repeatedly halving the
+/// box with the widest extent along that axis at its median. Returns the
+/// mean color of each box and the box of each sample.
+pub(crate) fn median_cut(samples: &[Oklab], n: usize) -> (Vec<Oklab>, Vec<usize>) {
+    let channels = |color: &Oklab| [color.l, color.a, color.b];
+    // widest axis of a box and its extent
+    let widest = |members: &[usize]| {
+        (0..3)
+            .map(|axis| {
+                let (min, max) = members.iter().fold((f32::MAX, f32::MIN), |(min, max), &i| {
+                    let value = channels(&samples[i])[axis];
+                    (min.min(value), max.max(value))
+                });
+                (axis, max - min)
+            })
+            .max_by(|(_, e1), (_, e2)| e1.total_cmp(e2))
+            .unwrap()
+    };
+
+    let mut boxes: Vec<Vec<usize>> = vec![];
+    if !samples.is_empty() && n > 0 {
+        boxes.push((0..samples.len()).collect());
+    }
+    while boxes.len() < n {
+        let Some((split, (axis, _))) = boxes
+            .iter()
+            .map(|members| widest(members))
+            .enumerate()
+            .filter(|(_, (_, extent))| *extent > 0.0)
+            .max_by(|(_, (_, e1)), (_, (_, e2))| e1.total_cmp(e2))
+        else {
+            // every box holds a single color
+            break;
+        };
+        let mut members = boxes.swap_remove(split);
+        members
+            .sort_by(|&i, &j| channels(&samples[i])[axis].total_cmp(&channels(&samples[j])[axis]));
+        // keep equal values in one box, so that boxes don't share a color
+        let median = channels(&samples[members[members.len() / 2]])[axis];
+        let mut at = members.partition_point(|&i| channels(&samples[i])[axis] < median);
+        if at == 0 {
+            at = members.partition_point(|&i| channels(&samples[i])[axis] <= median);
+        }
+        let upper = members.split_off(at);
+        boxes.push(members);
+        boxes.push(upper);
+    }
+
+    let mut assignment = vec![0; samples.len()];
+    let centers = boxes
+        .iter()
+        .enumerate()
+        .map(|(b, members)| {
+            let mut sum = Oklab::new(0.0, 0.0, 0.0);
+            for &i in members {
+                sum += samples[i];
+                assignment[i] = b;
+            }
+            sum / members.len() as f32
+        })
+        .collect();
+    (centers, assignment)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::hex_to_rgb8;
+
+    fn image_of(colors: &[[u8; 4]]) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+        ImageBuffer::from_fn(colors.len() as u32, 1, |x, _| Rgba(colors[x as usize]))
+    }
+
+    #[test]
+    fn exact_palette_lists_distinct_colors_in_order() {
+        // a 4-color reference, with repeats and a transparent pixel
+        let img = image_of(&[
+            [255, 0, 0, 255],
+            [0, 0, 255, 255],
+            [255, 0, 0, 128],
+            [0, 255, 0, 0],
+            [0, 255, 0, 255],
+            [0, 0, 255, 255],
+            [255, 255, 255, 255],
+            [0, 255, 0, 255],
+        ]);
+        assert_eq!(
+            palette_from_image_exact(&img, None),
+            ["ff0000", "0000ff", "00ff00", "ffffff"]
+        );
+    }
+
+    #[test]
+    fn exact_palette_merges_closest_colors_beyond_the_limit() {
+        let img = image_of(&[[0, 0, 0, 255], [250, 250, 250, 255], [255, 255, 255, 255]]);
+        let palette_hex = palette_from_image_exact(&img, Some(2));
+        assert_eq!(palette_hex.len(), 2);
+        assert_eq!(palette_hex[0], "000000");
+        // the two near whites merge into one in between
+        assert!(("fafafa".."ffffff").contains(&palette_hex[1].as_str()));
+    }
+
+    #[test]
+    fn shared_palette_covers_every_image() {
+        let red = ImageBuffer::from_pixel(8, 8, Rgba([255, 0, 0, 255]));
+        let blue = ImageBuffer::from_pixel(4, 4, Rgba([0, 0, 255, 255]));
+        let imgs = [red, blue];
+        let mut palette_hex = generate_shared_palette_kmeans(&imgs, 2, 0.0);
+        assert_eq!(palette_hex, generate_shared_palette_kmeans(&imgs, 2, 0.0));
+        palette_hex.sort();
+        assert_eq!(palette_hex, ["0000ff", "ff0000"]);
+    }
+
+    #[test]
+    fn kmeans_stops_at_the_distinct_colors() {
+        let img = ImageBuffer::from_pixel(4, 4, Rgba([10, 200, 30, 255]));
+        assert_eq!(generate_palette_kmeans(&img, 5, 0.0), ["0ac81e"]);
+        assert!(generate_palette_kmeans(&ImageBuffer::new(2, 2), 5, 0.0).is_empty());
+    }
+
+    #[test]
+    fn extending_a_gray_palette_for_a_red_image_adds_red() {
+        let img = ImageBuffer::from_fn(8, 8, |x, _| {
+            if x < 2 {
+                Rgba([128, 128, 128, 255])
+            } else {
+                Rgba([200, 30, 20, 255])
+            }
+        });
+        let palette_hex = ["000000", "808080", "ffffff"];
+        let extended = extend_palette(&img, &palette_hex, 1, 0.0);
+        assert_eq!(extended[..3], palette_hex);
+        assert_eq!(extended.len(), 4);
+        let [r, g, b] = hex_to_rgb8(&extended[3]).unwrap();
+        assert!(r > 150 && g < 80 && b < 80, "{}", extended[3]);
+    }
+
+    #[test]
+    fn extending_a_covering_palette_adds_nothing_close() {
+        let img = ImageBuffer::from_pixel(4, 4, Rgba([128, 128, 128, 255]));
+        let palette_hex = ["808080"];
+        assert_eq!(extend_palette(&img, &palette_hex, 2, 0.05), palette_hex);
+    }
+
+    #[test]
+    fn median_cut_separates_clusters() {
+        let dark = [Oklab::new(0.1, 0.0, 0.0), Oklab::new(0.12, 0.0, 0.0)];
+        let light = [Oklab::new(0.9, 0.0, 0.0), Oklab::new(0.92, 0.0, 0.0)];
+        let samples = [dark[0], light[0], dark[1], light[1]];
+        let (centers, assignment) = median_cut(&samples, 2);
+        assert_eq!(centers.len(), 2);
+        assert_eq!(assignment[0], assignment[2]);
+        assert_eq!(assignment[1], assignment[3]);
+        assert_ne!(assignment[0], assignment[1]);
+        assert!((centers[assignment[0]].l - 0.11).abs() < 1e-6);
+        assert!((centers[assignment[1]].l - 0.91).abs() < 1e-6);
+
+        // never more boxes than distinct colors
+        let (centers, _) = median_cut(&[dark[0], dark[0], light[0]], 8);
+        assert_eq!(centers.len(), 2);
+        assert!(median_cut(&[], 4).0.is_empty());
+    }
+
+    #[test]
+    fn generated_colors_keep_the_minimum_separation() {
+        let img = ImageBuffer::from_fn(32, 32, |x, y| Rgba([x as u8 * 8, y as u8 * 8, 96, 255]));
+        let min_distance = |palette_hex: &[String]| {
+            let hex: Vec<&str> = palette_hex.iter().map(|s| s.as_str()).collect();
+            let colors = palette_as_oklab(&hex);
+            let mut min = f32::MAX;
+            for (i, a) in colors.iter().enumerate() {
+                for b in &colors[i + 1..] {
+                    min = min.min(a.distance_squared(*b).sqrt());
+                }
+            }
+            min
+        };
+
+        let dense = generate_palette_kmeans(&img, 16, 0.0);
+        let sparse = generate_palette_kmeans(&img, 16, 0.15);
+        assert_eq!(dense.len(), 16);
+        assert!(min_distance(&dense) < 0.15);
+        assert!(sparse.len() > 1 && sparse.len() < 16);
+        // hex rounding can move colors slightly
+        assert!(min_distance(&sparse) > 0.149, "{:?}", sparse);
+
+        let extended = extend_palette(&img, &["000000", "ffffff"], 8, 0.15);
+        assert_eq!(extended[..2], ["000000", "ffffff"]);
+        assert!(extended.len() > 2);
+        assert!(min_distance(&extended) > 0.149, "{:?}", extended);
+    }
+
+    #[test]
+    fn median_cut_palette_covers_every_image() {
+        let red = ImageBuffer::from_pixel(8, 8, Rgba([255, 0, 0, 255]));
+        let blue = ImageBuffer::from_pixel(4, 4, Rgba([0, 0, 255, 255]));
+        let imgs = [red, blue];
+        let mut palette_hex = generate_shared_palette_median_cut(&imgs, 4);
+        assert_eq!(palette_hex, generate_shared_palette_median_cut(&imgs, 4));
+        palette_hex.sort();
+        assert_eq!(palette_hex, ["0000ff", "ff0000"]);
+    }
+
+    #[test]
+    fn median_cut_palette_spreads_over_a_gradient() {
+        let img = ImageBuffer::from_fn(64, 1, |x, _| {
+            let c = x as u8 * 4;
+            Rgba([c, c, c, 255])
+        });
+        let palette_hex = generate_palette_median_cut(&img, 4);
+        assert_eq!(palette_hex.len(), 4);
+        let mut grays: Vec<u8> = palette_hex
+            .iter()
+            .map(|hex| hex_to_rgb8(hex).unwrap()[0])
+            .collect();
+        grays.sort();
+        // one box for each quarter of the pixels
+        for (i, gray) in grays.iter().enumerate() {
+            let quarter = i as u8 * 64..=i as u8 * 64 + 60;
+            assert!(quarter.contains(gray), "{:?}", grays);
+        }
+
+        // k-means moves its centers to the mean of their closest pixels
+        // instead of splitting the pixels evenly, so it settles elsewhere
+        let mut kmeans = generate_palette_kmeans(&img, 4, 0.0);
+        assert_eq!(kmeans.len(), 4);
+        kmeans.sort();
+        let mut median_cut = palette_hex;
+        median_cut.sort();
+        assert_ne!(kmeans, median_cut);
+    }
+}
@@ -1,11 +1,25 @@
+use crate::{
+    approx::{to_fixed, FixedOklab},
+    extract::median_cut,
+};
 use anyhow::Result;
-use image::{ImageBuffer, ImageError, Rgba};
-use palette::{color_difference::EuclideanDistance, IntoColor, Oklab, Srgb};
+use image::{
+    error::{ParameterError, ParameterErrorKind},
+    ImageBuffer, ImageError, Luma, Rgba,
+};
+use palette::{convert::IntoColorUnclamped, IntoColor, Lab, LinSrgb, Oklab, Oklch, ShiftHue, Srgb};
+use std::ops::Range;
 
 pub const THRESHOLD_MAP: [[usize; 2]; 2] = [[0, 2], [3, 1]];
 pub const MAP_SIZE: usize = THRESHOLD_MAP.len();
 pub const COLOR_DITHER: f32 = 0.04;
 pub const ALPHA_DITHER: f32 = 0.12;
+/// `color_dither` at a dither strength of 100.
+pub const MAX_COLOR_DITHER: f32 = 0.25;
+/// Oklch chroma below which a color counts as gray for `hue_range`.
+const ACHROMATIC_CHROMA: f32 = 0.02;
+/// Output of pixels skipped by `skip_transparent`.
+pub const SKIPPED_COLOR: Rgba<u8> = Rgba([0, 0, 0, 0]);
 
 pub const PALETTE_HEX: [&str; 48] = [
     "1b112c", "413047", "543e54", "75596f", "91718b", "b391aa", "ccb3c6", "e3cfe3", "fff7ff",
@@ -16,10 +30,691 @@ pub const PALETTE_HEX: [&str; 48] = [
     "56aec4", "92d7d9", "c3ebe3",
 ];
 
+/// Black and white, for [`run_bilevel`].
+pub const BILEVEL_PALETTE: [&str; 2] = ["000000", "ffffff"];
+
 pub const PALETTE_HEX_2: [&str; 8] = [
     "0d2b45", "203c56", "544e68", "8d697a", "d08159", "ffaa5e", "ffd4a3", "ffecd6",
 ];
 
+/// `color_dither` for a dither strength from 0 for none to 100 for the most
+/// useful, as `MAX_COLOR_DITHER * (strength / 100)²`. The square spends more
+/// of the range on weak dithering, where small changes are most visible;
+/// [`COLOR_DITHER`] is a strength of 40.
+pub fn color_dither_from_strength(strength: f32) -> f32 {
+    MAX_COLOR_DITHER * (strength.clamp(0.0, 100.0) / 100.0).powi(2)
+}
+
+/// Inverse of [`color_dither_from_strength`], saturating at 100.
+pub fn strength_from_color_dither(color_dither: f32) -> f32 {
+    ((color_dither.max(0.0) / MAX_COLOR_DITHER).sqrt() * 100.0).min(100.0)
+}
+
+/// Bayer threshold map with `2^order` rows and columns. Order 1 is
+/// [`THRESHOLD_MAP`].
+pub fn bayer_matrix(order: u32) -> Vec<Vec<usize>> {
+    let mut map = vec![vec![0]];
+    for _ in 0..order {
+        let n = map.len();
+        let mut next = vec![vec![0; 2 * n]; 2 * n];
+        for (y, row) in map.iter().enumerate() {
+            for (x, &value) in row.iter().enumerate() {
+                next[y][x] = 4 * value;
+                next[y][x + n] = 4 * value + 2;
+                next[y + n][x] = 4 * value + 3;
+                next[y + n][x + n] = 4 * value + 1;
+            }
+        }
+        map = next;
+    }
+    map
+}
+
+/// How a pixel is turned into a palette color and an alpha value.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DitherMode {
+    /// Generate candidates with error feedback, sort them by lightness
+    /// and pick one with the threshold map.
+    #[default]
+    CandidateSort,
+    /// Offset the pixel by the threshold map and snap it to the closest
+    /// palette color, as in textbook ordered dithering. `color_dither` is
+    /// the spread of the offset in Oklab lightness.
+    Ordered,
+}
+
+/// Color space in which distances to palette colors are measured.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MatchSpace {
+    /// Euclidean distance in Oklab.
+    #[default]
+    Oklab,
+    /// Euclidean distance in CIELAB, i.e. ΔE76.
+    Lab,
+}
+
+impl MatchSpace {
+    fn coordinates(self, color: Oklab) -> [f32; 3] {
+        match self {
+            MatchSpace::Oklab => [color.l, color.a, color.b],
+            MatchSpace::Lab => {
+                let lab: Lab = color.into_color();
+                [lab.l, lab.a, lab.b]
+            }
+        }
+    }
+}
+
+/// Which of several palette colors at exactly the same distance is taken as
+/// the closest.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum TieBreak {
+    /// The first in palette order, so results depend on the order.
+    #[default]
+    LowestIndex,
+    /// The darkest, then the first in palette order.
+    LowestLightness,
+    /// The closest in 8-bit sRGB, then the first in palette order.
+    SrgbDistance,
+}
+
+/// Which color threshold index dithers alpha when alpha has no threshold map
+/// of its own.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AlphaPattern {
+    /// The same index as color, so both patterns line up.
+    #[default]
+    Aligned,
+    /// The complement of the color index, `entries - 1 - index`.
+    Complement,
+    /// The color index plus this offset, wrapping around.
+    Shifted(usize),
+}
+
+impl AlphaPattern {
+    fn index(self, index: usize, map_size: usize) -> usize {
+        let entries = map_size.pow(2);
+        match self {
+            AlphaPattern::Aligned => index,
+            AlphaPattern::Complement => entries - 1 - index,
+            AlphaPattern::Shifted(offset) => (index + offset) % entries,
+        }
+    }
+}
+
+/// How threshold maps are tiled across the image.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Lattice {
+    /// Repeat the map on a square grid.
+    #[default]
+    Square,
+    /// Shift every other row of maps by half a map, like bricks, so the
+    /// pattern isn't aligned to both axes.
+    Hex,
+}
+
+/// Quantization of individual RGB channels to evenly spaced levels, bypassing
+/// the palette. Meant for channels holding data rather than color, such as
+/// normal or height maps, which perceptual remapping would corrupt.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChannelLevels {
+    /// Which of the R, G and B channels are quantized this way.
+    pub channels: [bool; 3],
+    /// Number of levels per channel, at least 2.
+    pub levels: u32,
+    /// Which of the R, G and B channels are dithered between levels with the
+    /// threshold map, while the others are quantized cleanly.
+    pub dither: [bool; 3],
+}
+
+impl ChannelLevels {
+    fn quantize(&self, value: u8, bias: f32) -> u8 {
+        let steps = (self.levels.max(2) - 1) as f32;
+        let level = (value as f32 / 255.0 * steps + bias)
+            .round()
+            .clamp(0.0, steps);
+        (level / steps * 255.0).round() as u8
+    }
+}
+
+/// How colors are dithered to the palette, for [`run_with_configs`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct ColorConfig<'a> {
+    /// Square map of the candidate index used at each position.
+    pub threshold_map: Vec<Vec<usize>>,
+    pub dither: f32,
+    pub palette_hex: &'a [&'a str],
+}
+
+impl Default for ColorConfig<'_> {
+    fn default() -> Self {
+        Self {
+            threshold_map: THRESHOLD_MAP.iter().map(|row| row.to_vec()).collect(),
+            dither: COLOR_DITHER,
+            palette_hex: &PALETTE_HEX,
+        }
+    }
+}
+
+/// How alpha is dithered to evenly spaced levels, independently of color.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AlphaConfig {
+    /// Square threshold map for alpha, with the same rules as the color one.
+    pub threshold_map: Vec<Vec<usize>>,
+    pub dither: f32,
+    /// Number of alpha levels, at least 2.
+    pub levels: u32,
+}
+
+impl Default for AlphaConfig {
+    fn default() -> Self {
+        Self {
+            threshold_map: THRESHOLD_MAP.iter().map(|row| row.to_vec()).collect(),
+            dither: ALPHA_DITHER,
+            levels: 2,
+        }
+    }
+}
+
+impl AlphaConfig {
+    fn quantize(&self, alpha: f32, index: usize) -> f32 {
+        let steps = (self.levels.max(2) - 1) as f32;
+        let bias = threshold_bias(index, self.threshold_map.len()) * self.dither;
+        (alpha * steps + bias).round().clamp(0.0, steps) / steps
+    }
+}
+
+/// Horizontal scanlines for a CRT look: the first half of every `period`
+/// rows is darkened before matching.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ScanlinePattern {
+    /// Rows from one scanline to the next, at least 2.
+    pub period: u32,
+    /// Oklab lightness taken off the darkened rows.
+    pub strength: f32,
+}
+
+#[derive(Clone)]
+pub struct Options<'a> {
+    /// Square map of the candidate index used at each position. Every entry
+    /// must be smaller than the number of entries.
+    pub threshold_map: Vec<Vec<usize>>,
+    pub color_dither: f32,
+    pub alpha_dither: f32,
+    /// Colors to quantize to, in index order, as 6-digit hex codes without
+    /// alpha; see `transparent_index` for transparency. The filter never
+    /// reorders, merges or drops them: index `i` always means
+    /// `palette_hex[i]`, even for duplicates, so indices can carry meaning
+    /// such as 0 for the background.
+    pub palette_hex: &'a [&'a str],
+    pub dither_mode: DitherMode,
+    pub match_space: MatchSpace,
+    /// Snap pixels whose closest palette color lies within this distance
+    /// in the matching space to it, and only dither the color of the rest.
+    /// Alpha is dithered either way.
+    pub snap_threshold: Option<f32>,
+    /// Source colors that are copied through exactly instead of being
+    /// quantized, such as a transparency key.
+    pub preserve_colors: &'a [&'a str],
+    /// Size in pixels of each threshold map cell, for chunkier dithering.
+    pub dither_scale: u32,
+    /// Use the closest palette color instead of the dithered choice when the
+    /// latter is farther from the pixel by more than this distance in the
+    /// matching space.
+    pub snap_if_better: Option<f32>,
+    /// Make pixels fully transparent when their closest palette color is
+    /// farther than this distance in the matching space, so colors the
+    /// palette can't represent drop out instead of getting a bad match.
+    pub drop_beyond: Option<f32>,
+    /// Keep the source's 8-bit alpha instead of dithering it to fully opaque
+    /// or fully transparent.
+    pub preserve_alpha: bool,
+    /// Channels quantized to levels instead of taking the palette color.
+    pub channel_levels: Option<ChannelLevels>,
+    /// Scale the color dither of each pixel by its alpha, so that
+    /// transparent pixels keep their closest color instead of noise.
+    pub alpha_weighted_error: bool,
+    /// Pick threshold indices from white noise with this seed instead of the
+    /// threshold map. The same seed always gives the same output.
+    pub noise_seed: Option<u64>,
+    /// Added to every threshold index, wrapping around the number of map
+    /// entries. Stepping it over successive frames cycles each pixel through
+    /// its candidates, for temporal dithering.
+    pub phase: usize,
+    /// Write fully transparent pixels that would stay transparent as
+    /// `SKIPPED_COLOR` without matching them to the palette.
+    pub skip_transparent: bool,
+    /// Degrees to rotate the hue of every pixel by in Oklch before matching
+    /// it to the palette.
+    pub hue_rotate: f32,
+    /// Quantize alpha with its own threshold map, dither and levels instead
+    /// of `alpha_dither` and the color threshold map.
+    pub alpha: Option<AlphaConfig>,
+    /// Which threshold index alpha is dithered with when `alpha` isn't set,
+    /// to keep the alpha pattern from lining up with the color pattern.
+    pub alpha_pattern: AlphaPattern,
+    /// Width in Oklab lightness of the soft knees at both ends of the
+    /// palette's lightness range. Lightness within a knee is compressed
+    /// smoothly toward the palette's extremes instead of clipping to them,
+    /// so clipped shadows and highlights keep some variation.
+    pub soft_knee: Option<f32>,
+    /// Match colors darker than the given Oklab lightness only to palette
+    /// colors with at most the given Oklch chroma, so that saturated colors
+    /// don't sparkle in noisy shadows. Ignored if no palette color is that
+    /// muted.
+    pub shadow_chroma: Option<(f32, f32)>,
+    /// Draw edges whose Sobel gradient of Oklab lightness exceeds this
+    /// strength in the palette color closest to black, over the filtered
+    /// fill, for an inked look. A hard step from black to white has a
+    /// strength of 1. With `wrap`, neighbors past a border come from the
+    /// opposite border, so edges match across tiles.
+    pub ink_edges: Option<f32>,
+    /// Per-pixel factor of `color_dither`, from 0 for no dither at black to
+    /// full dither at white. Must have the size of the filtered image.
+    pub dither_map: Option<&'a ImageBuffer<Luma<u8>, Vec<u8>>>,
+    /// Make sure the output tiles seamlessly, by failing unless everything
+    /// that varies with position repeats a whole number of times across the
+    /// image: the threshold maps, laid out by `dither_scale` and `lattice`,
+    /// and the `scanlines`. Noise has no period, so it only needs whole
+    /// cells.
+    pub wrap: bool,
+    /// Darken rows in a scanline pattern before matching.
+    pub scanlines: Option<ScanlinePattern>,
+    /// Only quantize pixels whose Oklch hue lies from the first to the second
+    /// angle in degrees, wrapping around 0° if the first is larger, and copy
+    /// the rest through. Grays have no hue and are copied too.
+    pub hue_range: Option<(f32, f32)>,
+    /// Only quantize pixels within the given distance of the key color in the
+    /// matching space, such as a green screen region, and copy the rest
+    /// through.
+    pub chroma_key: Option<(Srgb, f32)>,
+    /// Hex color of the background the output will be shown over. Alpha is
+    /// then dithered so that the output over it looks like the source over
+    /// it, instead of matching alpha values.
+    pub alpha_matte: Option<&'a str>,
+    /// Pick the candidates a second time, aimed past the pixel's color by
+    /// the mean error of the first set, so that they average out to the
+    /// pixel's color instead of leaning toward the closest palette color.
+    /// Helps most with larger threshold maps and low `color_dither`. Only
+    /// affects `DitherMode::CandidateSort`.
+    pub centered_error: bool,
+    /// How the threshold maps are tiled. Ignored with `noise_seed`.
+    pub lattice: Lattice,
+    /// Speed up large palettes by first matching against the palette colors
+    /// within this distance in the matching space, beyond the closest one,
+    /// of some color of the image. A match is only kept when no color left
+    /// out could be closer, and the whole palette is searched otherwise, so
+    /// the output is the same at any margin. Too small a margin falls back
+    /// often, as with large `color_dither` or with `hue_rotate`, `soft_knee`
+    /// and `scanlines`, which move colors before matching.
+    pub palette_subset: Option<f32>,
+    /// Colors to quantize to given directly in Oklab, in index order, used
+    /// instead of `palette_hex`. They are matched as given, without a round
+    /// trip through 8-bit sRGB, and only clamped to sRGB on output.
+    pub palette_oklab: Option<&'a [Oklab]>,
+    /// Palette index whose pixels come out fully transparent. Palette colors
+    /// carry no alpha, which is quantized separately, so this is the way to
+    /// reserve a color for transparency, as in indexed formats.
+    pub transparent_index: Option<usize>,
+    /// Which palette color wins when several are equally close.
+    pub tie_break: TieBreak,
+    /// Convert pixels to Oklab and, when matching in Oklab, find the closest
+    /// palette color with the fixed-point math of [`crate::approx`], trading
+    /// some accuracy in dark colors for speed.
+    pub approximate: bool,
+    /// Number of candidates generated per pixel by `DitherMode::CandidateSort`,
+    /// with threshold map entries scaled onto them, so tonal resolution no
+    /// longer depends on the map size. Defaults to the number of map entries.
+    pub candidates: Option<usize>,
+    /// Multiply the color of every output pixel by its alpha, as game engines
+    /// and browsers often expect. Alpha is dithered to 0 or 255 unless kept
+    /// with `preserve_alpha` or quantized with `alpha`, in which case this
+    /// only blackens fully transparent pixels.
+    pub output_premultiplied: bool,
+}
+
+impl Default for Options<'_> {
+    fn default() -> Self {
+        Self {
+            threshold_map: THRESHOLD_MAP.iter().map(|row| row.to_vec()).collect(),
+            color_dither: COLOR_DITHER,
+            alpha_dither: ALPHA_DITHER,
+            palette_hex: &PALETTE_HEX,
+            dither_mode: DitherMode::default(),
+            match_space: MatchSpace::default(),
+            snap_threshold: None,
+            preserve_colors: &[],
+            dither_scale: 1,
+            snap_if_better: None,
+            drop_beyond: None,
+            preserve_alpha: false,
+            channel_levels: None,
+            alpha_weighted_error: false,
+            noise_seed: None,
+            phase: 0,
+            skip_transparent: false,
+            hue_rotate: 0.0,
+            alpha: None,
+            alpha_pattern: AlphaPattern::default(),
+            soft_knee: None,
+            shadow_chroma: None,
+            ink_edges: None,
+            dither_map: None,
+            wrap: false,
+            scanlines: None,
+            hue_range: None,
+            chroma_key: None,
+            alpha_matte: None,
+            centered_error: false,
+            lattice: Lattice::default(),
+            palette_subset: None,
+            palette_oklab: None,
+            transparent_index: None,
+            tie_break: TieBreak::default(),
+            approximate: false,
+            candidates: None,
+            output_premultiplied: false,
+        }
+    }
+}
+
+/// Filtered image as indices into the palette. Alpha is kept separately,
+/// since palette colors don't carry any.
+pub struct IndexedImage {
+    pub width: u32,
+    pub height: u32,
+    /// Palette index of each pixel, row by row.
+    pub indices: Vec<usize>,
+    /// Alpha of each pixel, row by row.
+    pub alpha: Vec<u8>,
+}
+
+impl IndexedImage {
+    pub fn index(&self, x: u32, y: u32) -> usize {
+        self.indices[(y * self.width + x) as usize]
+    }
+}
+
+/// The two palette colors the dither mixes at each pixel and how much of the
+/// second, so that a shader can reproduce the dither at runtime by showing
+/// `second` where its threshold is below `weight` and `first` elsewhere.
+pub struct DualIndexImage {
+    pub width: u32,
+    pub height: u32,
+    /// Palette index of the most used color of each pixel, row by row.
+    pub first: Vec<usize>,
+    /// Palette index of the second most used color of each pixel, or the
+    /// first one again if the pixel isn't dithered.
+    pub second: Vec<usize>,
+    /// Share of `second` in each pixel, from 0 to 1.
+    pub weight: Vec<f32>,
+}
+
+pub fn run_with_options(
+    img: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    options: &Options,
+) -> Result<ImageBuffer<Rgba<u8>, Vec<u8>>, ImageError> {
+    let mut output_buffer = ImageBuffer::<Rgba<u8>, _>::new(img.width(), img.height());
+    // an empty image has no pixels to filter, but its options must still be
+    // valid
+    if img.width() == 0 || img.height() == 0 {
+        check_dimensions(img, options)?;
+        Filter::new(options)?;
+        return Ok(output_buffer);
+    }
+    run_rows(img, options, 0..img.height(), &mut output_buffer)?;
+    Ok(output_buffer)
+}
+
+/// Filter only the given rows of `img` into the same rows of `output_buffer`,
+/// so that large images can be processed in bands.
+pub fn run_rows(
+    img: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    options: &Options,
+    rows: Range<u32>,
+    output_buffer: &mut ImageBuffer<Rgba<u8>, Vec<u8>>,
+) -> Result<(), ImageError> {
+    if img.dimensions() != output_buffer.dimensions() {
+        return Err(ImageError::Parameter(ParameterError::from_kind(
+            ParameterErrorKind::DimensionMismatch,
+        )));
+    }
+
+    check_dimensions(img, options)?;
+
+    let filter = Filter::for_image(options, img)?;
+    for y in rows.start..rows.end.min(img.height()) {
+        // a row of a single color only has one output per threshold index,
+        // which is common for solid tiles
+        let uniform = options.dither_map.is_none()
+            && (1..img.width()).all(|x| img.get_pixel(x, y) == img.get_pixel(0, y));
+        let alpha_indices = filter.alpha_threshold_indices();
+        let mut outputs = vec![None; options.threshold_map.len().pow(2) * alpha_indices];
+
+        for x in 0..img.width() {
+            let pixel = *img.get_pixel(x, y);
+            if filter.is_preserved(pixel) {
+                output_buffer.put_pixel(x, y, filter.finish(pixel));
+                continue;
+            }
+            if filter.is_skipped(pixel) {
+                output_buffer.put_pixel(x, y, SKIPPED_COLOR);
+                continue;
+            }
+
+            // output the new color to the buffer
+            let cell = filter.cell(x, y);
+            let output = if uniform {
+                *outputs[cell.index * alpha_indices + cell.alpha_index]
+                    .get_or_insert_with(|| filter.output(cell, pixel))
+            } else {
+                filter.output(cell, pixel)
+            };
+            let output = if filter.is_edge(img, x, y) {
+                filter.palette.rgba(filter.ink, output.0[3] as f32 / 255.0)
+            } else {
+                output
+            };
+            output_buffer.put_pixel(x, y, filter.finish(output));
+        }
+    }
+
+    Ok(())
+}
+
+/// Filter an image into palette indices instead of colors. Indices refer to
+/// `options.palette_hex` in the order given.
+///
+/// `preserve_colors`, `hue_range`, `chroma_key` and `channel_levels` are
+/// ignored, since the colors they produce have no index.
+pub fn run_indexed(
+    img: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    options: &Options,
+) -> Result<IndexedImage, ImageError> {
+    check_dimensions(img, options)?;
+
+    let filter = Filter::for_image(options, img)?;
+    let (indices, alpha) = img
+        .enumerate_pixels()
+        .map(|(x, y, pixel)| {
+            let (mut index, alpha) = filter.choose(filter.cell(x, y), *pixel);
+            if filter.is_edge(img, x, y) {
+                index = filter.ink;
+            }
+            (index, (alpha * 255.0).round() as u8)
+        })
+        .unzip();
+
+    Ok(IndexedImage {
+        width: img.width(),
+        height: img.height(),
+        indices,
+        alpha,
+    })
+}
+
+/// Filter an image into the two palette colors the dither mixes at each
+/// pixel and their blend weight. Every threshold map entry is tried at each
+/// pixel, and the two colors chosen most often are kept.
+///
+/// Alpha is ignored, as are the options [`run_indexed`] ignores.
+pub fn run_dual_index(
+    img: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    options: &Options,
+) -> Result<DualIndexImage, ImageError> {
+    check_dimensions(img, options)?;
+
+    let filter = Filter::for_image(options, img)?;
+    let entries = options.threshold_map.len().pow(2);
+    let mut dual = DualIndexImage {
+        width: img.width(),
+        height: img.height(),
+        first: vec![],
+        second: vec![],
+        weight: vec![],
+    };
+    let mut counts = vec![0; filter.palette.colors.len()];
+    let mut lowest = vec![0; filter.palette.colors.len()];
+    for (x, y, pixel) in img.enumerate_pixels() {
+        counts.fill(0);
+        let cell = filter.cell(x, y);
+        for index in 0..entries {
+            let chosen = filter.choose(Cell { index, ..cell }, *pixel).0;
+            if counts[chosen] == 0 {
+                lowest[chosen] = index;
+            }
+            counts[chosen] += 1;
+        }
+
+        let mut order: Vec<usize> = (0..counts.len()).filter(|&i| counts[i] > 0).collect();
+        order.sort_by_key(|&i| std::cmp::Reverse(counts[i]));
+        let (mut first, mut second) = (order[0], order.get(1).copied().unwrap_or(order[0]));
+        if lowest[first] < lowest[second] {
+            std::mem::swap(&mut first, &mut second);
+        }
+        dual.first.push(first);
+        dual.second.push(second);
+        dual.weight.push(if second == first {
+            0.0
+        } else {
+            counts[second] as f32 / (counts[first] + counts[second]) as f32
+        });
+    }
+    Ok(dual)
+}
+
+/// Reduce an image to at most `colors` colors with median cut in Oklab, then
+/// map each of those to its closest palette color, without dithering. This
+/// only matches `colors` colors instead of every pixel, and gives flat areas
+/// on photos.
+///
+/// Alpha is rounded to fully opaque or transparent unless `preserve_alpha`
+/// is set. Only the palette, `match_space` and `tie_break` are used
+/// otherwise.
+pub fn run_two_stage(
+    img: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    options: &Options,
+    colors: usize,
+) -> Result<ImageBuffer<Rgba<u8>, Vec<u8>>, ImageError> {
+    check_dimensions(img, options)?;
+
+    let filter = Filter::new(options)?;
+    let samples: Vec<Oklab> = img
+        .pixels()
+        .map(|pixel| {
+            let [r, g, b, _] = pixel.0;
+            Srgb::new(r, g, b).into_format::<f32>().into_color()
+        })
+        .collect();
+    let (centers, assignment) = median_cut(&samples, colors.max(1));
+    let indices: Vec<usize> = centers
+        .iter()
+        .map(|center| filter.palette.find_closest(*center))
+        .collect();
+
+    let mut output_buffer = ImageBuffer::new(img.width(), img.height());
+    for ((pixel, output), center) in img.pixels().zip(output_buffer.pixels_mut()).zip(assignment) {
+        let alpha = pixel.0[3] as f32 / 255.0;
+        let alpha = if options.preserve_alpha {
+            alpha
+        } else {
+            alpha.round()
+        };
+        *output = filter.palette.rgba(indices[center], alpha);
+    }
+    Ok(output_buffer)
+}
+
+/// Dither an image to pure black and white, as for e-ink or thermal printers.
+/// This is filtering to [`BILEVEL_PALETTE`], but only thresholds each pixel's
+/// Oklab lightness against its threshold map entry instead of matching colors,
+/// and gives 0 for black and 255 for white.
+///
+/// Only the threshold map, `dither_scale`, `noise_seed`, `dither_map` and
+/// `scanlines` are used, and alpha is ignored.
+pub fn run_bilevel(
+    img: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    options: &Options,
+) -> Result<ImageBuffer<Luma<u8>, Vec<u8>>, ImageError> {
+    check_dimensions(img, options)?;
+
+    let filter = Filter::new(options)?;
+    let entries = options.threshold_map.len().pow(2) as f32;
+    Ok(ImageBuffer::from_fn(img.width(), img.height(), |x, y| {
+        let [r, g, b, _] = img.get_pixel(x, y).0;
+        let pixel_oklab: Oklab = Srgb::new(r, g, b).into_format::<f32>().into_color();
+        let cell = filter.cell(x, y);
+        let threshold = 0.5 + ((cell.index as f32 + 0.5) / entries - 0.5) * cell.dither_weight;
+        if pixel_oklab.l + cell.lightness_offset > threshold {
+            Luma([255])
+        } else {
+            Luma([0])
+        }
+    }))
+}
+
+/// Filter `passes` times, feeding each output into the next pass and halving
+/// `color_dither` every time, so later passes only refine the earlier ones.
+///
+/// Every pass after the first re-quantizes an image that is already made of
+/// palette colors, which mostly quantize to themselves, so the output settles
+/// after a pass or two.
+pub fn run_passes(
+    img: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    options: &Options,
+    passes: u32,
+) -> Result<ImageBuffer<Rgba<u8>, Vec<u8>>, ImageError> {
+    let mut output_buffer = run_with_options(img, options)?;
+    let mut options = options.clone();
+    for _ in 1..passes {
+        options.color_dither /= 2.0;
+        output_buffer = run_with_options(&output_buffer, &options)?;
+    }
+    Ok(output_buffer)
+}
+
+/// Filter with color and alpha configured independently, each with its own
+/// threshold map and dither strength.
+pub fn run_with_configs(
+    img: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    color: &ColorConfig,
+    alpha: &AlphaConfig,
+) -> Result<ImageBuffer<Rgba<u8>, Vec<u8>>, ImageError> {
+    run_with_options(
+        img,
+        &Options {
+            threshold_map: color.threshold_map.clone(),
+            color_dither: color.dither,
+            palette_hex: color.palette_hex,
+            alpha: Some(alpha.clone()),
+            ..Default::default()
+        },
+    )
+}
+
+/// Filter with one threshold map shared by color and alpha.
 pub fn run_with_parameters(
     img: &ImageBuffer<Rgba<u8>, Vec<u8>>,
     threshold_map: &[[usize; 2]; 2],
@@ -27,74 +722,752 @@ pub fn run_with_parameters(
     alpha_dither: f32,
     palette_hex: &[&str],
 ) -> Result<ImageBuffer<Rgba<u8>, Vec<u8>>, ImageError> {
-    let map_size = threshold_map.len();
-    let pixels = img.enumerate_pixels();
-    let mut output_buffer = ImageBuffer::<Rgba<u8>, _>::new(img.width(), img.height());
+    run_with_options(
+        img,
+        &Options {
+            threshold_map: threshold_map.iter().map(|row| row.to_vec()).collect(),
+            color_dither,
+            alpha_dither,
+            palette_hex,
+            ..Default::default()
+        },
+    )
+}
+
+/// Recolor an image to a palette: every pixel becomes its closest palette
+/// color without dithering, and alpha is kept as is.
+pub fn remap(
+    img: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    palette_hex: &[&str],
+) -> Result<ImageBuffer<Rgba<u8>, Vec<u8>>, ImageError> {
+    run_with_options(
+        img,
+        &Options {
+            palette_hex,
+            snap_threshold: Some(f32::INFINITY),
+            preserve_alpha: true,
+            ..Default::default()
+        },
+    )
+}
+
+pub fn run(
+    img: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+) -> Result<ImageBuffer<Rgba<u8>, Vec<u8>>, ImageError> {
+    run_with_options(img, &Options::default())
+}
+
+/// Fail unless the dither map, if any, has the size of the image, and the
+/// patterns repeat evenly across the image when wrapping.
+fn check_dimensions(
+    img: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    options: &Options,
+) -> Result<(), ImageError> {
+    if options
+        .dither_map
+        .is_some_and(|dither_map| dither_map.dimensions() != img.dimensions())
+    {
+        return Err(ImageError::Parameter(ParameterError::from_kind(
+            ParameterErrorKind::DimensionMismatch,
+        )));
+    }
+
+    if options.wrap {
+        let dither_scale = options.dither_scale.max(1);
+        // noise doesn't repeat, so only cells have to line up
+        let map_sizes = match options.noise_seed {
+            Some(_) => vec![1],
+            None => std::iter::once(&options.threshold_map)
+                .chain(options.alpha.as_ref().map(|alpha| &alpha.threshold_map))
+                .map(|map| map.len() as u32)
+                .collect(),
+        };
+        let mut periods: Vec<(u32, u32)> = map_sizes
+            .into_iter()
+            .map(|map_size| {
+                let period = map_size * dither_scale;
+                // shifted rows of maps repeat every other row
+                let rows = match options.lattice {
+                    Lattice::Hex if options.noise_seed.is_none() && map_size > 1 => 2,
+                    _ => 1,
+                };
+                (period, rows * period)
+            })
+            .collect();
+        if let Some(scanlines) = options.scanlines {
+            periods.push((1, scanlines.period.max(2)));
+        }
+        for (width_period, height_period) in periods {
+            if !img.width().is_multiple_of(width_period)
+                || !img.height().is_multiple_of(height_period)
+            {
+                return Err(ImageError::Parameter(ParameterError::from_kind(
+                    ParameterErrorKind::Generic(format!(
+                        "wrapping needs a width that is a multiple of {} and a height that is a multiple of {}, got {}x{}",
+                        width_period,
+                        height_period,
+                        img.width(),
+                        img.height()
+                    )),
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Everything about a pixel's position that its output depends on.
+#[derive(Clone, Copy)]
+struct Cell {
+    /// Threshold map entry.
+    index: usize,
+    /// Alpha threshold map entry, always 0 without an alpha config.
+    alpha_index: usize,
+    /// Factor of the color dither, from the dither map.
+    dither_weight: f32,
+    /// Lightness added before matching, from the scanlines.
+    lightness_offset: f32,
+}
+
+/// Everything needed to filter single pixels, prepared once per run.
+struct Filter<'a> {
+    options: &'a Options<'a>,
+    palette: Palette,
+    preserved: Vec<[u8; 3]>,
+    /// Darkest and lightest palette lightness.
+    lightness_range: (f32, f32),
+    matte: Option<Oklab>,
+    /// Palette index closest to black, used for `ink_edges`.
+    ink: usize,
+}
+
+impl<'a> Filter<'a> {
+    /// A filter for `img`, with the palette narrowed down to the colors it
+    /// needs if `palette_subset` is set.
+    fn for_image(
+        options: &'a Options<'a>,
+        img: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    ) -> Result<Self, ImageError> {
+        let mut filter = Self::new(options)?;
+        if let Some(margin) = options.palette_subset {
+            filter.palette.restrict(img, margin);
+        }
+        Ok(filter)
+    }
+
+    fn new(options: &'a Options<'a>) -> Result<Self, ImageError> {
+        let alpha_map = options.alpha.as_ref().map(|alpha| &alpha.threshold_map);
+        if !is_valid_threshold_map(&options.threshold_map)
+            || alpha_map.is_some_and(|map| !is_valid_threshold_map(map))
+        {
+            return Err(ImageError::Parameter(ParameterError::from_kind(
+                ParameterErrorKind::Generic("invalid threshold map".to_string()),
+            )));
+        }
+        if options.candidates == Some(0) {
+            return Err(ImageError::Parameter(ParameterError::from_kind(
+                ParameterErrorKind::Generic("at least one candidate is needed".to_string()),
+            )));
+        }
+        let colors = match options.palette_oklab {
+            Some(colors) => colors.to_vec(),
+            None => {
+                check_palette(options.palette_hex)?;
+                palette_as_oklab(options.palette_hex)
+            }
+        };
+        if colors.is_empty() {
+            return Err(ImageError::Parameter(ParameterError::from_kind(
+                ParameterErrorKind::Generic("empty palette".to_string()),
+            )));
+        }
+
+        let mut palette = Palette::new(colors, options.match_space);
+        palette.tie_break = options.tie_break;
+        if let Some((lightness, chroma)) = options.shadow_chroma {
+            palette.limit_shadows(lightness, chroma);
+        }
+        if options.approximate && options.match_space == MatchSpace::Oklab {
+            palette.fixed = Some(
+                palette
+                    .colors
+                    .iter()
+                    .map(|&c| FixedOklab::from_oklab(c))
+                    .collect(),
+            );
+        }
+        let lightness_range = palette
+            .colors
+            .iter()
+            .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), color| {
+                (min.min(color.l), max.max(color.l))
+            });
+        let ink = (0..palette.colors.len())
+            .min_by(|&i, &j| {
+                let black = |index: usize| {
+                    let color = palette.colors[index];
+                    color.l.powi(2) + color.a.powi(2) + color.b.powi(2)
+                };
+                black(i).partial_cmp(&black(j)).unwrap()
+            })
+            .unwrap();
+        Ok(Self {
+            options,
+            palette,
+            lightness_range,
+            ink,
+            matte: options
+                .alpha_matte
+                .map(|hex| {
+                    parse_hex(hex, "alpha matte")
+                        .map(|[r, g, b]| Srgb::new(r, g, b).into_format::<f32>().into_color())
+                })
+                .transpose()?,
+            preserved: options
+                .preserve_colors
+                .iter()
+                .map(|hex| parse_hex(hex, "preserved color"))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    /// Whether the pixel lies on an edge to be inked over.
+    fn is_edge(&self, img: &ImageBuffer<Rgba<u8>, Vec<u8>>, x: u32, y: u32) -> bool {
+        let Some(threshold) = self.options.ink_edges else {
+            return false;
+        };
+        let neighbor = |position: u32, offset: i32, size: u32| {
+            let position = position as i32 + offset;
+            if self.options.wrap {
+                position.rem_euclid(size as i32) as u32
+            } else {
+                position.clamp(0, size as i32 - 1) as u32
+            }
+        };
+        let lightness = |dx: i32, dy: i32| {
+            let x = neighbor(x, dx, img.width());
+            let y = neighbor(y, dy, img.height());
+            let [r, g, b, _] = img.get_pixel(x, y).0;
+            let oklab: Oklab = Srgb::new(r, g, b).into_format::<f32>().into_color();
+            oklab.l
+        };
+        let gx = lightness(1, -1) + 2.0 * lightness(1, 0) + lightness(1, 1)
+            - lightness(-1, -1)
+            - 2.0 * lightness(-1, 0)
+            - lightness(-1, 1);
+        let gy = lightness(-1, 1) + 2.0 * lightness(0, 1) + lightness(1, 1)
+            - lightness(-1, -1)
+            - 2.0 * lightness(0, -1)
+            - lightness(1, -1);
+        // a hard step gives a gradient of 4 across it
+        (gx.powi(2) + gy.powi(2)).sqrt() / 4.0 > threshold
+    }
+
+    /// Whether the pixel is keyed to be copied through untouched, or lies
+    /// outside of `chroma_key` or `hue_range`.
+    fn is_preserved(&self, pixel: Rgba<u8>) -> bool {
+        let [r, g, b, _] = pixel.0;
+        if self.preserved.contains(&[r, g, b]) {
+            return true;
+        }
+        if let Some((key, tolerance)) = self.options.chroma_key {
+            let space = self.options.match_space;
+            let pixel_oklab: Oklab = Srgb::new(r, g, b).into_format::<f32>().into_color();
+            let distance = distance_squared(
+                space.coordinates(pixel_oklab),
+                space.coordinates(key.into_color()),
+            );
+            if distance > tolerance.powi(2) {
+                return true;
+            }
+        }
+        let Some((start, end)) = self.options.hue_range else {
+            return false;
+        };
+
+        let pixel_oklch: Oklch = Srgb::new(r, g, b).into_format::<f32>().into_color();
+        // grays have no meaningful hue
+        if pixel_oklch.chroma < ACHROMATIC_CHROMA {
+            return true;
+        }
+        let hue = pixel_oklch.hue.into_positive_degrees();
+        let (start, end) = (start.rem_euclid(360.0), end.rem_euclid(360.0));
+        let in_range = if start <= end {
+            (start..=end).contains(&hue)
+        } else {
+            // wraps around 0°
+            hue >= start || hue <= end
+        };
+        !in_range
+    }
+
+    /// Whether the pixel is transparent and can't be dithered to visible, so
+    /// that `skip_transparent` can bypass the palette for it.
+    fn is_skipped(&self, pixel: Rgba<u8>) -> bool {
+        let options = self.options;
+        let alpha_dither = match &options.alpha {
+            Some(alpha) => alpha.dither,
+            None => options.alpha_dither,
+        };
+        // a bias of at most half a step can't round alpha 0 up
+        options.skip_transparent
+            && pixel.0[3] == 0
+            && (options.preserve_alpha || alpha_dither <= 1.0)
+    }
 
-    let palette_oklab = palette_as_oklab(palette_hex);
-    for pixel in pixels {
-        let (x, y) = (pixel.0, pixel.1);
-        let [r, g, b, a] = pixel.2 .0;
+    /// Everything about the given position that affects its output.
+    fn cell(&self, x: u32, y: u32) -> Cell {
+        let options = self.options;
+        Cell {
+            index: self.map_index(&options.threshold_map, x, y),
+            alpha_index: match &options.alpha {
+                Some(alpha) => self.map_index(&alpha.threshold_map, x, y),
+                None => 0,
+            },
+            dither_weight: match options.dither_map {
+                Some(dither_map) => dither_map.get_pixel(x, y).0[0] as f32 / 255.0,
+                None => 1.0,
+            },
+            lightness_offset: match options.scanlines {
+                Some(scanlines) if y % scanlines.period.max(2) < scanlines.period.max(2) / 2 => {
+                    -scanlines.strength
+                }
+                _ => 0.0,
+            },
+        }
+    }
+
+    /// Number of distinct alpha threshold indices.
+    fn alpha_threshold_indices(&self) -> usize {
+        match &self.options.alpha {
+            Some(alpha) => alpha.threshold_map.len().pow(2),
+            None => 1,
+        }
+    }
+
+    fn map_index(&self, threshold_map: &[Vec<usize>], x: u32, y: u32) -> usize {
+        let map_size = threshold_map.len();
+        let dither_scale = self.options.dither_scale.max(1);
+        let (cell_x, cell_y) = ((x / dither_scale) as usize, (y / dither_scale) as usize);
+        let index = match self.options.noise_seed {
+            Some(seed) => {
+                let cell = ((cell_y as u64) << 32) | cell_x as u64;
+                (split_mix(seed ^ split_mix(cell)) % map_size.pow(2) as u64) as usize
+            }
+            None => {
+                let shift = match self.options.lattice {
+                    Lattice::Square => 0,
+                    Lattice::Hex => (cell_y / map_size % 2) * (map_size / 2),
+                };
+                threshold_map[(cell_x + shift) % map_size][cell_y % map_size]
+            }
+        };
+        (index + self.options.phase) % map_size.pow(2)
+    }
+
+    /// Output color for the pixel in the given cell.
+    fn output(&self, cell: Cell, pixel: Rgba<u8>) -> Rgba<u8> {
+        let (index, alpha) = self.choose(cell, pixel);
+        let mut output = self.palette.rgba(index, alpha);
+
+        if let Some(levels) = self.options.channel_levels {
+            let bias = threshold_bias(cell.index, self.options.threshold_map.len());
+            for channel in 0..3 {
+                if levels.channels[channel] {
+                    let bias = if levels.dither[channel] { bias } else { 0.0 };
+                    output.0[channel] = levels.quantize(pixel.0[channel], bias);
+                }
+            }
+        }
+        output
+    }
+
+    /// Output pixel as written, premultiplied if asked for.
+    fn finish(&self, pixel: Rgba<u8>) -> Rgba<u8> {
+        if !self.options.output_premultiplied {
+            return pixel;
+        }
+        let [r, g, b, a] = pixel.0;
+        let premultiply = |c: u8| ((c as u32 * a as u32 + 127) / 255) as u8;
+        Rgba([premultiply(r), premultiply(g), premultiply(b), a])
+    }
+
+    /// Palette index and alpha chosen for the pixel in the given cell.
+    fn choose(&self, cell: Cell, pixel: Rgba<u8>) -> (usize, f32) {
+        let options = self.options;
+        let palette = &self.palette;
+        let [r, g, b, a] = pixel.0;
 
         let alpha_f32 = (a as f32) / 255.0;
-        let pixel_rgb = Srgb::new(r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0);
-        let pixel_oklab: Oklab = pixel_rgb.into_color();
+        let mut pixel_oklab: Oklab = if options.approximate {
+            FixedOklab::from_srgb([r, g, b]).to_oklab()
+        } else {
+            Srgb::new(r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0).into_color()
+        };
+        if options.hue_rotate != 0.0 {
+            let pixel_oklch: Oklch = pixel_oklab.into_color();
+            pixel_oklab = pixel_oklch.shift_hue(options.hue_rotate).into_color();
+        }
+        if let Some(width) = options.soft_knee {
+            let (darkest, lightest) = self.lightness_range;
+            pixel_oklab.l = soft_knee(pixel_oklab.l, darkest, lightest, width);
+        }
+        pixel_oklab.l += cell.lightness_offset;
+
+        let closest = (options.snap_threshold.is_some()
+            || options.snap_if_better.is_some()
+            || options.drop_beyond.is_some())
+        .then(|| palette.find_closest_with_distance(pixel_oklab));
+        let snapped = options.snap_threshold.and_then(|threshold| {
+            let (closest, dist_of_closest) = closest.unwrap();
+            // close enough to the palette that dithering the color would only
+            // add noise, while alpha is still dithered below
+            (dist_of_closest <= threshold.powi(2)).then_some(closest)
+        });
+        let mut color_dither = options.color_dither * cell.dither_weight;
+        if options.alpha_weighted_error {
+            color_dither *= alpha_f32;
+        }
+        let mut chosen = snapped.unwrap_or_else(|| match options.dither_mode {
+            DitherMode::CandidateSort => {
+                candidate_sort(palette, pixel_oklab, cell.index, color_dither, options)
+            }
+            DitherMode::Ordered => ordered(palette, pixel_oklab, cell.index, color_dither, options),
+        });
+        let mut chosen_alpha = match options.dither_mode {
+            DitherMode::CandidateSort => candidate_sort_alpha(alpha_f32, cell.index, options),
+            DitherMode::Ordered => ordered_alpha(alpha_f32, cell.index, options),
+        };
+
+        if let Some(alpha) = &options.alpha {
+            chosen_alpha = alpha.quantize(alpha_f32, cell.alpha_index);
+        } else if let Some(matte) = self.matte {
+            let coverage = matte_coverage(palette.colors[chosen], pixel_oklab, alpha_f32, matte);
+            let map_size = options.threshold_map.len();
+            let alpha_index = options.alpha_pattern.index(cell.index, map_size);
+            let bias = threshold_bias(alpha_index, map_size);
+            chosen_alpha = (coverage + bias * options.alpha_dither)
+                .round()
+                .clamp(0.0, 1.0);
+        }
+        if options.preserve_alpha {
+            chosen_alpha = alpha_f32;
+        }
+
+        if let Some(tolerance) = options.snap_if_better {
+            // the threshold map can only pick among the generated candidates,
+            // which may all be worse than the closest color
+            let (closest, dist_of_closest) = closest.unwrap();
+            let dist_of_chosen = palette.distance_squared(chosen, pixel_oklab);
+            if dist_of_chosen.sqrt() - dist_of_closest.sqrt() > tolerance {
+                chosen = closest;
+            }
+        }
+        if options.transparent_index == Some(chosen)
+            || options
+                .drop_beyond
+                .is_some_and(|cutoff| closest.unwrap().1 > cutoff.powi(2))
+        {
+            chosen_alpha = 0.0;
+        }
+        (chosen, chosen_alpha)
+    }
+}
+
+fn candidate_sort(
+    palette: &Palette,
+    pixel_oklab: Oklab,
+    index: usize,
+    color_dither: f32,
+    options: &Options,
+) -> usize {
+    let entries = options.threshold_map.len().pow(2);
+    let count = options.candidates.unwrap_or(entries);
+    let mut candidates_c = match &palette.fixed {
+        Some(fixed) => fixed_candidates(palette, fixed, pixel_oklab, count, color_dither, options),
+        None => float_candidates(palette, pixel_oklab, count, color_dither, options),
+    };
+
+    // sort candidates by brightness and choose one based on the pixel
+    // coordinates
+    candidates_c.sort_by(|c1, c2| {
+        let (l1, l2) = (palette.colors[*c1].l, palette.colors[*c2].l);
+        l1.partial_cmp(&l2).unwrap()
+    });
+    candidates_c[index * count / entries]
+}
+
+/// Candidate colors for `candidate_sort`, found with error feedback.
+fn float_candidates(
+    palette: &Palette,
+    pixel_oklab: Oklab,
+    count: usize,
+    color_dither: f32,
+    options: &Options,
+) -> Vec<usize> {
+    // create a list of candidate colors
+    let mut candidates_c: Vec<usize> = vec![];
+    let mut error_c = Oklab::new(0.0, 0.0, 0.0);
+    for _ in 0..count {
+        let sample_c = pixel_oklab + error_c * color_dither;
+        let candidate_c = palette.find_closest(sample_c);
+        candidates_c.push(candidate_c);
+        error_c += pixel_oklab - palette.colors[candidate_c];
+    }
 
-        // create a list of candidate color and alpha values
-        let mut candidates_c: Vec<Oklab> = vec![];
-        let mut candidates_a: Vec<f32> = vec![];
+    if options.centered_error {
+        // the first candidate never gets any error, which pulls the set
+        // toward the closest color; pick the set again aimed past the pixel
+        // by its mean error, so that it averages out closer to the pixel
+        let target = pixel_oklab + error_c / count as f32;
+        candidates_c.clear();
         let mut error_c = Oklab::new(0.0, 0.0, 0.0);
-        let mut error_a = 0.0;
-        for _ in 0..map_size.pow(2) {
-            // color
-            let sample_c = pixel_oklab + error_c * color_dither;
-            let candidate_c = find_closest(&palette_oklab, sample_c);
+        for _ in 0..count {
+            let candidate_c = palette.find_closest(target + error_c * color_dither);
             candidates_c.push(candidate_c);
-            error_c += pixel_oklab - candidate_c;
+            error_c += pixel_oklab - palette.colors[candidate_c];
+        }
+    }
+    candidates_c
+}
 
-            // alpha
-            let sample_a = alpha_f32 + error_a * alpha_dither;
-            let candidate_a = sample_a.round();
-            candidates_a.push(candidate_a);
-            error_a += alpha_f32 - candidate_a;
+/// [`float_candidates`] in fixed point, converting the pixel once instead of
+/// every sample.
+fn fixed_candidates(
+    palette: &Palette,
+    fixed: &[FixedOklab],
+    pixel_oklab: Oklab,
+    count: usize,
+    color_dither: f32,
+    options: &Options,
+) -> Vec<usize> {
+    let pixel = FixedOklab::from_oklab(pixel_oklab);
+    let color_dither = to_fixed(color_dither);
+    let zero = FixedOklab([0; 3]);
+    let mut candidates_c: Vec<usize> = vec![];
+    let mut error_c = zero;
+    for _ in 0..count {
+        let sample_c = pixel + error_c.scale(color_dither);
+        let candidate_c = palette.find_closest_fixed(sample_c).0;
+        candidates_c.push(candidate_c);
+        error_c = error_c + (pixel - fixed[candidate_c]);
+    }
+
+    if options.centered_error {
+        // see float_candidates
+        let target = pixel + error_c / count as i64;
+        candidates_c.clear();
+        let mut error_c = zero;
+        for _ in 0..count {
+            let candidate_c = palette
+                .find_closest_fixed(target + error_c.scale(color_dither))
+                .0;
+            candidates_c.push(candidate_c);
+            error_c = error_c + (pixel - fixed[candidate_c]);
         }
+    }
+    candidates_c
+}
+
+/// Alpha chosen by `DitherMode::CandidateSort`, from candidates generated
+/// with error feedback like the colors.
+fn candidate_sort_alpha(alpha_f32: f32, index: usize, options: &Options) -> f32 {
+    let mut candidates_a: Vec<f32> = vec![];
+    let mut error_a = 0.0;
+    let entries = options.threshold_map.len().pow(2);
+    let count = options.candidates.unwrap_or(entries);
+    for _ in 0..count {
+        let sample_a = alpha_f32 + error_a * options.alpha_dither;
+        let candidate_a = sample_a.round();
+        candidates_a.push(candidate_a);
+        error_a += alpha_f32 - candidate_a;
+    }
 
-        // sort candidates by brightness and alpha, respectively
-        candidates_c
-            .sort_by(|Oklab { l: l1, .. }, Oklab { l: l2, .. }| l1.partial_cmp(l2).unwrap());
-        candidates_a.sort_by(|a1, a2| a1.partial_cmp(&a2).unwrap());
+    candidates_a.sort_by(|a1, a2| a1.partial_cmp(a2).unwrap());
+    let alpha_index = options
+        .alpha_pattern
+        .index(index, options.threshold_map.len());
+    candidates_a[alpha_index * count / entries]
+}
 
-        // choose a candidate based on the pixel coordinates
-        let index = threshold_map[x as usize % map_size][y as usize % map_size];
-        let chosen_color: Srgb = candidates_c[index].into_color();
-        let chosen_alpha = candidates_a[index];
+fn ordered(
+    palette: &Palette,
+    pixel_oklab: Oklab,
+    index: usize,
+    color_dither: f32,
+    options: &Options,
+) -> usize {
+    let bias = threshold_bias(index, options.threshold_map.len());
+    let sample_c = Oklab {
+        l: pixel_oklab.l + bias * color_dither,
+        ..pixel_oklab
+    };
+    palette.find_closest(sample_c)
+}
 
-        // output the new color to the buffer
-        let output_pixel = output_buffer.get_pixel_mut(x, y);
-        *output_pixel = image::Rgba([
-            (chosen_color.red * 255.0) as u8,
-            (chosen_color.green * 255.0) as u8,
-            (chosen_color.blue * 255.0) as u8,
-            (chosen_alpha * 255.0) as u8,
-        ]);
+/// Alpha chosen by `DitherMode::Ordered`, offset by the threshold map like
+/// the colors.
+fn ordered_alpha(alpha_f32: f32, index: usize, options: &Options) -> f32 {
+    let map_size = options.threshold_map.len();
+    let alpha_bias = threshold_bias(options.alpha_pattern.index(index, map_size), map_size);
+    (alpha_f32 + alpha_bias * options.alpha_dither)
+        .round()
+        .clamp(0.0, 1.0)
+}
+
+/// How much of `chosen` to show over the matte, from 0 to 1, so that mixing
+/// the two looks most like the source composited over the matte. Mixes are
+/// compared in Oklab, since perceived transparency isn't linear in alpha.
+fn matte_coverage(chosen: Oklab, source: Oklab, alpha: f32, matte: Oklab) -> f32 {
+    let (source, matte_linear): (LinSrgb, LinSrgb) = (source.into_color(), matte.into_color());
+    let composite: Oklab = (source * alpha + matte_linear * (1.0 - alpha)).into_color();
+
+    let span = chosen - matte;
+    let length_squared = span.l.powi(2) + span.a.powi(2) + span.b.powi(2);
+    if length_squared == 0.0 {
+        return alpha;
     }
+    let offset = composite - matte;
+    ((offset.l * span.l + offset.a * span.a + offset.b * span.b) / length_squared).clamp(0.0, 1.0)
+}
 
-    Ok(output_buffer)
+/// Compress lightness within `width` of either end of `min..max`
+/// exponentially, so that it approaches the ends without reaching them.
+/// Knees wider than half the range are narrowed to fit.
+fn soft_knee(l: f32, min: f32, max: f32, width: f32) -> f32 {
+    let width = width.min((max - min) / 2.0);
+    if width <= 0.0 {
+        return l;
+    }
+    let compress = |t: f32| width * (1.0 - (-t / width).exp());
+    if l > max - width {
+        max - width + compress(l - (max - width))
+    } else if l < min + width {
+        min + width - compress(min + width - l)
+    } else {
+        l
+    }
 }
 
-pub fn run(
+/// Whether the map is square and every entry is smaller than the number of
+/// entries.
+fn is_valid_threshold_map(threshold_map: &[Vec<usize>]) -> bool {
+    let map_size = threshold_map.len();
+    map_size != 0
+        && threshold_map
+            .iter()
+            .all(|row| row.len() == map_size && row.iter().all(|&i| i < map_size.pow(2)))
+}
+
+/// SplitMix64 finalizer, which turns nearby inputs into unrelated outputs.
+fn split_mix(mut z: u64) -> u64 {
+    z = z.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+/// Map a threshold index to an offset in [-0.5, 0.5).
+fn threshold_bias(index: usize, map_size: usize) -> f32 {
+    (index as f32 + 0.5) / map_size.pow(2) as f32 - 0.5
+}
+
+/// The palette as the filter writes it, in index order, so that the indices
+/// of [`run_indexed`] can be mapped to colors.
+pub fn effective_palette(palette_hex: &[&str]) -> Vec<String> {
+    oklab_palette_hex(&palette_as_oklab(palette_hex))
+}
+
+/// Hex codes of the colors the filter writes for a palette given in Oklab,
+/// clamped to sRGB.
+pub fn oklab_palette_hex(colors: &[Oklab]) -> Vec<String> {
+    Palette::new(colors.to_vec(), MatchSpace::default())
+        .rgb
+        .iter()
+        .map(|[r, g, b]| format!("{:02x}{:02x}{:02x}", r, g, b))
+        .collect()
+}
+
+/// Indices of palette colors outside sRGB by enough to change an output
+/// byte, and so get clamped when written. Colors from hex codes only leave
+/// sRGB through rounding, so this matters for palettes given in Oklab.
+pub fn out_of_gamut(colors: &[Oklab]) -> Vec<usize> {
+    let tolerance = 0.5 / 255.0;
+    colors
+        .iter()
+        .enumerate()
+        .filter(|(_, color)| {
+            let rgb: Srgb = (**color).into_color_unclamped();
+            [rgb.red, rgb.green, rgb.blue]
+                .iter()
+                .any(|&c| !(-tolerance..=1.0 + tolerance).contains(&c))
+        })
+        .map(|(index, _)| index)
+        .collect()
+}
+
+/// Filter tightly packed RGBA bytes of the given dimensions.
+pub fn filter_bytes(
+    bytes: &[u8],
+    width: u32,
+    height: u32,
+    options: &Options,
+) -> Result<Vec<u8>, ImageError> {
+    let img = rgba_buffer(width, height, bytes.to_vec())?;
+    Ok(run_with_options(&img, options)?.into_raw())
+}
+
+/// Filter an image into tightly packed RGBA bytes, row by row, along with
+/// the width and height, ready for a texture upload without encoding.
+pub fn run_to_rgba_vec(
     img: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    options: &Options,
+) -> Result<(Vec<u8>, u32, u32), ImageError> {
+    let output_buffer = run_with_options(img, options)?;
+    let (width, height) = output_buffer.dimensions();
+    Ok((output_buffer.into_raw(), width, height))
+}
+
+/// Wrap tightly packed, non-premultiplied RGBA bytes, such as canvas image
+/// data, failing with a descriptive error unless there are exactly four bytes
+/// per pixel.
+pub fn rgba_buffer(
+    width: u32,
+    height: u32,
+    bytes: Vec<u8>,
 ) -> Result<ImageBuffer<Rgba<u8>, Vec<u8>>, ImageError> {
-    run_with_parameters(
-        img,
-        &THRESHOLD_MAP,
-        COLOR_DITHER,
-        ALPHA_DITHER,
-        &PALETTE_HEX,
-    )
+    let expected = width as usize * height as usize * 4;
+    if bytes.len() != expected {
+        return Err(ImageError::Parameter(ParameterError::from_kind(
+            ParameterErrorKind::Generic(format!(
+                "expected {} bytes of RGBA for {}x{}, got {}",
+                expected,
+                width,
+                height,
+                bytes.len()
+            )),
+        )));
+    }
+    Ok(ImageBuffer::from_raw(width, height, bytes).unwrap())
 }
 
-fn palette_as_oklab(palette_hex: &[&str]) -> Vec<Oklab> {
+/// Fail unless the palette has colors and all of them are 6-digit hex
+/// codes, naming the first bad one.
+pub fn check_palette(palette_hex: &[&str]) -> Result<(), ImageError> {
+    if palette_hex.is_empty() {
+        return Err(ImageError::Parameter(ParameterError::from_kind(
+            ParameterErrorKind::Generic("empty palette".to_string()),
+        )));
+    }
+    for (index, hex) in palette_hex.iter().enumerate() {
+        parse_hex(hex, &format!("palette color {}", index))?;
+    }
+    Ok(())
+}
+
+/// Oklab colors of a palette. Panics on invalid hex codes, which
+/// [`check_palette`] reports instead.
+pub fn palette_as_oklab(palette_hex: &[&str]) -> Vec<Oklab> {
     let mut oklab_palette: Vec<Oklab> = vec![];
     for hex in palette_hex {
         let rgb = hex_to_rgb(hex).unwrap();
@@ -103,8 +1476,14 @@ fn palette_as_oklab(palette_hex: &[&str]) -> Vec<Oklab> {
     oklab_palette
 }
 
-fn hex_to_rgb(hex: &str) -> Result<Srgb, &'static str> {
-    if hex.len() != 6 {
+pub(crate) fn rgb_to_hex(rgb: Srgb) -> String {
+    let rgb: Srgb<u8> = rgb.into_format();
+    format!("{:02x}{:02x}{:02x}", rgb.red, rgb.green, rgb.blue)
+}
+
+pub(crate) fn hex_to_rgb8(hex: &str) -> Result<[u8; 3], &'static str> {
+    // anything but ASCII can't be hex, nor be sliced by bytes
+    if hex.len() != 6 || !hex.is_ascii() {
         return Err("Invalid hex color code");
     }
 
@@ -112,6 +1491,20 @@ fn hex_to_rgb(hex: &str) -> Result<Srgb, &'static str> {
     let g = u8::from_str_radix(&hex[2..4], 16).map_err(|_| "Invalid hex color code")?;
     let b = u8::from_str_radix(&hex[4..6], 16).map_err(|_| "Invalid hex color code")?;
 
+    Ok([r, g, b])
+}
+
+/// Parse a hex color of the options, failing with an error that names it.
+fn parse_hex(hex: &str, name: &str) -> Result<[u8; 3], ImageError> {
+    hex_to_rgb8(hex).map_err(|_| {
+        ImageError::Parameter(ParameterError::from_kind(ParameterErrorKind::Generic(
+            format!("invalid {} `{}`, expected a 6-digit hex code", name, hex),
+        )))
+    })
+}
+
+fn hex_to_rgb(hex: &str) -> Result<Srgb, &'static str> {
+    let [r, g, b] = hex_to_rgb8(hex)?;
     Ok(Srgb::new(
         r as f32 / 255.0,
         g as f32 / 255.0,
@@ -119,16 +1512,1817 @@ fn hex_to_rgb(hex: &str) -> Result<Srgb, &'static str> {
     ))
 }
 
-fn find_closest(palette: &Vec<Oklab>, color: Oklab) -> Oklab {
-    let mut dist_of_closest = std::f32::MAX;
-    let mut closest = Oklab::new(0.0, 0.0, 0.0);
+/// Palette colors alongside their coordinates in the matching space.
+struct Palette {
+    colors: Vec<Oklab>,
+    rgb: Vec<[u8; 3]>,
+    coordinates: Vec<[f32; 3]>,
+    match_space: MatchSpace,
+    /// Indices searched for the closest color, in order.
+    searched: Vec<usize>,
+    tie_break: TieBreak,
+    /// Fixed-point colors searched instead of `coordinates` when set.
+    fixed: Option<Vec<FixedOklab>>,
+    /// Lightness below which only the given indices are searched.
+    shadow: Option<(f32, Vec<usize>)>,
+    /// Colors searched first, see [`Palette::restrict`].
+    subset: Option<Subset>,
+}
+
+/// Part of a palette searched before the whole of it.
+struct Subset {
+    /// The searched indices and muted shadow colors that were kept.
+    searched: Vec<usize>,
+    muted: Vec<usize>,
+    /// Squared distance within which a kept color is closer than any color
+    /// left out: a hair under half the distance to the closest one left out,
+    /// which leaves room for float rounding. 0 for colors left out.
+    guard: Vec<f32>,
+}
+
+impl Palette {
+    fn new(colors: Vec<Oklab>, match_space: MatchSpace) -> Self {
+        let rgb = colors
+            .iter()
+            .map(|color| {
+                let rgb: Srgb = (*color).into_color();
+                [rgb.red, rgb.green, rgb.blue].map(|c| (c * 255.0).round().clamp(0.0, 255.0) as u8)
+            })
+            .collect();
+        let coordinates = colors
+            .iter()
+            .map(|color| match_space.coordinates(*color))
+            .collect();
+        Self {
+            searched: (0..colors.len()).collect(),
+            tie_break: TieBreak::default(),
+            fixed: None,
+            shadow: None,
+            subset: None,
+            colors,
+            rgb,
+            coordinates,
+            match_space,
+        }
+    }
+
+    /// Search the colors within `margin` of the closest palette color of
+    /// some color of the image first. Image colors are bucketed to 5 bits per
+    /// channel, so this costs one search per distinct bucket.
+    ///
+    /// The closest kept color is closer than any color left out when it's
+    /// within half the distance between them, by the triangle inequality, so
+    /// only matches failing that search the whole palette again.
+    fn restrict(&mut self, img: &ImageBuffer<Rgba<u8>, Vec<u8>>, margin: f32) {
+        let mut buckets = vec![false; 1 << 15];
+        for pixel in img.pixels() {
+            let [r, g, b, _] = pixel.0.map(|c| c as usize >> 3);
+            buckets[r << 10 | g << 5 | b] = true;
+        }
+
+        let mut kept = vec![false; self.colors.len()];
+        for bucket in (0..buckets.len()).filter(|&bucket| buckets[bucket]) {
+            let center = |shift: usize| ((bucket >> shift & 31) << 3 | 4) as u8;
+            let color: Oklab = Srgb::new(center(10), center(5), center(0))
+                .into_format::<f32>()
+                .into_color();
+            let (_, closest) = self.find_closest_with_distance(color);
+            let limit = (closest.sqrt() + margin).powi(2);
+            for (i, kept) in kept.iter_mut().enumerate() {
+                if !*kept && self.distance_squared(i, color) <= limit {
+                    *kept = true;
+                }
+            }
+        }
+        if kept.iter().all(|&kept| kept) {
+            return;
+        }
+
+        let left_out: Vec<usize> = (0..self.colors.len()).filter(|&i| !kept[i]).collect();
+        let guard = (0..self.colors.len())
+            .map(|i| {
+                if !kept[i] {
+                    return 0.0;
+                }
+                let closest = left_out
+                    .iter()
+                    .map(|&j| self.palette_distance_squared(i, j))
+                    .fold(f32::INFINITY, f32::min);
+                closest / 4.0 * 0.999
+            })
+            .collect();
+        let keep = |indices: &[usize]| indices.iter().copied().filter(|&i| kept[i]).collect();
+        self.subset = Some(Subset {
+            searched: keep(&self.searched),
+            muted: self
+                .shadow
+                .as_ref()
+                .map_or_else(Vec::new, |(_, muted)| keep(muted)),
+            guard,
+        });
+    }
+
+    /// Squared distance between two palette colors, as measured by
+    /// [`Palette::find_closest_with_distance`].
+    fn palette_distance_squared(&self, i: usize, j: usize) -> f32 {
+        match &self.fixed {
+            Some(fixed) => fixed[i].distance_squared(fixed[j]) as f32 / (1u64 << 32) as f32,
+            None => distance_squared(self.coordinates[i], self.coordinates[j]),
+        }
+    }
+
+    /// Only search colors with at most `chroma` for colors darker than
+    /// `lightness`.
+    fn limit_shadows(&mut self, lightness: f32, chroma: f32) {
+        let muted = self
+            .searched
+            .iter()
+            .copied()
+            .filter(|&i| {
+                let oklch: Oklch = self.colors[i].into_color();
+                oklch.chroma <= chroma
+            })
+            .collect();
+        self.shadow = Some((lightness, muted));
+    }
+
+    /// Whether only the muted colors are searched for a color with the
+    /// given lightness.
+    fn in_shadow(&self, l: f32) -> bool {
+        match &self.shadow {
+            Some((lightness, muted)) => l < *lightness && !muted.is_empty(),
+            None => false,
+        }
+    }
+
+    /// Indices searched for the closest color to a color with the given
+    /// lightness.
+    fn searched_for(&self, l: f32) -> &[usize] {
+        match &self.shadow {
+            Some((_, muted)) if self.in_shadow(l) => muted,
+            _ => &self.searched,
+        }
+    }
+
+    /// Output pixel for a palette index and an alpha in [0, 1].
+    fn rgba(&self, index: usize, alpha: f32) -> Rgba<u8> {
+        let [r, g, b] = self.rgb[index];
+        Rgba([r, g, b, (alpha * 255.0).round() as u8])
+    }
+
+    /// Squared distance between a palette color and a color in the
+    /// matching space.
+    fn distance_squared(&self, index: usize, color: Oklab) -> f32 {
+        distance_squared(self.coordinates[index], self.match_space.coordinates(color))
+    }
+
+    fn find_closest(&self, color: Oklab) -> usize {
+        self.find_closest_with_distance(color).0
+    }
+
+    /// Index of the closest palette color and its squared distance in the
+    /// matching space.
+    fn find_closest_with_distance(&self, color: Oklab) -> (usize, f32) {
+        if self.fixed.is_some() {
+            return self.find_closest_fixed(FixedOklab::from_oklab(color));
+        }
+        self.closest_of(color.l, |indices| self.search(indices, color))
+    }
+
+    /// [`Palette::find_closest_with_distance`] for a fixed-point color, only
+    /// called when the palette has fixed-point colors.
+    fn find_closest_fixed(&self, color: FixedOklab) -> (usize, f32) {
+        self.closest_of(color.lightness(), |indices| {
+            self.search_fixed(indices, color)
+        })
+    }
+
+    /// Closest color found by `search` among the colors searched for a color
+    /// with lightness `l`, trying the palette subset first.
+    fn closest_of(&self, l: f32, search: impl Fn(&[usize]) -> (usize, f32)) -> (usize, f32) {
+        if let Some(subset) = &self.subset {
+            let indices = if self.in_shadow(l) {
+                &subset.muted
+            } else {
+                &subset.searched
+            };
+            let (closest, dist_of_closest) = search(indices);
+            if dist_of_closest < subset.guard[closest] {
+                return (closest, dist_of_closest);
+            }
+        }
+        search(self.searched_for(l))
+    }
+
+    /// Closest of the given fixed-point palette colors and its squared
+    /// distance.
+    fn search_fixed(&self, indices: &[usize], color: FixedOklab) -> (usize, f32) {
+        let fixed = self.fixed.as_ref().unwrap();
+        let mut dist_of_closest = i64::MAX;
+        let mut closest = 0;
+        for &i in indices {
+            let d = color.distance_squared(fixed[i]);
+            if d < dist_of_closest
+                || (d == dist_of_closest && self.wins_tie(i, closest, color.to_oklab()))
+            {
+                dist_of_closest = d;
+                closest = i;
+            }
+        }
+        (closest, dist_of_closest as f32 / (1u64 << 32) as f32)
+    }
+
+    /// Closest of the given palette colors and its squared distance.
+    fn search(&self, indices: &[usize], color: Oklab) -> (usize, f32) {
+        let target = self.match_space.coordinates(color);
+        let mut dist_of_closest = f32::MAX;
+        let mut closest = 0;
+
+        for &i in indices {
+            let d = distance_squared(target, self.coordinates[i]);
+            if d < dist_of_closest || (d == dist_of_closest && self.wins_tie(i, closest, color)) {
+                dist_of_closest = d;
+                closest = i;
+            }
+        }
+        (closest, dist_of_closest)
+    }
+
+    /// Whether palette color `i`, found after `closest` at the same distance
+    /// from `color`, should replace it.
+    fn wins_tie(&self, i: usize, closest: usize, color: Oklab) -> bool {
+        match self.tie_break {
+            TieBreak::LowestIndex => false,
+            TieBreak::LowestLightness => self.colors[i].l < self.colors[closest].l,
+            TieBreak::SrgbDistance => {
+                let rgb: Srgb = color.into_color();
+                let target = [rgb.red * 255.0, rgb.green * 255.0, rgb.blue * 255.0];
+                let rgb_distance =
+                    |index: usize| distance_squared(target, self.rgb[index].map(|c| c as f32));
+                rgb_distance(i) < rgb_distance(closest)
+            }
+        }
+    }
+}
+
+fn distance_squared(a: [f32; 3], b: [f32; 3]) -> f32 {
+    a.iter().zip(&b).map(|(a, b)| (a - b).powi(2)).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Opaque image with red and green ramping across it.
+    fn gradient(width: u32, height: u32) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+        ImageBuffer::from_fn(width, height, |x, y| {
+            Rgba([
+                (x * 255 / width.max(2).saturating_sub(1)) as u8,
+                (y * 255 / height.max(2).saturating_sub(1)) as u8,
+                96,
+                255,
+            ])
+        })
+    }
+
+    #[test]
+    fn filter_bytes_matches_run_with_options() {
+        let img = gradient(6, 4);
+        let options = Options::default();
+        let bytes = filter_bytes(img.as_raw(), 6, 4, &options).unwrap();
+        assert_eq!(bytes, run_with_options(&img, &options).unwrap().into_raw());
+    }
+
+    #[test]
+    fn filter_bytes_rejects_wrong_length() {
+        let err = filter_bytes(&[0; 15], 2, 2, &Options::default()).unwrap_err();
+        assert!(err.to_string().contains("expected 16 bytes"), "{}", err);
+    }
+
+    #[test]
+    fn ordered_dither_follows_the_threshold_map() {
+        let img = ImageBuffer::from_pixel(4, 4, Rgba([99, 99, 99, 255]));
+        let options = Options {
+            palette_hex: &BILEVEL_PALETTE,
+            dither_mode: DitherMode::Ordered,
+            color_dither: 1.0,
+            ..Default::default()
+        };
+        let output = run_with_options(&img, &options).unwrap();
+        for (x, y, pixel) in output.enumerate_pixels() {
+            // the lower half of the entries pushes the gray toward black
+            let index = THRESHOLD_MAP[x as usize % 2][y as usize % 2];
+            let expected = if index < 2 { 0 } else { 255 };
+            assert_eq!(
+                pixel.0,
+                [expected, expected, expected, 255],
+                "at {},{}",
+                x,
+                y
+            );
+        }
+    }
+
+    #[test]
+    fn ordered_mixes_more_colors_per_tile_than_candidate_sort() {
+        // Both modes write palette colors in a threshold map pattern, but
+        // candidate sort draws each 4x4 tile from a few candidates that
+        // average to the pixel, while the textbook mode biases each pixel
+        // and snaps it alone, so its tiles scatter across more colors.
+        let img = gradient(16, 16);
+        let run = |dither_mode| {
+            let options = Options {
+                palette_hex: &PALETTE_HEX_2,
+                dither_mode,
+                color_dither: 1.0,
+                ..Default::default()
+            };
+            run_with_options(&img, &options).unwrap()
+        };
+        let ordered = run(DitherMode::Ordered);
+        let sorted = run(DitherMode::CandidateSort);
+        assert_ne!(ordered, sorted);
+        let palette: Vec<[u8; 3]> = PALETTE_HEX_2
+            .iter()
+            .map(|hex| hex_to_rgb8(hex).unwrap())
+            .collect();
+        let tile_colors = |output: &ImageBuffer<Rgba<u8>, Vec<u8>>| {
+            let mut total = 0;
+            for tile_y in (0..16).step_by(4) {
+                for tile_x in (0..16).step_by(4) {
+                    let mut colors = Vec::new();
+                    for y in tile_y..tile_y + 4 {
+                        for x in tile_x..tile_x + 4 {
+                            let [r, g, b, _] = output.get_pixel(x, y).0;
+                            assert!(palette.contains(&[r, g, b]), "{},{}", x, y);
+                            if !colors.contains(&[r, g, b]) {
+                                colors.push([r, g, b]);
+                            }
+                        }
+                    }
+                    total += colors.len();
+                }
+            }
+            total
+        };
+        assert_eq!(tile_colors(&ordered), 88);
+        assert_eq!(tile_colors(&sorted), 53);
+    }
+
+    #[test]
+    fn lab_matching_can_pick_another_color() {
+        let closest = |rgb: [u8; 3], palette_hex: &[&str], match_space| {
+            let [r, g, b] = rgb;
+            let img = ImageBuffer::from_pixel(1, 1, Rgba([r, g, b, 255]));
+            let options = Options {
+                palette_hex,
+                match_space,
+                snap_threshold: Some(f32::INFINITY),
+                ..Default::default()
+            };
+            run_with_options(&img, &options).unwrap().get_pixel(0, 0).0
+        };
+        // saturated yellow is closer to pale yellow in Oklab, but to gold
+        // in CIELAB
+        let yellow = [0xff, 0xff, 0];
+        let palette_hex = ["ffd700", "ffff80"];
+        assert_eq!(
+            closest(yellow, &palette_hex, MatchSpace::Oklab),
+            [0xff, 0xff, 0x80, 255]
+        );
+        assert_eq!(
+            closest(yellow, &palette_hex, MatchSpace::Lab),
+            [0xff, 0xd7, 0, 255]
+        );
+        // dark blue is closer to blue in Oklab, but to black in CIELAB
+        let blue = [0, 0, 0x66];
+        let palette_hex = ["0000ff", "000000"];
+        assert_eq!(
+            closest(blue, &palette_hex, MatchSpace::Oklab),
+            [0, 0, 255, 255]
+        );
+        assert_eq!(closest(blue, &palette_hex, MatchSpace::Lab), [0, 0, 0, 255]);
+    }
+
+    #[test]
+    fn snapping_leaves_fewer_pixels_dithered() {
+        // palette colors with slight noise, as from a lossy save
+        let img = ImageBuffer::from_fn(16, 16, |x, y| {
+            let [r, g, b] = hex_to_rgb8(PALETTE_HEX_2[(x / 4 + y / 8) as usize % 8]).unwrap();
+            let noise = ((x * 7 + y * 3) % 5 * 4) as u8;
+            Rgba([r.saturating_add(noise), g.saturating_sub(noise), b, 255])
+        });
+        let closest = remap(&img, &PALETTE_HEX_2).unwrap();
+        let dithered = |snap_threshold| {
+            let options = Options {
+                palette_hex: &PALETTE_HEX_2,
+                color_dither: 1.0,
+                snap_threshold,
+                ..Default::default()
+            };
+            let output = run_with_options(&img, &options).unwrap();
+            output
+                .pixels()
+                .zip(closest.pixels())
+                .filter(|(a, b)| a != b)
+                .count()
+        };
+        assert!(dithered(None) > 0);
+        assert_eq!(dithered(Some(0.06)), 0);
+    }
+
+    #[test]
+    fn snapped_pixels_still_dither_alpha() {
+        let img = ImageBuffer::from_pixel(2, 2, Rgba([0x0d, 0x2b, 0x45, 128]));
+        let options = Options {
+            palette_hex: &PALETTE_HEX_2,
+            snap_threshold: Some(f32::INFINITY),
+            ..Default::default()
+        };
+        let output = run_with_options(&img, &options).unwrap();
+        let mut alphas: Vec<u8> = output.pixels().map(|pixel| pixel.0[3]).collect();
+        alphas.sort();
+        assert_eq!(alphas, [0, 0, 255, 255]);
+        assert!(output
+            .pixels()
+            .all(|pixel| pixel.0[..3] == [0x0d, 0x2b, 0x45]));
+    }
+
+    #[test]
+    fn ordered_dither_without_spread_snaps() {
+        let img = gradient(8, 8);
+        let options = Options {
+            dither_mode: DitherMode::Ordered,
+            color_dither: 0.0,
+            ..Default::default()
+        };
+        let snapped = Options {
+            snap_threshold: Some(f32::INFINITY),
+            ..Default::default()
+        };
+        assert_eq!(
+            run_with_options(&img, &options).unwrap(),
+            run_with_options(&img, &snapped).unwrap()
+        );
+    }
+
+    #[test]
+    fn preserved_colors_pass_through() {
+        let key = Rgba([0xff, 0x00, 0xff, 200]);
+        let img = ImageBuffer::from_fn(4, 1, |x, _| {
+            if x % 2 == 0 {
+                key
+            } else {
+                Rgba([0xfe, 0x00, 0xff, 200])
+            }
+        });
+        let options = Options {
+            preserve_colors: &["ff00ff"],
+            ..Default::default()
+        };
+        let output = run_with_options(&img, &options).unwrap();
+        assert_eq!(*output.get_pixel(0, 0), key);
+        assert_eq!(*output.get_pixel(2, 0), key);
+        // a near miss is quantized as usual
+        assert_ne!(output.get_pixel(1, 0).0[..3], [0xfe, 0x00, 0xff]);
+    }
+
+    #[test]
+    fn invalid_preserved_color_is_an_error() {
+        let options = Options {
+            preserve_colors: &["zz"],
+            ..Default::default()
+        };
+        let err = run_with_options(&gradient(2, 2), &options).unwrap_err();
+        assert!(err.to_string().contains("preserved color `zz`"), "{}", err);
+    }
+
+    #[test]
+    fn bayer_matrices_are_valid_threshold_maps() {
+        assert_eq!(bayer_matrix(0), [[0]]);
+        assert_eq!(bayer_matrix(1), THRESHOLD_MAP.map(|row| row.to_vec()));
+        for order in 0..=4 {
+            let map = bayer_matrix(order);
+            assert!(is_valid_threshold_map(&map));
+            let mut entries: Vec<usize> = map.concat();
+            entries.sort();
+            assert_eq!(entries, (0..4usize.pow(order)).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn dither_scale_enlarges_the_pattern() {
+        let img = ImageBuffer::from_pixel(8, 8, Rgba([120, 90, 140, 255]));
+        let options = Options {
+            color_dither: 0.5,
+            ..Default::default()
+        };
+        let fine = run_with_options(&img, &options).unwrap();
+        let coarse = Options {
+            dither_scale: 2,
+            ..options
+        };
+        let coarse = run_with_options(&img, &coarse).unwrap();
+        assert!(fine.pixels().any(|pixel| pixel != fine.get_pixel(0, 0)));
+        for (x, y, pixel) in coarse.enumerate_pixels() {
+            assert_eq!(pixel, fine.get_pixel(x / 2, y / 2));
+        }
+    }
+
+    #[test]
+    fn indexed_output_matches_the_colors() {
+        let img = gradient(8, 6);
+        let options = Options::default();
+        let indexed = run_indexed(&img, &options).unwrap();
+        let output = run_with_options(&img, &options).unwrap();
+        let palette = effective_palette(options.palette_hex);
+        for (x, y, pixel) in output.enumerate_pixels() {
+            let [r, g, b, a] = pixel.0;
+            assert_eq!(
+                palette[indexed.index(x, y)],
+                format!("{:02x}{:02x}{:02x}", r, g, b)
+            );
+            assert_eq!(indexed.alpha[(y * 8 + x) as usize], a);
+        }
+    }
+
+    #[test]
+    fn snap_if_better_keeps_exact_palette_colors() {
+        let [r, g, b] = hex_to_rgb8(PALETTE_HEX_2[3]).unwrap();
+        let img = ImageBuffer::from_pixel(4, 4, Rgba([r, g, b, 255]));
+        let options = Options {
+            palette_hex: &PALETTE_HEX_2,
+            dither_mode: DitherMode::Ordered,
+            color_dither: 1.0,
+            ..Default::default()
+        };
+        let exact = |output: &ImageBuffer<Rgba<u8>, Vec<u8>>| {
+            output
+                .pixels()
+                .filter(|pixel| pixel.0 == [r, g, b, 255])
+                .count()
+        };
+        assert!(exact(&run_with_options(&img, &options).unwrap()) < 16);
+        let options = Options {
+            snap_if_better: Some(0.0),
+            ..options
+        };
+        assert_eq!(exact(&run_with_options(&img, &options).unwrap()), 16);
+    }
+
+    #[test]
+    fn remap_snaps_colors_and_keeps_alpha() {
+        let img = ImageBuffer::from_fn(16, 1, |x, _| Rgba([200, 120, 90, (x * 17) as u8]));
+        let output = remap(&img, &PALETTE_HEX_2).unwrap();
+        let [r, g, b] = hex_to_rgb8(PALETTE_HEX_2[4]).unwrap();
+        for (pixel, source) in output.pixels().zip(img.pixels()) {
+            assert_eq!(pixel.0, [r, g, b, source.0[3]]);
+        }
+    }
+
+    #[test]
+    fn small_and_empty_images_filter_without_panicking() {
+        let threshold_map = [[0, 2], [3, 1]];
+        for (width, height) in [(1, 1), (1, 7), (7, 1), (0, 5), (5, 0), (0, 0)] {
+            let img = gradient(width, height);
+            let output = run(&img).unwrap();
+            assert_eq!(output.dimensions(), (width, height));
+            let output =
+                run_with_parameters(&img, &threshold_map, 0.5, 0.5, &PALETTE_HEX_2).unwrap();
+            assert_eq!(output.dimensions(), (width, height));
+            // every pixel still ends up a palette color
+            let palette: Vec<_> = PALETTE_HEX_2
+                .iter()
+                .map(|hex| hex_to_rgb8(hex).unwrap())
+                .collect();
+            for pixel in output.pixels() {
+                let [r, g, b, _] = pixel.0;
+                assert!(palette.contains(&[r, g, b]));
+            }
+        }
+    }
+
+    #[test]
+    fn empty_images_still_check_the_options() {
+        let img = gradient(0, 3);
+        let options = Options {
+            palette_hex: &[],
+            ..Default::default()
+        };
+        assert!(run_with_options(&img, &options).is_err());
+        assert!(run_with_parameters(&img, &[[0, 1], [2, 4]], 0.5, 0.5, &PALETTE_HEX_2).is_err());
+    }
+
+    #[test]
+    fn channel_levels_keep_flat_normals_valid() {
+        let img = ImageBuffer::from_pixel(4, 4, Rgba([128, 128, 255, 255]));
+        let options = Options {
+            channel_levels: Some(ChannelLevels {
+                channels: [true; 3],
+                levels: 3,
+                dither: [false; 3],
+            }),
+            ..Default::default()
+        };
+        let output = run_with_options(&img, &options).unwrap();
+        // the palette would shift the color, but the levels keep it
+        for pixel in output.pixels() {
+            assert_eq!(pixel.0, [128, 128, 255, 255]);
+        }
+    }
+
+    #[test]
+    fn channel_levels_only_touch_the_given_channels() {
+        let img = ImageBuffer::from_pixel(2, 2, Rgba([100, 100, 100, 255]));
+        let options = Options {
+            palette_hex: &["ff0000"],
+            channel_levels: Some(ChannelLevels {
+                channels: [false, true, true],
+                levels: 2,
+                dither: [false; 3],
+            }),
+            ..Default::default()
+        };
+        let output = run_with_options(&img, &options).unwrap();
+        for pixel in output.pixels() {
+            assert_eq!(pixel.0, [255, 0, 0, 255]);
+        }
+    }
+
+    #[test]
+    fn alpha_weighted_error_keeps_transparent_pixels_flat() {
+        // a transparent border around an opaque gray interior
+        let img = ImageBuffer::from_fn(8, 8, |x, y| {
+            let inside = (2..6).contains(&x) && (2..6).contains(&y);
+            Rgba([119, 119, 119, if inside { 255 } else { 0 }])
+        });
+        let filter = |alpha_weighted_error| {
+            let options = Options {
+                palette_hex: &["000000", "ffffff"],
+                color_dither: 1.0,
+                preserve_alpha: true,
+                alpha_weighted_error,
+                ..Default::default()
+            };
+            run_with_options(&img, &options).unwrap()
+        };
+        let (weighted, unweighted) = (filter(true), filter(false));
+        let colors = |output: &ImageBuffer<Rgba<u8>, Vec<u8>>, alpha| {
+            let mut colors: Vec<_> = output
+                .pixels()
+                .filter(|pixel| pixel.0[3] == alpha)
+                .map(|pixel| pixel.0)
+                .collect();
+            colors.sort();
+            colors.dedup();
+            colors.len()
+        };
+        assert_eq!(colors(&weighted, 0), 1);
+        assert!(colors(&unweighted, 0) > 1);
+        // the interior dithers the same either way
+        for (x, y, pixel) in weighted.enumerate_pixels() {
+            if pixel.0[3] == 255 {
+                assert_eq!(pixel, unweighted.get_pixel(x, y));
+            }
+        }
+    }
+
+    #[test]
+    fn more_passes_never_add_noise() {
+        let img = gradient(32, 32);
+        let options = Options {
+            palette_hex: &["000000", "ff0000", "00ff00", "ffff00"],
+            color_dither: 0.5,
+            dither_mode: DitherMode::Ordered,
+            ..Default::default()
+        };
+        // neighbors of different colors
+        let transitions = |output: &ImageBuffer<Rgba<u8>, Vec<u8>>| {
+            output
+                .enumerate_pixels()
+                .filter(|&(x, y, pixel)| x > 0 && pixel != output.get_pixel(x - 1, y))
+                .count()
+        };
+        let counts: Vec<_> = (1..=3)
+            .map(|passes| transitions(&run_passes(&img, &options, passes).unwrap()))
+            .collect();
+        assert_eq!(
+            run_passes(&img, &options, 1).unwrap(),
+            run_with_options(&img, &options).unwrap()
+        );
+        assert!(
+            counts.windows(2).all(|pair| pair[1] <= pair[0]),
+            "{:?}",
+            counts
+        );
+        // palette colors quantize to themselves, so the passes settle
+        assert_eq!(
+            run_passes(&img, &options, 2).unwrap(),
+            run_passes(&img, &options, 3).unwrap()
+        );
+    }
+
+    #[test]
+    fn uniform_rows_match_the_per_pixel_path() {
+        // solid rows take the fast path, while the last row doesn't
+        let img = ImageBuffer::from_fn(12, 9, |x, y| {
+            if y < 8 {
+                Rgba([90, 140, 200, 180])
+            } else {
+                Rgba([x as u8 * 20, 140, 200, 180])
+            }
+        });
+        let options = Options {
+            dither_scale: 3,
+            ..Default::default()
+        };
+        let output = run_with_options(&img, &options).unwrap();
+        let filter = Filter::for_image(&options, &img).unwrap();
+        for (x, y, pixel) in output.enumerate_pixels() {
+            let expected = filter.finish(filter.output(filter.cell(x, y), *img.get_pixel(x, y)));
+            assert_eq!(*pixel, expected);
+        }
+        // the pattern still varies across a solid image
+        let mut colors: Vec<_> = output.pixels().map(|pixel| pixel.0).collect();
+        colors.sort();
+        colors.dedup();
+        assert!(colors.len() > 1);
+    }
+
+    #[test]
+    fn skipped_transparent_pixels_are_neutral() {
+        // a sprite that is 90% transparent
+        let img = ImageBuffer::from_fn(10, 10, |x, y| {
+            let [r, g, b, _] = gradient(10, 10).get_pixel(x, y).0;
+            Rgba([r, g, b, if x == 0 { 255 } else { 0 }])
+        });
+        let filter = |skip_transparent| {
+            let options = Options {
+                skip_transparent,
+                ..Default::default()
+            };
+            run_with_options(&img, &options).unwrap()
+        };
+        let (skipped, filtered) = (filter(true), filter(false));
+        for (x, y, pixel) in skipped.enumerate_pixels() {
+            if x == 0 {
+                assert_eq!(pixel, filtered.get_pixel(x, y));
+            } else {
+                assert_eq!(*pixel, SKIPPED_COLOR);
+            }
+        }
+    }
+
+    #[test]
+    fn transparent_pixels_that_can_dither_visible_are_not_skipped() {
+        let options = Options {
+            skip_transparent: true,
+            alpha_dither: 4.0,
+            ..Default::default()
+        };
+        let filter = Filter::new(&options).unwrap();
+        assert!(!filter.is_skipped(Rgba([255, 0, 0, 0])));
+    }
+
+    #[test]
+    fn hue_rotation_turns_red_to_cyan() {
+        let img = ImageBuffer::from_pixel(2, 2, Rgba([220, 40, 40, 255]));
+        let filter = |hue_rotate| {
+            let options = Options {
+                palette_hex: &["d02828", "28d0d0"],
+                color_dither: 0.0,
+                hue_rotate,
+                ..Default::default()
+            };
+            run_with_options(&img, &options).unwrap().get_pixel(0, 0).0
+        };
+        assert_eq!(filter(0.0), [0xd0, 0x28, 0x28, 255]);
+        assert_eq!(filter(180.0), [0x28, 0xd0, 0xd0, 255]);
+        assert_eq!(filter(360.0), filter(0.0));
+    }
+
+    #[test]
+    fn color_and_alpha_configs_apply_independently() {
+        let img = ImageBuffer::from_pixel(8, 8, Rgba([119, 119, 119, 100]));
+        let color = ColorConfig {
+            dither: 1.0,
+            palette_hex: &["000000", "ffffff"],
+            ..Default::default()
+        };
+        let alpha = AlphaConfig {
+            threshold_map: vec![vec![0]],
+            dither: 0.0,
+            levels: 4,
+        };
+        let output = run_with_configs(&img, &color, &alpha).unwrap();
+        // the color dithers between black and white, while alpha snaps to a
+        // single one of its 4 levels
+        assert!(output.pixels().any(|pixel| pixel.0[0] == 0));
+        assert!(output.pixels().any(|pixel| pixel.0[0] == 255));
+        assert!(output.pixels().all(|pixel| pixel.0[3] == 85));
+
+        let color = ColorConfig {
+            dither: 0.0,
+            ..color
+        };
+        let alpha = AlphaConfig {
+            threshold_map: vec![vec![0, 2], vec![3, 1]],
+            dither: 1.0,
+            levels: 2,
+        };
+        let output = run_with_configs(&img, &color, &alpha).unwrap();
+        assert!(output.pixels().all(|pixel| pixel.0[..3] == [255, 255, 255]));
+        assert!(output.pixels().any(|pixel| pixel.0[3] == 0));
+        assert!(output.pixels().any(|pixel| pixel.0[3] == 255));
+    }
+
+    #[test]
+    fn rgba_buffer_rejects_short_and_long_data() {
+        assert!(rgba_buffer(2, 2, vec![0; 16]).is_ok());
+        let error = rgba_buffer(2, 2, vec![0; 15]).unwrap_err();
+        assert!(error.to_string().contains("expected 16 bytes"), "{}", error);
+        assert!(rgba_buffer(2, 2, vec![0; 17]).is_err());
+        // RGB without alpha has the wrong stride
+        assert!(rgba_buffer(4, 1, vec![0; 12]).is_err());
+    }
+
+    #[test]
+    fn soft_knee_compresses_toward_the_ends() {
+        assert_eq!(soft_knee(0.5, 0.0, 1.0, 0.2), 0.5);
+        let highlights: Vec<_> = (0..10)
+            .map(|i| soft_knee(0.85 + i as f32 * 0.1, 0.0, 1.0, 0.2))
+            .collect();
+        assert!(highlights.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(highlights.iter().all(|&l| (0.8..1.0).contains(&l)));
+        assert!(soft_knee(-0.5, 0.0, 1.0, 0.2) > 0.0);
+    }
+
+    #[test]
+    fn soft_knee_keeps_variation_in_highlights() {
+        // highlights mostly lighter than the lightest palette color
+        let img = ImageBuffer::from_fn(16, 1, |x, _| {
+            let c = 170 + x as u8 * 5;
+            Rgba([c, c, c, 255])
+        });
+        let levels = |soft_knee| {
+            let options = Options {
+                palette_hex: &["000000", "404040", "808080", "c0c0c0"],
+                color_dither: 0.0,
+                soft_knee,
+                ..Default::default()
+            };
+            let mut colors: Vec<_> = run_with_options(&img, &options)
+                .unwrap()
+                .pixels()
+                .map(|pixel| pixel.0)
+                .collect();
+            colors.sort();
+            colors.dedup();
+            colors.len()
+        };
+        assert!(levels(Some(0.3)) > levels(None));
+    }
+
+    #[test]
+    fn invalid_palettes_are_errors() {
+        assert!(check_palette(&PALETTE_HEX_2).is_ok());
+        assert!(check_palette(&[]).is_err());
+        let error = check_palette(&["000000", "#ffffff"]).unwrap_err();
+        assert!(
+            error.to_string().contains("palette color 1 `#ffffff`"),
+            "{}",
+            error
+        );
+        // multibyte characters fit in 6 bytes, but aren't hex
+        assert!(check_palette(&["ééé"]).is_err());
+
+        let img = gradient(2, 2);
+        assert!(run_with_parameters(&img, &[[0, 2], [3, 1]], 0.5, 0.5, &["zz"]).is_err());
+    }
+
+    #[test]
+    fn dither_map_only_dithers_where_white() {
+        let img = ImageBuffer::from_pixel(8, 8, Rgba([119, 119, 119, 255]));
+        let dither_map = ImageBuffer::from_fn(8, 8, |x, _| Luma([if x < 4 { 0 } else { 255 }]));
+        let options = Options {
+            palette_hex: &["000000", "ffffff"],
+            color_dither: 1.0,
+            dither_map: Some(&dither_map),
+            ..Default::default()
+        };
+        let output = run_with_options(&img, &options).unwrap();
+        let half = |xs: std::ops::Range<u32>| {
+            let mut colors: Vec<_> = output
+                .enumerate_pixels()
+                .filter(|(x, _, _)| xs.contains(x))
+                .map(|(_, _, pixel)| pixel.0)
+                .collect();
+            colors.sort();
+            colors.dedup();
+            colors.len()
+        };
+        assert_eq!(half(0..4), 1);
+        assert_eq!(half(4..8), 2);
+    }
+
+    #[test]
+    fn dither_map_must_match_the_image() {
+        let img = ImageBuffer::from_pixel(8, 8, Rgba([119, 119, 119, 255]));
+        let dither_map = ImageBuffer::new(8, 4);
+        let options = Options {
+            dither_map: Some(&dither_map),
+            ..Default::default()
+        };
+        assert!(run_with_options(&img, &options).is_err());
+    }
+
+    #[test]
+    fn wrapped_output_tiles_seamlessly() {
+        let img = gradient(8, 8);
+        let options = Options {
+            wrap: true,
+            dither_scale: 2,
+            scanlines: Some(ScanlinePattern {
+                period: 4,
+                strength: 0.05,
+            }),
+            ..Default::default()
+        };
+        let tile = run_with_options(&img, &options).unwrap();
+        // filtering the tiled input gives the tiled output, with nothing
+        // changing where the tiles meet
+        let tiled = |img: &ImageBuffer<Rgba<u8>, Vec<u8>>| {
+            ImageBuffer::from_fn(16, 16, |x, y| *img.get_pixel(x % 8, y % 8))
+        };
+        assert_eq!(
+            run_with_options(&tiled(&img), &options).unwrap(),
+            tiled(&tile)
+        );
+    }
+
+    #[test]
+    fn wrapping_rejects_sizes_that_cut_a_pattern() {
+        let wrapped = |width, height, scanlines| {
+            let options = Options {
+                wrap: true,
+                scanlines,
+                ..Default::default()
+            };
+            run_with_options(&gradient(width, height), &options).is_ok()
+        };
+        let map_size = THRESHOLD_MAP.len() as u32;
+        assert!(wrapped(map_size * 2, map_size, None));
+        assert!(!wrapped(map_size + 1, map_size, None));
+        assert!(!wrapped(map_size, map_size + 1, None));
+        let scanlines = ScanlinePattern {
+            period: 3,
+            strength: 0.05,
+        };
+        assert!(!wrapped(map_size, map_size, Some(scanlines)));
+        assert!(wrapped(map_size, map_size * 3, Some(scanlines)));
+    }
+
+    #[test]
+    fn scanlines_darken_alternate_rows() {
+        let img = ImageBuffer::from_pixel(8, 8, Rgba([150, 150, 150, 255]));
+        let options = Options {
+            palette_hex: &["000000", "555555", "aaaaaa", "ffffff"],
+            scanlines: Some(ScanlinePattern {
+                period: 2,
+                strength: 0.15,
+            }),
+            ..Default::default()
+        };
+        let output = run_with_options(&img, &options).unwrap();
+        let row_sum = |y| {
+            (0..8)
+                .map(|x| output.get_pixel(x, y).0[0] as u32)
+                .sum::<u32>()
+        };
+        for y in (0..8).step_by(2) {
+            assert!(row_sum(y) < row_sum(y + 1), "row {}", y);
+        }
+    }
+
+    #[test]
+    fn hue_range_only_quantizes_matching_hues() {
+        let (red, blue) = (Rgba([200, 30, 40, 255]), Rgba([40, 60, 200, 255]));
+        let img = ImageBuffer::from_fn(4, 4, |x, _| if x < 2 { red } else { blue });
+        let filter = |hue_range| {
+            let options = Options {
+                palette_hex: &["ff0000", "0000ff"],
+                color_dither: 0.0,
+                hue_range: Some(hue_range),
+                ..Default::default()
+            };
+            run_with_options(&img, &options).unwrap()
+        };
+        // red sits near 25° in Oklch, blue near 265°
+        for hue_range in [(0.0, 60.0), (350.0, 60.0)] {
+            let output = filter(hue_range);
+            for (x, _, pixel) in output.enumerate_pixels() {
+                if x < 2 {
+                    assert_eq!(*pixel, Rgba([255, 0, 0, 255]));
+                } else {
+                    assert_eq!(*pixel, blue);
+                }
+            }
+        }
+        // a range wrapping around 0° that leaves out red
+        assert_eq!(*filter((300.0, 10.0)).get_pixel(0, 0), red);
+    }
+
+    #[test]
+    fn effective_palette_is_the_given_palette() {
+        for palette_hex in [&PALETTE_HEX[..], &PALETTE_HEX_2, &BILEVEL_PALETTE] {
+            assert_eq!(effective_palette(palette_hex), palette_hex);
+        }
+        assert_eq!(
+            effective_palette(&["0f380f", "fefefe"]),
+            ["0f380f", "fefefe"]
+        );
+    }
+
+    #[test]
+    fn alpha_matte_dithers_alpha_against_the_background() {
+        // black at 63% alpha over white looks like a light gray, so less
+        // than half of it is black
+        let img = ImageBuffer::from_pixel(2, 2, Rgba([0, 0, 0, 160]));
+        let alpha = |alpha_matte| {
+            let options = Options {
+                palette_hex: &["ffffff", "000000"],
+                alpha_dither: 0.0,
+                alpha_matte,
+                ..Default::default()
+            };
+            run_with_options(&img, &options).unwrap().get_pixel(0, 0).0[3]
+        };
+        assert_eq!(alpha(None), 255);
+        assert_eq!(alpha(Some("ffffff")), 0);
+    }
+
+    #[test]
+    fn invalid_alpha_matte_is_an_error() {
+        let options = Options {
+            alpha_matte: Some("nothex"),
+            ..Default::default()
+        };
+        let error = run_with_options(&gradient(2, 2), &options).unwrap_err();
+        assert!(
+            error.to_string().contains("invalid alpha matte `nothex`"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn centered_error_reduces_the_mean_bias() {
+        let palette_hex = ["000000", "555555", "aaaaaa", "ffffff"];
+        let lightness = |[r, g, b, _]: [u8; 4]| {
+            let oklab: Oklab = Srgb::new(r, g, b).into_format::<f32>().into_color();
+            oklab.l
+        };
+        // total distance between the mean output lightness and the input's
+        // over a range of flat patches, each covering the 8×8 map once
+        let bias = |centered_error| {
+            (0..=255)
+                .step_by(5)
+                .map(|c| {
+                    let img = ImageBuffer::from_pixel(8, 8, Rgba([c, c, c, 255]));
+                    let options = Options {
+                        palette_hex: &palette_hex,
+                        threshold_map: bayer_matrix(3),
+                        centered_error,
+                        ..Default::default()
+                    };
+                    let output = run_with_options(&img, &options).unwrap();
+                    let mean = output.pixels().map(|pixel| lightness(pixel.0)).sum::<f32>() / 64.0;
+                    (mean - lightness([c, c, c, 255])).abs()
+                })
+                .sum::<f32>()
+        };
+        let (forward, centered) = (bias(false), bias(true));
+        assert!(
+            centered < forward,
+            "centered {} forward {}",
+            centered,
+            forward
+        );
+    }
+
+    #[test]
+    fn indices_follow_the_palette_order() {
+        let palette_hex = ["0000ff", "ff0000", "00ff00", "0000ff"];
+        let options = Options {
+            palette_hex: &palette_hex,
+            ..Default::default()
+        };
+        let img = ImageBuffer::from_fn(3, 1, |x, _| {
+            [
+                Rgba([255, 0, 0, 255]),
+                Rgba([0, 255, 0, 255]),
+                Rgba([0, 0, 255, 255]),
+            ][x as usize]
+        });
+        let indexed = run_indexed(&img, &options).unwrap();
+        assert_eq!(indexed.indices, [1, 2, 0]);
+        assert_eq!(effective_palette(&palette_hex), palette_hex);
+    }
+
+    #[test]
+    fn hex_palettes_are_in_gamut() {
+        assert!(out_of_gamut(&palette_as_oklab(&PALETTE_HEX_2)).is_empty());
+        let primaries = palette_as_oklab(&["ff0000", "00ff00", "0000ff", "ffffff"]);
+        assert!(out_of_gamut(&primaries).is_empty());
+        let green = Oklab::new(0.8, -0.35, 0.2);
+        assert_eq!(out_of_gamut(&[primaries[0], green]), [1]);
+    }
+
+    #[test]
+    fn bilevel_output_is_only_black_and_white() {
+        let options = Options {
+            threshold_map: bayer_matrix(2),
+            ..Default::default()
+        };
+        let img = ImageBuffer::from_fn(16, 4, |x, _| {
+            let c = (x * 17) as u8;
+            Rgba([c, c, c, 255])
+        });
+        let output = run_bilevel(&img, &options).unwrap();
+        assert!(output
+            .pixels()
+            .all(|pixel| pixel.0[0] == 0 || pixel.0[0] == 255));
+        // whiter toward the light end of the gradient
+        let whites = |x: u32| {
+            (0..4)
+                .filter(|&y| output.get_pixel(x, y).0[0] == 255)
+                .count()
+        };
+        assert_eq!(whites(0), 0);
+        assert_eq!(whites(15), 4);
+
+        let flat = |c| {
+            let img = ImageBuffer::from_pixel(4, 4, Rgba([c, c, c, 255]));
+            let output = run_bilevel(&img, &options).unwrap();
+            output.pixels().filter(|pixel| pixel.0[0] == 255).count()
+        };
+        assert_eq!(flat(0), 0);
+        assert_eq!(flat(255), 16);
+        assert!((1..16).contains(&flat(128)));
+    }
+
+    #[test]
+    fn filtering_in_bands_matches_a_single_run() {
+        let img = gradient(13, 11);
+        let options = Options {
+            palette_hex: &PALETTE_HEX_2,
+            threshold_map: bayer_matrix(2),
+            color_dither: 1.0,
+            ..Default::default()
+        };
+        let expected = run_with_options(&img, &options).unwrap();
+        let mut output = ImageBuffer::new(13, 11);
+        for row in (0..11).step_by(4) {
+            run_rows(&img, &options, row..row + 4, &mut output).unwrap();
+        }
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn hex_lattice_shifts_alternate_rows_of_maps() {
+        let palette_hex = ["000000", "ffffff"];
+        let img = ImageBuffer::from_pixel(16, 8, Rgba([128, 128, 128, 255]));
+        let run = |lattice| {
+            let options = Options {
+                palette_hex: &palette_hex,
+                threshold_map: bayer_matrix(2),
+                color_dither: 1.0,
+                lattice,
+                ..Default::default()
+            };
+            run_with_options(&img, &options).unwrap()
+        };
+        let (square, hex) = (run(Lattice::Square), run(Lattice::Hex));
+        assert_ne!(square, hex);
+        for (x, y, pixel) in hex.enumerate_pixels() {
+            let shift = if y < 4 { 0 } else { 2 };
+            assert_eq!(pixel, square.get_pixel((x + shift) % 16, y), "{} {}", x, y);
+        }
+
+        // shifted rows only repeat every other row of maps
+        let options = Options {
+            threshold_map: bayer_matrix(2),
+            lattice: Lattice::Hex,
+            wrap: true,
+            ..Default::default()
+        };
+        assert!(run_with_options(&ImageBuffer::new(8, 4), &options).is_err());
+        assert!(run_with_options(&ImageBuffer::new(8, 8), &options).is_ok());
+    }
+
+    #[test]
+    fn palette_subset_gives_the_same_output_at_any_margin() {
+        // a 64 color palette spread over the RGB cube
+        let palette: Vec<String> = (0..64)
+            .map(|i| {
+                format!(
+                    "{:02x}{:02x}{:02x}",
+                    (i & 3) * 85,
+                    (i >> 2 & 3) * 85,
+                    (i >> 4) * 85
+                )
+            })
+            .collect();
+        let palette_hex: Vec<&str> = palette.iter().map(|s| s.as_str()).collect();
+        // only reds and greens, so most of the palette is left out
+        let img = gradient(16, 16);
+        let variants = [
+            Options::default(),
+            Options {
+                color_dither: 1.0,
+                threshold_map: bayer_matrix(2),
+                ..Default::default()
+            },
+            Options {
+                hue_rotate: 90.0,
+                ..Default::default()
+            },
+            Options {
+                approximate: true,
+                shadow_chroma: Some((0.5, 0.05)),
+                ..Default::default()
+            },
+        ];
+        for base in variants {
+            let base = Options {
+                palette_hex: &palette_hex,
+                ..base
+            };
+            let expected = run_with_options(&img, &base).unwrap();
+            for margin in [0.0, 0.01, 0.1, 10.0] {
+                let options = Options {
+                    palette_subset: Some(margin),
+                    ..base.clone()
+                };
+                let filter = Filter::for_image(&options, &img).unwrap();
+                if margin == 0.0 {
+                    let subset = filter.palette.subset.as_ref().unwrap();
+                    assert!(subset.searched.len() < palette_hex.len() / 2);
+                }
+                assert_eq!(
+                    run_with_options(&img, &options).unwrap(),
+                    expected,
+                    "{}",
+                    margin
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn dither_strength_maps_onto_color_dither_and_back() {
+        assert_eq!(color_dither_from_strength(0.0), 0.0);
+        assert_eq!(color_dither_from_strength(100.0), MAX_COLOR_DITHER);
+        assert!((color_dither_from_strength(40.0) - COLOR_DITHER).abs() < 1e-6);
+        // out of range strengths saturate
+        assert_eq!(color_dither_from_strength(-5.0), 0.0);
+        assert_eq!(color_dither_from_strength(150.0), MAX_COLOR_DITHER);
+        assert_eq!(strength_from_color_dither(1.0), 100.0);
+        for strength in [0.0, 12.5, 40.0, 77.0, 100.0] {
+            let round_trip = strength_from_color_dither(color_dither_from_strength(strength));
+            assert!((round_trip - strength).abs() < 1e-3, "{}", strength);
+        }
+        // weak strengths change color_dither less per step
+        let step = |strength| {
+            color_dither_from_strength(strength + 1.0) - color_dither_from_strength(strength)
+        };
+        assert!(step(10.0) < step(90.0));
+    }
+
+    #[test]
+    fn oklab_palettes_replace_the_hex_palette() {
+        // black, and a green outside sRGB that gets clamped on output
+        let palette_oklab = [Oklab::new(0.0, 0.0, 0.0), Oklab::new(0.8, -0.35, 0.2)];
+        let written = oklab_palette_hex(&palette_oklab);
+        assert_eq!(written[0], "000000");
+        let options = Options {
+            palette_hex: &PALETTE_HEX_2,
+            palette_oklab: Some(&palette_oklab),
+            ..Default::default()
+        };
+        let output = run_with_options(&gradient(8, 8), &options).unwrap();
+        for pixel in output.pixels() {
+            let [r, g, b, _] = pixel.0;
+            assert!(written.contains(&format!("{:02x}{:02x}{:02x}", r, g, b)));
+        }
+        let indexed = run_indexed(&gradient(8, 8), &options).unwrap();
+        assert!(indexed.indices.iter().all(|&index| index < 2));
+        assert!(indexed.indices.contains(&1));
+    }
+
+    #[test]
+    fn transparent_index_clears_the_alpha_of_its_pixels() {
+        let palette_hex = ["00ff00", "000000", "ffffff"];
+        let options = Options {
+            palette_hex: &palette_hex,
+            transparent_index: Some(0),
+            ..Default::default()
+        };
+        let img = ImageBuffer::from_fn(3, 1, |x, _| {
+            [
+                Rgba([0, 255, 0, 255]),
+                Rgba([0, 0, 0, 255]),
+                Rgba([255, 255, 255, 255]),
+            ][x as usize]
+        });
+        let output = run_with_options(&img, &options).unwrap();
+        let alpha: Vec<u8> = output.pixels().map(|pixel| pixel.0[3]).collect();
+        assert_eq!(alpha, [0, 255, 255]);
+        let indexed = run_indexed(&img, &options).unwrap();
+        assert_eq!(indexed.indices, [0, 1, 2]);
+        assert_eq!(indexed.alpha, [0, 255, 255]);
+    }
+
+    #[test]
+    fn dual_index_keeps_the_two_colors_of_the_dither() {
+        let palette_hex = ["000000", "ffffff", "ff0000"];
+        let options = Options {
+            palette_hex: &palette_hex,
+            threshold_map: bayer_matrix(2),
+            color_dither: 1.0,
+            ..Default::default()
+        };
+        let img = ImageBuffer::from_fn(4, 4, |x, _| {
+            if x < 2 {
+                Rgba([255, 0, 0, 255])
+            } else {
+                Rgba([100, 100, 100, 255])
+            }
+        });
+        let dual = run_dual_index(&img, &options).unwrap();
+        let output = run_indexed(&img, &options).unwrap();
+        for i in 0..16 {
+            if i % 4 < 2 {
+                // palette colors aren't dithered
+                assert_eq!((dual.first[i], dual.second[i], dual.weight[i]), (2, 2, 0.0));
+            } else {
+                let mut pair = [dual.first[i], dual.second[i]];
+                pair.sort();
+                assert_eq!(pair, [0, 1]);
+                assert!(dual.weight[i] > 0.0 && dual.weight[i] < 1.0);
+                assert!(pair.contains(&output.indices[i]));
+            }
+        }
+    }
+
+    #[test]
+    fn level_dithering_is_chosen_per_channel() {
+        let img = ImageBuffer::from_pixel(4, 4, Rgba([128, 128, 128, 255]));
+        let options = Options {
+            palette_hex: &["000000"],
+            threshold_map: bayer_matrix(2),
+            channel_levels: Some(ChannelLevels {
+                channels: [true; 3],
+                levels: 2,
+                dither: [true, false, true],
+            }),
+            ..Default::default()
+        };
+        let output = run_with_options(&img, &options).unwrap();
+        let channel = |c: usize| {
+            let mut values: Vec<u8> = output.pixels().map(|pixel| pixel.0[c]).collect();
+            values.sort();
+            values.dedup();
+            values
+        };
+        assert_eq!(channel(0), [0, 255]);
+        assert_eq!(channel(1).len(), 1);
+        assert_eq!(channel(2), [0, 255]);
+    }
+
+    #[test]
+    fn dual_index_reconstructs_the_filtered_gradient() {
+        // what a shader does with the dual index image
+        let reconstruct = |dual: &DualIndexImage, options: &Options| {
+            let filter = Filter::new(options).unwrap();
+            let entries = options.threshold_map.len().pow(2) as f32;
+            (0..dual.height)
+                .flat_map(|y| (0..dual.width).map(move |x| (x, y)))
+                .map(|(x, y)| {
+                    let i = (y * dual.width + x) as usize;
+                    let threshold = (filter.cell(x, y).index as f32 + 0.5) / entries;
+                    if threshold < dual.weight[i] {
+                        dual.second[i]
+                    } else {
+                        dual.first[i]
+                    }
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // a ramp between the two colors is reproduced exactly
+        let ramp = ImageBuffer::from_fn(16, 16, |x, y| {
+            let value = (y * 16 + x) as u8;
+            Rgba([value, value, value, 255])
+        });
+        let bilevel = Options {
+            palette_hex: &BILEVEL_PALETTE,
+            ..Default::default()
+        };
+        let dual = run_dual_index(&ramp, &bilevel).unwrap();
+        assert_eq!(
+            reconstruct(&dual, &bilevel),
+            run_indexed(&ramp, &bilevel).unwrap().indices
+        );
+
+        // pixels mixing more colors keep only two, but stay close
+        let img = gradient(16, 16);
+        let options = Options {
+            color_dither: 1.0,
+            ..Default::default()
+        };
+        let palette = Filter::new(&options).unwrap().palette.colors;
+        let dual = run_dual_index(&img, &options).unwrap();
+        let output = run_indexed(&img, &options).unwrap().indices;
+        let reconstructed = reconstruct(&dual, &options);
+        let matching = reconstructed.iter().zip(&output).filter(|(a, b)| a == b);
+        assert!(matching.count() > 256 * 8 / 10);
+        let distance: f32 = reconstructed
+            .iter()
+            .zip(&output)
+            .map(|(&a, &b)| {
+                let (a, b) = (palette[a], palette[b]);
+                ((a.l - b.l).powi(2) + (a.a - b.a).powi(2) + (a.b - b.b).powi(2)).sqrt()
+            })
+            .sum();
+        assert!(distance / 256.0 < 0.02, "{}", distance / 256.0);
+    }
+
+    #[test]
+    fn tie_break_picks_among_equally_close_colors() {
+        let closest = |colors: Vec<Oklab>, tie_break, target| {
+            let mut palette = Palette::new(colors, MatchSpace::Oklab);
+            palette.tie_break = tie_break;
+            palette.find_closest(target)
+        };
+        // exactly as far above as below
+        let target = Oklab::new(0.5, 0.0, 0.0);
+        let colors = vec![Oklab::new(0.75, 0.0, 0.0), Oklab::new(0.25, 0.0, 0.0)];
+        assert_eq!(closest(colors.clone(), TieBreak::LowestIndex, target), 0);
+        assert_eq!(closest(colors, TieBreak::LowestLightness, target), 1);
+
+        // as far toward green as toward red, which is closer in sRGB
+        let colors = vec![Oklab::new(0.5, -0.0625, 0.0), Oklab::new(0.5, 0.0625, 0.0)];
+        let palette = Palette::new(colors.clone(), MatchSpace::Oklab);
+        let rgb: Srgb = target.into_color();
+        let rgb_distance = |index: usize| {
+            let target = [rgb.red, rgb.green, rgb.blue].map(|c| c * 255.0);
+            distance_squared(target, palette.rgb[index].map(|c| c as f32))
+        };
+        assert!(rgb_distance(1) < rgb_distance(0));
+        assert_eq!(closest(colors.clone(), TieBreak::LowestIndex, target), 0);
+        assert_eq!(closest(colors, TieBreak::SrgbDistance, target), 1);
+    }
+
+    #[test]
+    fn two_stage_maps_flat_regions_to_palette_colors() {
+        let palette_hex = ["000000", "ffffff", "ff0000"];
+        let options = Options {
+            palette_hex: &palette_hex,
+            ..Default::default()
+        };
+        // slightly noisy dark and light halves, and a transparent corner
+        let img = ImageBuffer::from_fn(8, 4, |x, y| {
+            let noise = ((x * 7 + y * 3) % 5) as u8;
+            let alpha = if (x, y) == (0, 0) { 40 } else { 255 };
+            if x < 4 {
+                Rgba([30 + noise, 30, 30, alpha])
+            } else {
+                Rgba([220 + noise, 220, 220, 255])
+            }
+        });
+        let output = run_two_stage(&img, &options, 2).unwrap();
+        for (x, y, pixel) in output.enumerate_pixels() {
+            let expected = if x < 4 { [0, 0, 0] } else { [255, 255, 255] };
+            assert_eq!(pixel.0[..3], expected, "{} {}", x, y);
+        }
+        assert_eq!(output.get_pixel(0, 0).0[3], 0);
+        assert_eq!(output.get_pixel(1, 0).0[3], 255);
+    }
+
+    #[test]
+    fn two_stage_writes_at_most_n_colors_unlike_a_direct_run() {
+        let img = gradient(16, 16);
+        let distinct = |output: &ImageBuffer<Rgba<u8>, Vec<u8>>| {
+            output
+                .pixels()
+                .collect::<std::collections::HashSet<_>>()
+                .len()
+        };
+        let options = Options {
+            color_dither: 0.0,
+            ..Default::default()
+        };
+        let direct = run_with_options(&img, &options).unwrap();
+        assert!(distinct(&direct) > 4);
+        for n in [1, 2, 4] {
+            let output = run_two_stage(&img, &options, n).unwrap();
+            assert!(distinct(&output) <= n);
+        }
+        // with a box for every color, each pixel is matched on its own, as
+        // without dithering in a direct run
+        assert_eq!(run_two_stage(&img, &options, 256).unwrap(), direct);
+
+        let dither_map = ImageBuffer::new(4, 4);
+        let options = Options {
+            dither_map: Some(&dither_map),
+            ..options
+        };
+        assert!(run_two_stage(&img, &options, 4).is_err());
+    }
+
+    #[test]
+    fn approximate_output_mostly_matches_the_exact_output() {
+        let img = gradient(64, 64);
+        let exact = run_with_options(&img, &Options::default()).unwrap();
+        let options = Options {
+            approximate: true,
+            ..Default::default()
+        };
+        let approximate = run_with_options(&img, &options).unwrap();
+        let differ = exact
+            .pixels()
+            .zip(approximate.pixels())
+            .filter(|(a, b)| a != b)
+            .count();
+        assert!(differ * 100 < exact.len() / 4, "{differ} pixels differ");
+    }
+
+    #[test]
+    fn more_candidates_give_more_tones_from_a_small_map() {
+        // distinct 2x2 outputs over all flat grays
+        let tones = |candidates| {
+            let options = Options {
+                palette_hex: &BILEVEL_PALETTE,
+                color_dither: 1.0,
+                candidates: Some(candidates),
+                ..Default::default()
+            };
+            let outputs: std::collections::HashSet<_> = (0..=255)
+                .map(|c| {
+                    let img = ImageBuffer::from_pixel(2, 2, Rgba([c, c, c, 255]));
+                    run_with_options(&img, &options).unwrap().into_raw()
+                })
+                .collect();
+            outputs.len()
+        };
+        assert_eq!(tones(1), 2);
+        assert_eq!(tones(2), 3);
+        // all four pixels of the map can differ
+        assert_eq!(tones(4), 5);
+        assert_eq!(tones(16), 5);
+
+        let options = Options {
+            candidates: Some(0),
+            ..Default::default()
+        };
+        assert!(run_with_options(&gradient(2, 2), &options).is_err());
+    }
+
+    #[test]
+    fn premultiplied_output_scales_colors_by_alpha() {
+        let img = ImageBuffer::from_fn(8, 8, |x, y| {
+            Rgba([x as u8 * 32, y as u8 * 32, 96, (x * 36) as u8])
+        });
+        let straight = Options {
+            preserve_alpha: true,
+            ..Default::default()
+        };
+        let premultiplied = Options {
+            output_premultiplied: true,
+            ..straight.clone()
+        };
+        let straight = run_with_options(&img, &straight).unwrap();
+        let premultiplied = run_with_options(&img, &premultiplied).unwrap();
+        for (s, p) in straight.pixels().zip(premultiplied.pixels()) {
+            let a = s.0[3];
+            assert_eq!(p.0[3], a);
+            for c in 0..3 {
+                let expected = (s.0[c] as f32 * a as f32 / 255.0).round() as u8;
+                assert_eq!(p.0[c], expected, "{:?} from {:?}", p, s);
+            }
+            if a == 0 {
+                assert_eq!(p.0, [0; 4]);
+            }
+        }
+    }
+
+    #[test]
+    fn chroma_key_only_filters_the_keyed_region() {
+        let green = Rgba([30, 220, 40, 255]);
+        let img = ImageBuffer::from_fn(8, 8, |x, y| {
+            if x < 4 {
+                green
+            } else {
+                // reds to blues, far from the key
+                Rgba([x as u8 * 32, 0, 255 - y as u8 * 32, 255])
+            }
+        });
+        let options = Options {
+            chroma_key: Some((Srgb::new(0.0, 1.0, 0.0), 0.2)),
+            ..Default::default()
+        };
+        let output = run_with_options(&img, &options).unwrap();
+        let filtered = run_with_options(&img, &Options::default()).unwrap();
+        for (x, y, pixel) in output.enumerate_pixels() {
+            let expected = if x < 4 {
+                filtered.get_pixel(x, y)
+            } else {
+                img.get_pixel(x, y)
+            };
+            assert_eq!(pixel, expected, "{} {}", x, y);
+        }
+    }
+
+    #[test]
+    fn phase_cycles_each_pixel_through_its_candidates() {
+        let img = ImageBuffer::from_pixel(2, 2, Rgba([100, 100, 100, 255]));
+        let frames: Vec<Vec<bool>> = (0..5)
+            .map(|phase| {
+                let options = Options {
+                    palette_hex: &BILEVEL_PALETTE,
+                    color_dither: 1.0,
+                    phase,
+                    ..Default::default()
+                };
+                let output = run_with_options(&img, &options).unwrap();
+                output.pixels().map(|pixel| pixel.0[0] == 255).collect()
+            })
+            .collect();
+        // wraps around the 4 map entries
+        assert_eq!(frames[4], frames[0]);
+        let whites = frames[0].iter().filter(|&&white| white).count();
+        assert!((1..4).contains(&whites));
+        assert_ne!(frames[1], frames[0]);
+        // every frame keeps the tone, and every pixel shows each candidate
+        for pixel in 0..4 {
+            let shown = frames[..4].iter().filter(|frame| frame[pixel]).count();
+            assert_eq!(shown, whites);
+        }
+        for frame in &frames {
+            assert_eq!(frame.iter().filter(|&&white| white).count(), whites);
+        }
+    }
+
+    #[test]
+    fn shadow_chroma_keeps_saturated_colors_out_of_shadows() {
+        let palette_hex = ["000000", "404040", "800000", "ff4040", "ffffff"];
+        // dark reds on the left, light reds on the right
+        let img = ImageBuffer::from_fn(16, 4, |x, y| {
+            let c = x as u8 * 12;
+            Rgba([c + 40 + y as u8 * 4, c / 2, c / 2, 255])
+        });
+        let options = Options {
+            palette_hex: &palette_hex,
+            shadow_chroma: Some((0.4, 0.05)),
+            ..Default::default()
+        };
+        let dark_red = Rgba([0x80, 0, 0, 255]);
+        let plain = run_with_options(
+            &img,
+            &Options {
+                shadow_chroma: None,
+                ..options.clone()
+            },
+        )
+        .unwrap();
+        let output = run_with_options(&img, &options).unwrap();
+        let mut limited = 0;
+        for ((pixel, before), source) in output.pixels().zip(plain.pixels()).zip(img.pixels()) {
+            let [r, g, b, _] = source.0;
+            let source: Oklab = Srgb::new(r, g, b).into_format::<f32>().into_color();
+            // well below the limit, so that dithered samples stay below too
+            if source.l < 0.3 {
+                assert!(pixel.0[0] == pixel.0[1], "{:?} from {:?}", pixel, source);
+                limited += (*before == dark_red) as usize;
+            }
+        }
+        assert!(limited > 0);
+        // lighter pixels still get the saturated colors
+        assert!(output
+            .pixels()
+            .any(|&pixel| pixel == Rgba([0xff, 0x40, 0x40, 255])));
+
+        // ignored without any muted color
+        let saturated = ["800000", "ff4040"];
+        let options = Options {
+            palette_hex: &saturated,
+            ..options
+        };
+        let without = Options {
+            shadow_chroma: None,
+            ..options.clone()
+        };
+        assert_eq!(
+            run_with_options(&img, &options).unwrap(),
+            run_with_options(&img, &without).unwrap()
+        );
+    }
+
+    #[test]
+    fn ink_edges_wrap_around_the_borders() {
+        // a black first column, so the last column borders it when tiled
+        let img = ImageBuffer::from_fn(4, 4, |x, _| {
+            let c = if x == 0 { 0 } else { 255 };
+            Rgba([c, c, c, 255])
+        });
+        let inked_columns = |wrap| {
+            let options = Options {
+                palette_hex: &BILEVEL_PALETTE,
+                ink_edges: Some(0.5),
+                wrap,
+                ..Default::default()
+            };
+            let output = run_with_options(&img, &options).unwrap();
+            (0..4)
+                .filter(|&x| (0..4).all(|y| output.get_pixel(x, y).0[0] == 0))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(inked_columns(false), [0, 1]);
+        assert_eq!(inked_columns(true), [0, 1, 3]);
+    }
+
+    #[test]
+    fn rgba_vec_holds_the_pixels_row_by_row() {
+        let img = gradient(5, 3);
+        let options = Options::default();
+        let (bytes, width, height) = run_to_rgba_vec(&img, &options).unwrap();
+        assert_eq!((width, height), (5, 3));
+        assert_eq!(bytes.len(), 5 * 3 * 4);
+        let output = run_with_options(&img, &options).unwrap();
+        for (x, y, pixel) in output.enumerate_pixels() {
+            let start = ((y * width + x) * 4) as usize;
+            assert_eq!(bytes[start..start + 4], pixel.0);
+        }
+        let empty = Options {
+            palette_hex: &[],
+            ..Default::default()
+        };
+        assert!(run_to_rgba_vec(&img, &empty).is_err());
+    }
+
+    #[test]
+    fn alpha_pattern_moves_alpha_against_the_color_pattern() {
+        let img = ImageBuffer::from_pixel(2, 2, Rgba([128, 128, 128, 128]));
+        // which pixels are white and which are opaque
+        let patterns = |alpha_pattern| {
+            let options = Options {
+                palette_hex: &BILEVEL_PALETTE,
+                color_dither: 1.0,
+                alpha_pattern,
+                ..Default::default()
+            };
+            let output = run_with_options(&img, &options).unwrap();
+            let white: Vec<bool> = output.pixels().map(|pixel| pixel.0[0] == 255).collect();
+            let opaque: Vec<bool> = output.pixels().map(|pixel| pixel.0[3] == 255).collect();
+            (white, opaque)
+        };
+        let (white, opaque) = patterns(AlphaPattern::Aligned);
+        assert_eq!(opaque, white);
+        let (_, complement) = patterns(AlphaPattern::Complement);
+        assert_eq!(complement, white.iter().map(|&w| !w).collect::<Vec<_>>());
+        let (_, shifted) = patterns(AlphaPattern::Shifted(1));
+        assert_ne!(shifted, opaque);
+        assert_eq!(shifted.iter().filter(|&&o| o).count(), 2);
+        // shifting by the whole map changes nothing
+        assert_eq!(patterns(AlphaPattern::Shifted(4)).1, opaque);
+    }
 
-    for palette_color in palette {
-        let d = color.distance_squared(*palette_color);
-        if d < dist_of_closest {
-            dist_of_closest = d;
-            closest = *palette_color
+    #[test]
+    fn drop_beyond_clears_colors_far_from_the_palette() {
+        // grays near the palette, then saturated reds and greens far from it
+        let img = ImageBuffer::from_fn(4, 2, |x, y| match (x, y) {
+            (_, 0) => {
+                let c = [0, 10, 245, 255][x as usize];
+                Rgba([c, c, c, 255])
+            }
+            (x, _) if x % 2 == 0 => Rgba([255, 0, 0, 255]),
+            _ => Rgba([0, 255, 0, 255]),
+        });
+        let options = Options {
+            palette_hex: &BILEVEL_PALETTE,
+            drop_beyond: Some(0.25),
+            ..Default::default()
+        };
+        let output = run_with_options(&img, &options).unwrap();
+        for (x, y, pixel) in output.enumerate_pixels() {
+            assert_eq!(pixel.0[3] == 0, y == 1, "{} {}", x, y);
         }
+        let options = Options {
+            drop_beyond: Some(10.0),
+            ..options
+        };
+        let output = run_with_options(&img, &options).unwrap();
+        assert!(output.pixels().all(|pixel| pixel.0[3] == 255));
     }
-    closest
 }
@@ -0,0 +1,91 @@
+use image::{
+    codecs::ico::{IcoEncoder, IcoFrame},
+    error::DecodingError,
+    ColorType, ImageBuffer, ImageError, ImageFormat, Rgba,
+};
+use std::io::Write;
+
+/// Images of an icon, in directory order.
+pub type Frames = Vec<ImageBuffer<Rgba<u8>, Vec<u8>>>;
+
+/// Bytes of the ICO header and of each directory entry.
+const HEADER_SIZE: usize = 6;
+const ENTRY_SIZE: usize = 16;
+
+/// Decode every image of an ICO file, such as the sizes of a favicon, in
+/// directory order. `image` alone only decodes the largest one.
+pub fn decode_ico(bytes: &[u8]) -> Result<Frames, ImageError> {
+    let count = match bytes.get(..HEADER_SIZE) {
+        Some([0, 0, 1, 0, low, high]) => u16::from_le_bytes([*low, *high]) as usize,
+        _ => return Err(ico_error("not an ICO file")),
+    };
+
+    let mut images = vec![];
+    for i in 0..count {
+        let start = HEADER_SIZE + i * ENTRY_SIZE;
+        let entry = bytes
+            .get(start..start + ENTRY_SIZE)
+            .ok_or_else(|| ico_error("truncated ICO directory"))?;
+        let size = u32::from_le_bytes(entry[8..12].try_into().unwrap()) as usize;
+        let offset = u32::from_le_bytes(entry[12..16].try_into().unwrap()) as usize;
+        let data = offset
+            .checked_add(size)
+            .and_then(|end| bytes.get(offset..end))
+            .ok_or_else(|| ico_error("truncated ICO image"))?;
+
+        // wrap the entry in an ICO of its own, so that `image` decodes it
+        // whether it's stored as PNG or BMP
+        let mut single = vec![0, 0, 1, 0, 1, 0];
+        single.extend_from_slice(&entry[..12]);
+        single.extend_from_slice(&((HEADER_SIZE + ENTRY_SIZE) as u32).to_le_bytes());
+        single.extend_from_slice(data);
+        images.push(image::load_from_memory_with_format(&single, ImageFormat::Ico)?.to_rgba8());
+    }
+    Ok(images)
+}
+
+/// Encode images as the entries of one ICO file, each stored as PNG so that
+/// alpha is kept. Images can be at most 256 pixels on each side.
+pub fn encode_ico<W: Write>(
+    writer: W,
+    images: &[ImageBuffer<Rgba<u8>, Vec<u8>>],
+) -> Result<(), ImageError> {
+    let frames = images
+        .iter()
+        .map(|img| IcoFrame::as_png(img.as_raw(), img.width(), img.height(), ColorType::Rgba8))
+        .collect::<Result<Vec<_>, _>>()?;
+    IcoEncoder::new(writer).encode_images(&frames)
+}
+
+fn ico_error(message: &str) -> ImageError {
+    ImageError::Decoding(DecodingError::new(ImageFormat::Ico.into(), message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_every_size_in_order() {
+        let images: Frames = [16, 32, 48]
+            .into_iter()
+            .map(|size| {
+                ImageBuffer::from_fn(size, size, |x, y| {
+                    Rgba([x as u8, y as u8, size as u8, (x * 5) as u8])
+                })
+            })
+            .collect();
+        let mut bytes = vec![];
+        encode_ico(&mut bytes, &images).unwrap();
+        assert_eq!(decode_ico(&bytes).unwrap(), images);
+    }
+
+    #[test]
+    fn rejects_truncated_icons() {
+        assert!(decode_ico(b"GIF89a").is_err());
+        let mut bytes = vec![];
+        encode_ico(&mut bytes, &[ImageBuffer::new(16, 16)]).unwrap();
+        assert!(decode_ico(&bytes[..HEADER_SIZE + ENTRY_SIZE - 1]).is_err());
+        assert!(decode_ico(&bytes[..bytes.len() - 1]).is_err());
+    }
+}
@@ -1,12 +1,18 @@
-use super::filter::*;
+use super::{blend::blend, filter::*};
 use base64::{engine::general_purpose::STANDARD, Engine};
-use gloo::file::{
-    callbacks::{read_as_bytes, FileReader},
-    File,
+use gloo::{
+    file::{
+        callbacks::{read_as_bytes, FileReader},
+        File,
+    },
+    timers::callback::Timeout,
+};
+use image::{
+    imageops::{self, FilterType},
+    ImageBuffer, ImageError, Rgba,
 };
-use image::{ImageBuffer, Rgba};
 use js_sys::Math::random;
-use std::collections::HashMap;
+use std::{borrow::Cow, collections::HashMap, ops::Range};
 use wasm_bindgen::{prelude::*, Clamped};
 use web_sys::{
     CanvasRenderingContext2d, Event, FileList, HtmlCanvasElement, HtmlImageElement,
@@ -14,152 +20,571 @@ use web_sys::{
 };
 use yew::prelude::*;
 
-#[allow(unused)]
-macro_rules! log {
-    ( $( $t:tt )* ) => {
-        web_sys::console::log_1(&format!( $( $t )* ).into());
-    }
-}
+/// Rows filtered per band before yielding back to the browser.
+pub const BAND_ROWS: u32 = 16;
+/// Largest width or height filtered for live previews, so that editing the
+/// parameters stays responsive on big images.
+pub const PREVIEW_MAX_SIZE: u32 = 512;
 
 pub enum Msg {
     Filter,
+    Preview,
+    FilterBand(u64, u32),
     Files(Option<FileList>),
-    Loaded(String, String, Vec<u8>),
+    /// A file of a selection has been read: the selection, the file's index
+    /// in it, its name, MIME type and contents.
+    Loaded(u64, usize, String, String, Vec<u8>),
     Random,
     OnEdit(String, String),
+    SelectImage(usize),
+    EditPalette(PaletteEdit),
+    /// Show the original in place of the filtered image while held.
+    ShowOriginal(bool),
+    /// Show only where a palette color is used, or stop if it already is.
+    IsolateColor(usize),
+}
+
+/// A change to one palette entry from the color pickers.
+pub enum PaletteEdit {
+    /// Set an entry to a color input value, such as `#1b112c`.
+    Set(usize, String),
+    /// Insert a copy of an entry after it.
+    Duplicate(usize),
+    /// Remove an entry, unless it's the last one.
+    Remove(usize),
+}
+
+impl PaletteEdit {
+    /// Apply the edit, returning whether the palette changed.
+    pub fn apply(self, palette_hex: &mut Vec<String>) -> bool {
+        match self {
+            PaletteEdit::Set(index, value) => {
+                let hex = value.trim_start_matches('#').to_lowercase();
+                if index >= palette_hex.len() || hex_to_rgb8(&hex).is_err() {
+                    return false;
+                }
+                palette_hex[index] = hex;
+            }
+            PaletteEdit::Duplicate(index) => {
+                let Some(hex) = palette_hex.get(index).cloned() else {
+                    return false;
+                };
+                palette_hex.insert(index + 1, hex);
+            }
+            PaletteEdit::Remove(index) => {
+                // the filter needs at least one color
+                if index >= palette_hex.len() || palette_hex.len() == 1 {
+                    return false;
+                }
+                palette_hex.remove(index);
+            }
+        }
+        true
+    }
 }
 
 #[derive(Default)]
 pub struct App {
-    threshold_map: [[usize; 2]; 2],
-    color_dither: f32,
-    alpha_dither: f32,
-    palette_hex: Vec<String>,
+    parameters: Parameters,
+    scale: u32,
 
+    gallery: Gallery,
+    status: String,
+    epoch: Epoch,
+    source: Option<ImageBuffer<Rgba<u8>, Vec<u8>>>,
+    output: Option<ImageBuffer<Rgba<u8>, Vec<u8>>>,
+    filtered_size: Option<(u32, u32)>,
+    show_original: bool,
+    /// Palette index shown alone, with the other colors dimmed.
+    isolated: Option<usize>,
+    /// Full resolution of the image being filtered, also while previewing.
+    source_size: (u32, u32),
+    /// Pixels of the shown image, read from the canvas once per image.
+    sources: SourceCache,
     image_element: NodeRef,
     target_canvas: NodeRef,
-    readers: HashMap<String, FileReader>,
+    /// Reads in flight for the current selection, by index in it.
+    readers: HashMap<usize, FileReader>,
 }
 
-impl Component for App {
-    type Message = Msg;
-    type Properties = ();
+impl App {
+    fn target_context(&self) -> CanvasRenderingContext2d {
+        self.target_canvas
+            .cast::<HtmlCanvasElement>()
+            .unwrap()
+            .get_context("2d")
+            .unwrap()
+            .unwrap()
+            .dyn_into::<CanvasRenderingContext2d>()
+            .unwrap()
+    }
 
-    fn create(_ctx: &Context<Self>) -> Self {
+    /// Draw the cached source while the original is shown, and otherwise
+    /// the cached output blended with it, without filtering again.
+    fn draw(&self) {
+        let (Some(source), Some(output)) = (&self.source, &self.output) else {
+            return;
+        };
+        // the color as written by the filter, after the round trip through Oklab
+        let isolated = self
+            .isolated
+            .and_then(|index| self.parameters.palette_hex.get(index))
+            .and_then(|hex| hex_to_rgb8(&effective_palette(&[hex])[0]).ok());
+        let output = match isolated {
+            Some(rgb) => Cow::Owned(isolate(output, rgb)),
+            None => Cow::Borrowed(output),
+        };
+        let displayed = displayed(source, &output, self.parameters.blend, self.show_original);
+        put_rows(&self.target_context(), &displayed, 0..displayed.height());
+    }
+
+    /// Size the canvas on screen so the filtered pixels are upscaled,
+    /// leaving the canvas data at full resolution.
+    fn canvas_style(&self) -> String {
+        match self.filtered_size {
+            Some((width, height)) => {
+                let (width, height) = scaled_dimensions(width, height, self.scale);
+                format!("width: {}px; height: {}px;", width, height)
+            }
+            None => String::new(),
+        }
+    }
+
+    /// Show another image, whose pixels are read again on the next run.
+    fn show(&mut self, src: &str) {
+        let image_element = self.image_element.cast::<HtmlImageElement>().unwrap();
+        image_element.set_src(src);
+        self.sources.clear();
+    }
+}
+
+/// Images loaded from the last file selection, as data URLs in the order the
+/// files were selected, whatever order they finish loading in.
+#[derive(Default)]
+pub struct Gallery {
+    /// Each selected file's image, once loaded.
+    images: Vec<Option<String>>,
+    selected: Option<usize>,
+    /// Counter of file selections, so that loads from an earlier one are
+    /// dropped.
+    selection: u64,
+}
+
+impl Gallery {
+    /// Replace the gallery with `count` images still loading, returning the
+    /// selection to tag their loads with.
+    pub fn start(&mut self, count: usize) -> u64 {
+        self.images = vec![None; count];
+        self.selected = None;
+        self.selection += 1;
+        self.selection
+    }
+
+    /// Add the loaded image at `index` of the given selection, selecting it
+    /// if nothing is selected yet. Returns whether the image became the
+    /// selection, which it never does for loads of an earlier selection.
+    pub fn insert(&mut self, selection: u64, index: usize, src: String) -> bool {
+        if selection != self.selection || index >= self.images.len() {
+            return false;
+        }
+        self.images[index] = Some(src);
+        if self.selected.is_none() {
+            self.selected = Some(index);
+            return true;
+        }
+        false
+    }
+
+    /// Select a loaded image by index, returning its data URL.
+    pub fn select(&mut self, index: usize) -> Option<&str> {
+        let src = self.images.get(index)?.as_deref()?;
+        self.selected = Some(index);
+        Some(src)
+    }
+
+    /// Loaded images with their indices, in selection order.
+    pub fn loaded(&self) -> impl Iterator<Item = (usize, &str)> {
+        self.images
+            .iter()
+            .enumerate()
+            .filter_map(|(index, src)| Some((index, src.as_deref()?)))
+    }
+}
+
+/// Counter identifying the latest filter run, so that the bands of a
+/// superseded run can notice and stop instead of committing stale output.
+#[derive(Default)]
+pub struct Epoch(u64);
+
+impl Epoch {
+    /// Start a new run, superseding all earlier ones.
+    pub fn advance(&mut self) -> u64 {
+        self.0 += 1;
+        self.0
+    }
+
+    pub fn is_current(&self, epoch: u64) -> bool {
+        self.0 == epoch
+    }
+
+    /// Filter the band of run `epoch` starting at `row` into `output`, as
+    /// `filter_band`, or return `None` without touching `output` if a newer
+    /// run has started since.
+    pub fn filter_band(
+        &self,
+        epoch: u64,
+        source: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+        parameters: &Parameters,
+        row: u32,
+        output: &mut ImageBuffer<Rgba<u8>, Vec<u8>>,
+    ) -> Option<Result<Option<u32>, ImageError>> {
+        self.is_current(epoch)
+            .then(|| filter_band(source, parameters, row, output))
+    }
+}
+
+/// Dimensions to downscale an image to for previews, keeping the aspect
+/// ratio, or `None` if it already fits within `max_size`.
+pub fn preview_dimensions(width: u32, height: u32, max_size: u32) -> Option<(u32, u32)> {
+    let longest = width.max(height);
+    if longest <= max_size {
+        return None;
+    }
+    let scale = |side: u32| ((side as u64 * max_size as u64 / longest as u64) as u32).max(1);
+    Some((scale(width), scale(height)))
+}
+
+/// The full resolution pixels of an image and the copy downscaled for
+/// previews, which is only made once, on the first preview.
+#[derive(Default)]
+pub struct SourceCache {
+    full: Option<ImageBuffer<Rgba<u8>, Vec<u8>>>,
+    preview: Option<ImageBuffer<Rgba<u8>, Vec<u8>>>,
+}
+
+impl SourceCache {
+    /// Forget the pixels, such as when another image is shown.
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Full resolution of the image, if its pixels are kept.
+    pub fn dimensions(&self) -> Option<(u32, u32)> {
+        self.full.as_ref().map(|full| full.dimensions())
+    }
+
+    /// Keep the pixels of a new image, from canvas image data.
+    pub fn store(&mut self, width: u32, height: u32, bytes: Vec<u8>) -> Result<(), ImageError> {
+        self.full = Some(rgba_buffer(width, height, bytes)?);
+        self.preview = None;
+        Ok(())
+    }
+
+    /// Image to filter, downscaled for previews, which are still shown at
+    /// the full resolution.
+    pub fn get(&mut self, preview: bool) -> Option<&ImageBuffer<Rgba<u8>, Vec<u8>>> {
+        let full = self.full.as_ref()?;
+        let preview_dimensions = preview_dimensions(full.width(), full.height(), PREVIEW_MAX_SIZE);
+        match preview_dimensions {
+            Some((width, height)) if preview => Some(self.preview.get_or_insert_with(|| {
+                imageops::resize(full, width, height, FilterType::Triangle)
+            })),
+            _ => Some(full),
+        }
+    }
+}
+
+/// Display dimensions of an image upscaled by an integer factor.
+pub fn scaled_dimensions(width: u32, height: u32, scale: u32) -> (u32, u32) {
+    let scale = scale.max(1);
+    (width * scale, height * scale)
+}
+
+/// Filter parameters edited in the UI.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Parameters {
+    pub threshold_map: [[usize; 2]; 2],
+    pub color_dither: f32,
+    pub alpha_dither: f32,
+    pub hue_rotate: f32,
+    /// Mix of the filtered image with the original, see [`blend`].
+    pub blend: f32,
+    pub palette_hex: Vec<String>,
+}
+
+impl Default for Parameters {
+    fn default() -> Self {
         Self {
             threshold_map: THRESHOLD_MAP,
             color_dither: COLOR_DITHER,
             alpha_dither: ALPHA_DITHER,
+            hue_rotate: 0.0,
+            blend: 1.0,
             palette_hex: PALETTE_HEX.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+}
+
+/// Filter the band of [`BAND_ROWS`] rows from `row` into the same rows of
+/// `output`, returning the first row of the next band unless this was the
+/// last one.
+pub fn filter_band(
+    source: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    parameters: &Parameters,
+    row: u32,
+    output: &mut ImageBuffer<Rgba<u8>, Vec<u8>>,
+) -> Result<Option<u32>, ImageError> {
+    let palette_hex = parameters
+        .palette_hex
+        .iter()
+        .map(|s| s.as_str())
+        .collect::<Vec<_>>();
+    let options = Options {
+        threshold_map: parameters
+            .threshold_map
+            .iter()
+            .map(|row| row.to_vec())
+            .collect(),
+        color_dither: parameters.color_dither,
+        alpha_dither: parameters.alpha_dither,
+        hue_rotate: parameters.hue_rotate,
+        palette_hex: &palette_hex,
+        ..Default::default()
+    };
+    run_rows(source, &options, row..row + BAND_ROWS, output)?;
+    let next_row = row + BAND_ROWS;
+    Ok((next_row < source.height()).then_some(next_row))
+}
+
+/// What the canvas shows of a run: the source while the original is shown,
+/// and otherwise the output mixed with it by `amount`.
+pub fn displayed<'a>(
+    source: &'a ImageBuffer<Rgba<u8>, Vec<u8>>,
+    output: &'a ImageBuffer<Rgba<u8>, Vec<u8>>,
+    amount: f32,
+    show_original: bool,
+) -> Cow<'a, ImageBuffer<Rgba<u8>, Vec<u8>>> {
+    if show_original {
+        Cow::Borrowed(source)
+    } else if amount < 1.0 {
+        Cow::Owned(blend(source, output, amount).unwrap())
+    } else {
+        Cow::Borrowed(output)
+    }
+}
+
+/// The given rows of a buffer and their bytes, cut to the rows it has.
+fn row_bytes(buffer: &ImageBuffer<Rgba<u8>, Vec<u8>>, rows: Range<u32>) -> (Range<u32>, &[u8]) {
+    let rows = rows.start.min(buffer.height())..rows.end.min(buffer.height());
+    let row_bytes = buffer.width() as usize * 4;
+    let bytes = &buffer.as_raw()[rows.start as usize * row_bytes..rows.end as usize * row_bytes];
+    (rows, bytes)
+}
+
+/// Keep the pixels of the given palette color and dim all others to a dark
+/// gray of their luma, to show where the color is used. Pixels are matched
+/// by color, so palette entries of the same color are shown together.
+pub fn isolate(
+    output: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    rgb: [u8; 3],
+) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+    let mut isolated = output.clone();
+    for pixel in isolated.pixels_mut() {
+        let [r, g, b, a] = pixel.0;
+        if [r, g, b] != rgb {
+            let luma = (299 * r as u32 + 587 * g as u32 + 114 * b as u32) / 1000;
+            let dimmed = (luma / 3) as u8;
+            *pixel = Rgba([dimmed, dimmed, dimmed, a]);
+        }
+    }
+    isolated
+}
+
+/// Draw the given rows of a buffer onto the canvas at the same rows.
+fn put_rows(
+    context: &CanvasRenderingContext2d,
+    buffer: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    rows: Range<u32>,
+) {
+    let (rows, bytes) = row_bytes(buffer, rows);
+    if rows.is_empty() {
+        return;
+    }
+    let image_data = ImageData::new_with_u8_clamped_array_and_sh(
+        Clamped(bytes),
+        buffer.width(),
+        rows.end - rows.start,
+    )
+    .unwrap();
+    context
+        .put_image_data(&image_data, 0.0, rows.start as f64)
+        .unwrap();
+}
+
+impl Component for App {
+    type Message = Msg;
+    type Properties = ();
+
+    fn create(_ctx: &Context<Self>) -> Self {
+        Self {
+            scale: 1,
             ..Default::default()
         }
     }
 
     fn update(&mut self, ctx: &Context<Self>, msg: Self::Message) -> bool {
         match msg {
-            Msg::Loaded(file_name, file_type, data) => {
-                let image_element = self.image_element.cast::<HtmlImageElement>().unwrap();
-
-                image_element.set_src(&format!(
-                    "data:{};base64,{}",
-                    file_type,
-                    STANDARD.encode(&data)
-                ));
-                self.readers.remove(&file_name);
+            Msg::Loaded(selection, index, file_name, file_type, data) => {
+                let src = format!("data:{};base64,{}", file_type, STANDARD.encode(&data));
+                if self.gallery.insert(selection, index, src.clone()) {
+                    self.show(&src);
+                }
+                log::debug!("loaded {} ({}, {} bytes)", file_name, file_type, data.len());
+                if self.gallery.selection == selection {
+                    self.readers.remove(&index);
+                }
                 true
             }
             Msg::Files(files) => {
-                match files {
-                    Some(files) => {
-                        let files = js_sys::try_iter(&files)
-                            .unwrap()
-                            .unwrap()
-                            .map(|v| web_sys::File::from(v.unwrap()))
-                            .map(File::from)
-                            .collect::<Vec<_>>();
-
-                        if files.len() != 0 {
-                            let link = ctx.link().clone();
-                            let file = files[0].clone();
-                            self.readers.insert(
-                                file.name(),
-                                read_as_bytes(&file.clone(), move |res| {
-                                    link.send_message(Msg::Loaded(
-                                        file.name(),
-                                        file.raw_mime_type(),
-                                        res.expect("Failed to read file"),
-                                    ))
-                                }),
-                            );
-                        }
+                if let Some(files) = files {
+                    let files = js_sys::try_iter(&files)
+                        .unwrap()
+                        .unwrap()
+                        .map(|v| web_sys::File::from(v.unwrap()))
+                        .map(File::from)
+                        .collect::<Vec<_>>();
+
+                    // dropping the readers of the last selection cancels them
+                    self.readers.clear();
+                    let selection = self.gallery.start(files.len());
+                    for (index, file) in files.into_iter().enumerate() {
+                        let link = ctx.link().clone();
+                        self.readers.insert(
+                            index,
+                            read_as_bytes(&file.clone(), move |res| {
+                                link.send_message(Msg::Loaded(
+                                    selection,
+                                    index,
+                                    file.name(),
+                                    file.raw_mime_type(),
+                                    res.expect("Failed to read file"),
+                                ))
+                            }),
+                        );
                     }
-                    None => {}
                 }
                 true
             }
-            Msg::Filter => {
+            Msg::SelectImage(index) => match self.gallery.select(index) {
+                Some(src) => {
+                    let src = src.to_string();
+                    self.show(&src);
+                    true
+                }
+                None => false,
+            },
+            msg @ (Msg::Filter | Msg::Preview) => {
                 let image_element = self.image_element.cast::<HtmlImageElement>().unwrap();
+                // the canvas can't hand out pixels of an empty image, such as
+                // one that hasn't finished loading
+                if image_element.natural_width() == 0 || image_element.natural_height() == 0 {
+                    return false;
+                }
                 let target_canvas = self.target_canvas.cast::<HtmlCanvasElement>().unwrap();
-                let target_context = target_canvas
-                    .get_context("2d")
-                    .unwrap()
-                    .unwrap()
-                    .dyn_into::<CanvasRenderingContext2d>()
-                    .unwrap();
-
-                target_canvas.set_width(image_element.natural_width());
-                target_canvas.set_height(image_element.natural_height());
-                target_context
-                    .draw_image_with_html_image_element(&image_element, 0.0, 0.0)
-                    .unwrap();
-
-                let data = target_context
-                    .get_image_data(
-                        0.0,
-                        0.0,
-                        target_canvas.width() as f64,
-                        target_canvas.height() as f64,
-                    )
-                    .unwrap();
-                let raw_data = data.data().0;
-                let converted: ImageBuffer<Rgba<u8>, _> = ImageBuffer::from_raw(
-                    image_element.natural_width(),
-                    image_element.natural_height(),
-                    raw_data,
-                )
-                .unwrap();
-
-                // run filter
-                let buf = run_with_parameters(
-                    &converted,
-                    &self.threshold_map,
-                    self.color_dither,
-                    self.alpha_dither,
-                    self.palette_hex
-                        .iter()
-                        .map(|s| s.as_str())
-                        .collect::<Vec<_>>()
-                        .as_slice(),
-                )
-                .unwrap();
-                let clamped_buf: Clamped<&[u8]> = Clamped(buf.as_raw());
-                let image_data_temp = ImageData::new_with_u8_clamped_array_and_sh(
-                    clamped_buf,
-                    image_element.natural_width(),
-                    image_element.natural_height(),
-                )
-                .unwrap();
-                target_canvas.set_width(image_element.natural_width());
-                target_canvas.set_height(image_element.natural_height());
-                target_context
-                    .put_image_data(&image_data_temp, 0.0, 0.0)
-                    .unwrap();
+
+                // the pixels are only read from the canvas once per image
+                if self.sources.dimensions().is_none() {
+                    let target_context = self.target_context();
+                    target_canvas.set_width(image_element.natural_width());
+                    target_canvas.set_height(image_element.natural_height());
+                    target_context
+                        .draw_image_with_html_image_element(&image_element, 0.0, 0.0)
+                        .unwrap();
+
+                    let data = target_context
+                        .get_image_data(
+                            0.0,
+                            0.0,
+                            target_canvas.width() as f64,
+                            target_canvas.height() as f64,
+                        )
+                        .unwrap();
+                    if let Err(e) = self
+                        .sources
+                        .store(data.width(), data.height(), data.data().0)
+                    {
+                        self.status = format!("Can't read the image: {}", e);
+                        return true;
+                    }
+                }
+                // previews run on a downscaled copy, shown at full size
+                let preview = matches!(msg, Msg::Preview);
+                let Some(converted) = self.sources.get(preview).cloned() else {
+                    return false;
+                };
+                self.source_size = self.sources.dimensions().unwrap();
+
+                // bands are drawn onto a cleared canvas as they complete
+                target_canvas.set_width(converted.width());
+                target_canvas.set_height(converted.height());
+                self.filtered_size = Some(self.source_size);
+
+                let epoch = self.epoch.advance();
+                self.output = Some(ImageBuffer::new(converted.width(), converted.height()));
+                self.source = Some(converted);
+                self.status = "Filtering…".to_string();
+                ctx.link().send_message(Msg::FilterBand(epoch, 0));
+                true
+            }
+            Msg::FilterBand(epoch, row) => {
+                let (Some(source), Some(output)) = (&self.source, &mut self.output) else {
+                    return false;
+                };
+
+                // run filter on the next band, unless the run was superseded
+                let band = self
+                    .epoch
+                    .filter_band(epoch, source, &self.parameters, row, output);
+                let next_row = match band {
+                    None => return false,
+                    Some(Ok(next_row)) => next_row,
+                    Some(Err(e)) => {
+                        // stop the run rather than show a partial result
+                        self.output = None;
+                        self.status = format!("Can't filter the image: {}", e);
+                        return true;
+                    }
+                };
+                let dimensions = output.dimensions();
+
+                if let Some(next_row) = next_row {
+                    // isolation is applied to the whole frame at the end
+                    if !self.show_original && self.isolated.is_none() {
+                        let output = self.output.as_ref().unwrap();
+                        put_rows(&self.target_context(), output, row..next_row);
+                    }
+                    let link = ctx.link().clone();
+                    Timeout::new(0, move || {
+                        link.send_message(Msg::FilterBand(epoch, next_row))
+                    })
+                    .forget();
+                    return false;
+                }
+
+                // the final frame is drawn whole, since blending changes
+                // the bands drawn so far
+                log::debug!("filtered {}x{}", dimensions.0, dimensions.1);
+                self.draw();
+                self.status = if dimensions == self.source_size {
+                    "Filtering complete".to_string()
+                } else {
+                    "Preview complete, filter for full resolution".to_string()
+                };
                 true
             }
             Msg::Random => {
-                let image_element = self.image_element.cast::<HtmlImageElement>().unwrap();
                 let seed = (random() * 50.).floor() as usize;
-                image_element.set_src(&format!(
+                self.show(&format!(
                     "https://source.unsplash.com/random/100x100/?{}",
                     seed
                 ));
@@ -168,23 +593,74 @@ impl Component for App {
             Msg::OnEdit(id, value) => {
                 match id.as_str() {
                     "color_dither" => match value.parse() {
-                        Ok(s) => self.color_dither = s,
+                        Ok(s) => self.parameters.color_dither = s,
+                        Err(_) => return false,
+                    },
+                    "dither_strength" => match value.parse() {
+                        Ok(s) => self.parameters.color_dither = color_dither_from_strength(s),
                         Err(_) => return false,
                     },
                     "alpha_dither" => match value.parse() {
-                        Ok(s) => self.alpha_dither = s,
+                        Ok(s) => self.parameters.alpha_dither = s,
+                        Err(_) => return false,
+                    },
+                    "blend" => match value.parse() {
+                        Ok(s) => self.parameters.blend = s,
+                        Err(_) => return false,
+                    },
+                    "hue_rotate" => match value.parse() {
+                        Ok(s) => self.parameters.hue_rotate = s,
                         Err(_) => return false,
                     },
                     "threshold_map" => match serde_json::from_str(&value) {
-                        Ok(s) => self.threshold_map = s,
+                        Ok(s) => self.parameters.threshold_map = s,
                         Err(_) => return false,
                     },
                     "palette_hex" => match serde_json::from_str(&value) {
-                        Ok(s) => self.palette_hex = s,
+                        Ok(s) => self.parameters.palette_hex = s,
                         Err(_) => return false,
                     },
+                    "scale" => match value.parse() {
+                        Ok(s) if s >= 1 => {
+                            self.scale = s;
+                            return true;
+                        }
+                        _ => return false,
+                    },
                     _ => {}
                 }
+
+                // refresh the preview, superseding any run still in progress
+                if self.source.is_some() {
+                    ctx.link().send_message(Msg::Preview);
+                }
+                true
+            }
+            Msg::ShowOriginal(show_original) => {
+                if self.show_original == show_original {
+                    return false;
+                }
+                self.show_original = show_original;
+                self.draw();
+                false
+            }
+            Msg::IsolateColor(index) => {
+                self.isolated = match self.isolated {
+                    Some(isolated) if isolated == index => None,
+                    _ => Some(index),
+                };
+                self.draw();
+                true
+            }
+            Msg::EditPalette(edit) => {
+                if !edit.apply(&mut self.parameters.palette_hex) {
+                    return false;
+                }
+                // indices may have shifted
+                self.isolated = None;
+                if self.source.is_some() {
+                    ctx.link().send_message(Msg::Preview);
+                }
                 true
             }
         }
@@ -197,28 +673,72 @@ impl Component for App {
                 <section class="image-display">
                 <div class="origin">
                     <h3>{"Original Image"}</h3>
-                    <img id="img" width="224px" crossorigin="anonymous"
+                    <img id="img" width="224px" crossorigin="anonymous" alt="Original image"
                     ref={self.image_element.clone()} />
+                    <label for="img-input">{ "Choose Images" }</label>
                     <input
                     id="img-input"
                     type="file"
                     accept="image/png, image/jpeg"
+                    multiple=true
                     onchange={ctx.link().callback(|e: Event| {
                         let input: HtmlInputElement = e.target_unchecked_into();
                         Msg::Files(input.files())
                     })}
                     />
+                    <div class="gallery" role="group" aria-label="Loaded images">
+                        { for self.gallery.loaded().map(|(index, src)| html! {
+                            <button
+                                type="button"
+                                aria-label={format!("Select image {}", index + 1)}
+                                aria-pressed={(self.gallery.selected == Some(index)).to_string()}
+                                class={classes!((self.gallery.selected == Some(index)).then_some("selected"))}
+                                onclick={ctx.link().callback(move |_| Msg::SelectImage(index))}
+                                >
+                                <img src={src.to_string()} alt="" />
+                            </button>
+                        }) }
+                    </div>
                     <div>
-                        <button onclick={ctx.link().callback(|_| {
+                        <button type="button" aria-label="Load a random image"
+                        onclick={ctx.link().callback(|_| {
                             Msg::Random
                         }) }>{ "Random Image" }</button>
-                        <button onclick={ctx.link().callback(|_| Msg::Filter)}>{ "Filter" }</button>
+                        <button type="button" aria-label="Filter the image"
+                        onclick={ctx.link().callback(|_| Msg::Filter)}>{ "Filter" }</button>
                     </div>
                 </div>
 
                 <div class="filtered">
                     <h3>{"Filtered Canvas"}</h3>
-                    <canvas id="canvas" width="224" ref={self.target_canvas.clone()}></canvas>
+                    <canvas id="canvas" width="224" style={self.canvas_style()}
+                    role="img" aria-label="Filtered image"
+                    ref={self.target_canvas.clone()}></canvas>
+                    <p class="status" role="status" aria-live="polite">{ &self.status }</p>
+                    <button type="button" aria-label="Hold to show the original image"
+                        onpointerdown={ctx.link().callback(|_| Msg::ShowOriginal(true))}
+                        onpointerup={ctx.link().callback(|_| Msg::ShowOriginal(false))}
+                        onpointerleave={ctx.link().callback(|_| Msg::ShowOriginal(false))}
+                        onkeydown={ctx.link().batch_callback(|e: KeyboardEvent| {
+                            (e.key() == " ").then_some(Msg::ShowOriginal(true))
+                        })}
+                        onkeyup={ctx.link().batch_callback(|e: KeyboardEvent| {
+                            (e.key() == " ").then_some(Msg::ShowOriginal(false))
+                        })}
+                        onblur={ctx.link().callback(|_| Msg::ShowOriginal(false))}
+                        >{ "Hold for Original" }</button>
+                    <label for="scale">{ "Display Scale" }</label>
+                    <input
+                        type="number"
+                        min="1"
+                        step="1"
+                        id="scale"
+                        value={ format!("{}", &self.scale) }
+                        onchange={ctx.link().callback(|e: Event| {
+                            let input: HtmlInputElement = e.target_unchecked_into();
+                            Msg::OnEdit(input.id(), input.value())
+                        })}
+                        />
                 </div>
 
                 <div class="parameters">
@@ -227,21 +747,35 @@ impl Component for App {
                     <input
                         type="text"
                         id="threshold_map"
-                        value={ format!("{:?}", &self.threshold_map) }
+                        value={ format!("{:?}", &self.parameters.threshold_map) }
+                        onchange={ctx.link().callback(|e: Event| {
+                            let input: HtmlInputElement = e.target_unchecked_into();
+                            Msg::OnEdit(input.id(), input.value())
+                        })}
+                        />
+
+                    <label for="dither_strength">{ "Dither Strength" }</label>
+                    <input
+                        type="range"
+                        min="0"
+                        max="100"
+                        step="any"
+                        id="dither_strength"
+                        value={ format!("{}", strength_from_color_dither(self.parameters.color_dither)) }
                         onchange={ctx.link().callback(|e: Event| {
                             let input: HtmlInputElement = e.target_unchecked_into();
                             Msg::OnEdit(input.id(), input.value())
                         })}
                         />
 
-                    <label for="color_dither">{ "Color Dither" }</label>
+                    <label for="color_dither">{ "Color Dither Coefficient" }</label>
                     <input
                         type="range"
                         min="0"
                         max="1"
                         step="any"
                         id="color_dither"
-                        value={ format!("{}", &self.color_dither) }
+                        value={ format!("{}", &self.parameters.color_dither) }
                         onchange={ctx.link().callback(|e: Event| {
                             let input: HtmlInputElement = e.target_unchecked_into();
                             Msg::OnEdit(input.id(), input.value())
@@ -255,7 +789,35 @@ impl Component for App {
                         max="1"
                         step="any"
                         id="alpha_dither"
-                        value={ format!("{}", &self.alpha_dither) }
+                        value={ format!("{}", &self.parameters.alpha_dither) }
+                        onchange={ctx.link().callback(|e: Event| {
+                            let input: HtmlInputElement = e.target_unchecked_into();
+                            Msg::OnEdit(input.id(), input.value())
+                        })}
+                        />
+
+                    <label for="hue_rotate">{ "Hue Rotation" }</label>
+                    <input
+                        type="range"
+                        min="-180"
+                        max="180"
+                        step="any"
+                        id="hue_rotate"
+                        value={ format!("{}", &self.parameters.hue_rotate) }
+                        onchange={ctx.link().callback(|e: Event| {
+                            let input: HtmlInputElement = e.target_unchecked_into();
+                            Msg::OnEdit(input.id(), input.value())
+                        })}
+                        />
+
+                    <label for="blend">{ "Blend" }</label>
+                    <input
+                        type="range"
+                        min="0"
+                        max="1"
+                        step="any"
+                        id="blend"
+                        value={ format!("{}", &self.parameters.blend) }
                         onchange={ctx.link().callback(|e: Event| {
                             let input: HtmlInputElement = e.target_unchecked_into();
                             Msg::OnEdit(input.id(), input.value())
@@ -266,15 +828,291 @@ impl Component for App {
                     <textarea
                         type="text"
                         id="palette_hex"
-                        value={ format!("{:?}", &self.palette_hex) }
+                        value={ format!("{:?}", &self.parameters.palette_hex) }
                         onchange={ctx.link().callback(|e: Event| {
                             let input: HtmlInputElement = e.target_unchecked_into();
                             Msg::OnEdit(input.id(), input.value())
                         })}
                         />
+
+                    <div class="palette-editor" role="group" aria-label="Palette colors">
+                        { for self.parameters.palette_hex.iter().enumerate().map(|(index, hex)| html! {
+                            <div class="palette-entry">
+                                <input
+                                    type="color"
+                                    aria-label={format!("Palette color {}", index + 1)}
+                                    value={format!("#{}", hex)}
+                                    onchange={ctx.link().callback(move |e: Event| {
+                                        let input: HtmlInputElement = e.target_unchecked_into();
+                                        Msg::EditPalette(PaletteEdit::Set(index, input.value()))
+                                    })}
+                                    />
+                                <button type="button"
+                                    aria-label={format!("Duplicate palette color {}", index + 1)}
+                                    onclick={ctx.link().callback(move |_| {
+                                        Msg::EditPalette(PaletteEdit::Duplicate(index))
+                                    })}>{ "+" }</button>
+                                <button type="button"
+                                    aria-label={format!("Remove palette color {}", index + 1)}
+                                    disabled={self.parameters.palette_hex.len() == 1}
+                                    onclick={ctx.link().callback(move |_| {
+                                        Msg::EditPalette(PaletteEdit::Remove(index))
+                                    })}>{ "−" }</button>
+                                <button type="button"
+                                    aria-label={format!("Show only palette color {}", index + 1)}
+                                    aria-pressed={(self.isolated == Some(index)).to_string()}
+                                    onclick={ctx.link().callback(move |_| Msg::IsolateColor(index))}>
+                                    { "◐" }
+                                </button>
+                            </div>
+                        }) }
+                    </div>
                 </div>
                 </section>
             </>
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scales_display_dimensions() {
+        assert_eq!(scaled_dimensions(30, 20, 1), (30, 20));
+        assert_eq!(scaled_dimensions(30, 20, 4), (120, 80));
+        // a scale of 0 would hide the canvas
+        assert_eq!(scaled_dimensions(30, 20, 0), (30, 20));
+    }
+
+    #[test]
+    fn epoch_supersedes_earlier_runs() {
+        let mut epoch = Epoch::default();
+        let first = epoch.advance();
+        assert!(epoch.is_current(first));
+        let second = epoch.advance();
+        assert!(!epoch.is_current(first));
+        assert!(epoch.is_current(second));
+    }
+
+    #[test]
+    fn superseded_run_leaves_the_output_unchanged() {
+        let source = ImageBuffer::from_pixel(3, BAND_ROWS + 1, Rgba([10, 20, 30, 255]));
+        let parameters = Parameters::default();
+        let mut epoch = Epoch::default();
+        let stale = epoch.advance();
+        let current = epoch.advance();
+
+        // the newer run has filtered its first band
+        let mut output = ImageBuffer::new(3, BAND_ROWS + 1);
+        let next_row = epoch
+            .filter_band(current, &source, &parameters, 0, &mut output)
+            .unwrap()
+            .unwrap();
+        assert_eq!(next_row, Some(BAND_ROWS));
+        let shown = output.clone();
+
+        // a band of the older run arriving late commits nothing
+        let blank = Parameters {
+            palette_hex: vec!["ffffff".to_string()],
+            ..Default::default()
+        };
+        for row in [0, BAND_ROWS] {
+            assert!(epoch
+                .filter_band(stale, &source, &blank, row, &mut output)
+                .is_none());
+        }
+        assert_eq!(output, shown);
+    }
+
+    #[test]
+    fn gallery_keeps_selection_order() {
+        let mut gallery = Gallery::default();
+        let selection = gallery.start(3);
+        assert!(gallery.insert(selection, 2, "c".to_string()));
+        assert!(!gallery.insert(selection, 0, "a".to_string()));
+        assert!(!gallery.insert(selection, 1, "b".to_string()));
+        let loaded: Vec<_> = gallery.loaded().collect();
+        assert_eq!(loaded, [(0, "a"), (1, "b"), (2, "c")]);
+        assert_eq!(gallery.selected, Some(2));
+    }
+
+    #[test]
+    fn gallery_drops_loads_of_earlier_selections() {
+        let mut gallery = Gallery::default();
+        let stale = gallery.start(2);
+        let selection = gallery.start(1);
+        assert!(!gallery.insert(stale, 0, "old".to_string()));
+        assert!(!gallery.insert(stale, 1, "old".to_string()));
+        assert_eq!(gallery.loaded().count(), 0);
+        assert!(gallery.insert(selection, 0, "new".to_string()));
+        assert_eq!(gallery.loaded().collect::<Vec<_>>(), [(0, "new")]);
+    }
+
+    #[test]
+    fn gallery_only_selects_loaded_images() {
+        let mut gallery = Gallery::default();
+        let selection = gallery.start(2);
+        gallery.insert(selection, 1, "b".to_string());
+        assert_eq!(gallery.select(0), None);
+        assert_eq!(gallery.select(1), Some("b"));
+        assert_eq!(gallery.select(2), None);
+    }
+
+    #[test]
+    fn source_cache_downscales_previews_once() {
+        let mut sources = SourceCache::default();
+        assert!(sources.get(true).is_none());
+        let (width, height) = (PREVIEW_MAX_SIZE * 2, 4);
+        sources
+            .store(width, height, vec![128; (width * height * 4) as usize])
+            .unwrap();
+        assert_eq!(sources.dimensions(), Some((width, height)));
+
+        assert_eq!(sources.get(false).unwrap().dimensions(), (width, height));
+        let preview = sources.get(true).unwrap() as *const _;
+        assert_eq!(
+            sources.get(true).unwrap().dimensions(),
+            (PREVIEW_MAX_SIZE, 2)
+        );
+        // later previews reuse the same copy
+        assert_eq!(sources.get(true).unwrap() as *const _, preview);
+
+        sources.clear();
+        assert_eq!(sources.dimensions(), None);
+    }
+
+    #[test]
+    fn source_cache_keeps_small_images_for_previews() {
+        let mut sources = SourceCache::default();
+        sources.store(2, 2, vec![0; 16]).unwrap();
+        assert_eq!(sources.get(true).unwrap().dimensions(), (2, 2));
+        assert!(sources.store(2, 2, vec![0; 15]).is_err());
+    }
+
+    #[test]
+    fn previews_downscale_only_large_images() {
+        assert_eq!(preview_dimensions(512, 300, 512), None);
+        assert_eq!(preview_dimensions(2048, 1024, 512), Some((512, 256)));
+        assert_eq!(preview_dimensions(100, 4000, 512), Some((12, 512)));
+        // thin images keep at least a pixel
+        assert_eq!(preview_dimensions(10000, 1, 512), Some((512, 1)));
+    }
+
+    #[test]
+    fn bands_cover_the_rows_they_draw() {
+        let buffer = ImageBuffer::from_fn(3, 10, |x, y| Rgba([x as u8, y as u8, 0, 255]));
+        let (rows, bytes) = row_bytes(&buffer, 4..8);
+        assert_eq!(rows, 4..8);
+        assert_eq!(bytes.len(), 3 * 4 * 4);
+        assert_eq!(bytes[..4], [0, 4, 0, 255]);
+        assert_eq!(bytes[bytes.len() - 4..], [2, 7, 0, 255]);
+        // the last band stops at the bottom of the image
+        let (rows, bytes) = row_bytes(&buffer, 8..16);
+        assert_eq!(rows, 8..10);
+        assert_eq!(bytes, &buffer.as_raw()[3 * 4 * 8..]);
+        assert!(row_bytes(&buffer, 12..16).0.is_empty());
+    }
+
+    #[test]
+    fn palette_edits_set_duplicate_and_remove_entries() {
+        let mut palette_hex = vec!["000000".to_string(), "ffffff".to_string()];
+        assert!(PaletteEdit::Set(1, "#1B112C".to_string()).apply(&mut palette_hex));
+        assert_eq!(palette_hex, ["000000", "1b112c"]);
+        assert!(PaletteEdit::Duplicate(0).apply(&mut palette_hex));
+        assert_eq!(palette_hex, ["000000", "000000", "1b112c"]);
+        assert!(PaletteEdit::Remove(1).apply(&mut palette_hex));
+        assert!(PaletteEdit::Remove(0).apply(&mut palette_hex));
+        assert_eq!(palette_hex, ["1b112c"]);
+
+        // invalid edits leave the palette as it was
+        assert!(!PaletteEdit::Set(0, "#zzzzzz".to_string()).apply(&mut palette_hex));
+        assert!(!PaletteEdit::Set(1, "#000000".to_string()).apply(&mut palette_hex));
+        assert!(!PaletteEdit::Duplicate(1).apply(&mut palette_hex));
+        assert!(!PaletteEdit::Remove(0).apply(&mut palette_hex));
+        assert_eq!(palette_hex, ["1b112c"]);
+    }
+
+    #[test]
+    fn holding_shows_the_original_over_any_blend() {
+        let source = ImageBuffer::from_pixel(2, 2, Rgba([200, 100, 50, 255]));
+        let output = ImageBuffer::from_pixel(2, 2, Rgba([0, 0, 0, 255]));
+        for amount in [0.0, 0.5, 1.0] {
+            assert_eq!(*displayed(&source, &output, amount, true), source);
+        }
+        assert_eq!(*displayed(&source, &output, 1.0, false), output);
+        assert_eq!(*displayed(&source, &output, 0.0, false), source);
+        let mixed = displayed(&source, &output, 0.5, false);
+        assert!(mixed
+            .pixels()
+            .all(|pixel| pixel.0[0] > 0 && pixel.0[0] < 200));
+    }
+
+    #[test]
+    fn filter_band_reports_invalid_parameters() {
+        let source = ImageBuffer::from_pixel(4, 4, Rgba([10, 20, 30, 255]));
+        let mut output = ImageBuffer::new(4, 4);
+        let empty = Parameters {
+            palette_hex: vec![],
+            ..Default::default()
+        };
+        assert!(filter_band(&source, &empty, 0, &mut output).is_err());
+        let invalid_map = Parameters {
+            threshold_map: [[0, 9], [3, 1]],
+            ..Default::default()
+        };
+        assert!(filter_band(&source, &invalid_map, 0, &mut output).is_err());
+    }
+
+    #[test]
+    fn filter_band_steps_through_the_image() {
+        let source = ImageBuffer::from_pixel(3, BAND_ROWS + 1, Rgba([10, 20, 30, 255]));
+        let mut output = ImageBuffer::new(3, BAND_ROWS + 1);
+        let parameters = Parameters::default();
+        assert_eq!(
+            filter_band(&source, &parameters, 0, &mut output).unwrap(),
+            Some(BAND_ROWS)
+        );
+        assert_eq!(
+            filter_band(&source, &parameters, BAND_ROWS, &mut output).unwrap(),
+            None
+        );
+        let options = Options::default();
+        assert_eq!(output, run_with_options(&source, &options).unwrap());
+    }
+
+    #[test]
+    fn isolation_dims_every_other_color() {
+        let source = ImageBuffer::from_fn(8, 8, |x, y| {
+            Rgba([
+                x as u8 * 32,
+                y as u8 * 32,
+                96,
+                if x == 0 { 128 } else { 255 },
+            ])
+        });
+        let options = Options {
+            palette_hex: &PALETTE_HEX_2,
+            color_dither: 0.0,
+            ..Default::default()
+        };
+        let output = run_with_options(&source, &options).unwrap();
+        let rgb = |pixel: &Rgba<u8>| [pixel.0[0], pixel.0[1], pixel.0[2]];
+        let kept = rgb(output.get_pixel(4, 4));
+        let isolated = isolate(&output, kept);
+        let mut dimmed = 0;
+        for (before, after) in output.pixels().zip(isolated.pixels()) {
+            assert_eq!(before.0[3], after.0[3]);
+            if rgb(before) == kept {
+                assert_eq!(before, after);
+            } else {
+                let [r, g, b, _] = after.0;
+                assert!(r == g && g == b && r < 85, "{:?}", after);
+                dimmed += 1;
+            }
+        }
+        // the result used several colors
+        assert!(dimmed > 0 && dimmed < 64);
+    }
+}
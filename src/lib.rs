@@ -1,2 +1,12 @@
+pub mod analysis;
+pub mod approx;
+pub mod blend;
+pub mod diff;
+pub mod export;
+pub mod extract;
 pub mod filter;
+pub mod ico;
 pub mod layout;
+pub mod preprocess;
+pub mod sheet;
+pub mod style;
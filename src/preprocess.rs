@@ -0,0 +1,390 @@
+use crate::filter::palette_as_oklab;
+use image::{
+    error::{ParameterError, ParameterErrorKind},
+    imageops, ImageBuffer, ImageError, Luma, Rgba,
+};
+use palette::{Clamp, IntoColor, LinSrgb, Oklab, Srgb};
+
+/// Operator used to bring linear HDR values into [0, 1].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ToneMap {
+    /// `c / (1 + c)`, which never clips.
+    #[default]
+    Reinhard,
+    /// Narkowicz's fit of the ACES filmic curve, with more contrast.
+    Aces,
+}
+
+impl ToneMap {
+    fn apply(self, c: f32) -> f32 {
+        let c = c.max(0.0);
+        match self {
+            ToneMap::Reinhard => c / (1.0 + c),
+            ToneMap::Aces => {
+                ((c * (2.51 * c + 0.03)) / (c * (2.43 * c + 0.59) + 0.14)).clamp(0.0, 1.0)
+            }
+        }
+    }
+}
+
+/// Tone-map a linear float image, such as a decoded EXR, into 8-bit sRGB.
+///
+/// Alpha is optional in the source (it decodes as 1.0 when missing) and is
+/// clamped rather than tone-mapped.
+pub fn tone_map(
+    img: &ImageBuffer<Rgba<f32>, Vec<f32>>,
+    operator: ToneMap,
+) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+    ImageBuffer::from_fn(img.width(), img.height(), |x, y| {
+        let [r, g, b, a] = img.get_pixel(x, y).0;
+        let linear = LinSrgb::new(operator.apply(r), operator.apply(g), operator.apply(b));
+        let encoded: Srgb<u8> = Srgb::from_linear(linear);
+        Rgba([
+            encoded.red,
+            encoded.green,
+            encoded.blue,
+            (a.clamp(0.0, 1.0) * 255.0).round() as u8,
+        ])
+    })
+}
+
+/// Replace each `block_size`×`block_size` block with its average color.
+///
+/// Colors are averaged in linear light and weighted by alpha, so that
+/// transparent pixels don't tint their block.
+pub fn block_average(
+    img: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    block_size: u32,
+) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+    let block_size = block_size.max(1);
+    let mut output_buffer = img.clone();
+
+    for block_y in (0..img.height()).step_by(block_size as usize) {
+        for block_x in (0..img.width()).step_by(block_size as usize) {
+            let xs = block_x..(block_x + block_size).min(img.width());
+            let ys = block_y..(block_y + block_size).min(img.height());
+
+            let mut color_sum = LinSrgb::new(0.0, 0.0, 0.0);
+            let mut alpha_sum = 0.0;
+            let mut count = 0.0;
+            for y in ys.clone() {
+                for x in xs.clone() {
+                    let [r, g, b, a] = img.get_pixel(x, y).0;
+                    let alpha = a as f32 / 255.0;
+                    let linear: LinSrgb = Srgb::new(r, g, b).into_format::<f32>().into_linear();
+                    color_sum += linear * alpha;
+                    alpha_sum += alpha;
+                    count += 1.0;
+                }
+            }
+
+            let average: Srgb<u8> = if alpha_sum > 0.0 {
+                Srgb::from_linear(color_sum / alpha_sum)
+            } else {
+                Srgb::new(0, 0, 0)
+            };
+            let average_pixel = Rgba([
+                average.red,
+                average.green,
+                average.blue,
+                (alpha_sum / count * 255.0).round() as u8,
+            ]);
+            for y in ys.clone() {
+                for x in xs.clone() {
+                    output_buffer.put_pixel(x, y, average_pixel);
+                }
+            }
+        }
+    }
+
+    output_buffer
+}
+
+/// Average the Oklab chroma (`a` and `b`) of each 2×2 block while keeping
+/// the lightness of every pixel, like the chroma subsampling of video
+/// codecs. Chroma is weighted by alpha, so transparent pixels don't tint
+/// their block, and colors that leave sRGB are clamped.
+pub fn subsample_chroma(img: &ImageBuffer<Rgba<u8>, Vec<u8>>) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+    let oklab = |x, y| {
+        let [r, g, b, a] = img.get_pixel(x, y).0;
+        let color: Oklab = Srgb::new(r, g, b).into_format::<f32>().into_color();
+        (color, a as f32 / 255.0)
+    };
+    let mut output_buffer = img.clone();
+
+    for block_y in (0..img.height()).step_by(2) {
+        for block_x in (0..img.width()).step_by(2) {
+            let xs = block_x..(block_x + 2).min(img.width());
+            let ys = block_y..(block_y + 2).min(img.height());
+
+            let (mut a_sum, mut b_sum, mut alpha_sum) = (0.0, 0.0, 0.0);
+            for y in ys.clone() {
+                for x in xs.clone() {
+                    let (color, alpha) = oklab(x, y);
+                    a_sum += color.a * alpha;
+                    b_sum += color.b * alpha;
+                    alpha_sum += alpha;
+                }
+            }
+            if alpha_sum == 0.0 {
+                continue;
+            }
+
+            for y in ys.clone() {
+                for x in xs.clone() {
+                    let (color, _) = oklab(x, y);
+                    let subsampled = Oklab::new(color.l, a_sum / alpha_sum, b_sum / alpha_sum);
+                    let rgb: Srgb = subsampled.into_color();
+                    let rgb: Srgb<u8> = rgb.clamp().into_format();
+                    let a = img.get_pixel(x, y).0[3];
+                    output_buffer.put_pixel(x, y, Rgba([rgb.red, rgb.green, rgb.blue, a]));
+                }
+            }
+        }
+    }
+
+    output_buffer
+}
+
+/// Crop the largest centered region with the aspect ratio `ratio_width` to
+/// `ratio_height`, so the image can be fit to a fixed format without
+/// stretching it.
+pub fn crop_to_aspect(
+    img: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    ratio_width: u32,
+    ratio_height: u32,
+) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+    let (width, height) = img.dimensions();
+    let (ratio_width, ratio_height) = (ratio_width.max(1) as u64, ratio_height.max(1) as u64);
+    let (crop_width, crop_height) = if width as u64 * ratio_height > height as u64 * ratio_width {
+        // too wide, so keep the full height
+        let crop_width = (height as u64 * ratio_width + ratio_height / 2) / ratio_height;
+        (crop_width as u32, height)
+    } else {
+        let crop_height = (width as u64 * ratio_height + ratio_width / 2) / ratio_width;
+        (width, crop_height as u32)
+    };
+    imageops::crop_imm(
+        img,
+        (width - crop_width) / 2,
+        (height - crop_height) / 2,
+        crop_width,
+        crop_height,
+    )
+    .to_image()
+}
+
+/// Replace the alpha of every pixel with the value of a grayscale mask of the
+/// same size, for art that stores color and alpha in separate files.
+pub fn apply_alpha_mask(
+    img: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    mask: &ImageBuffer<Luma<u8>, Vec<u8>>,
+) -> Result<ImageBuffer<Rgba<u8>, Vec<u8>>, ImageError> {
+    if img.dimensions() != mask.dimensions() {
+        return Err(ImageError::Parameter(ParameterError::from_kind(
+            ParameterErrorKind::DimensionMismatch,
+        )));
+    }
+
+    let mut masked = img.clone();
+    for (pixel, alpha) in masked.pixels_mut().zip(mask.pixels()) {
+        pixel.0[3] = alpha.0[0];
+    }
+    Ok(masked)
+}
+
+/// Stretch the Oklab lightness of the non-transparent pixels to span the
+/// lightness range of the palette, so that a dark or low-contrast palette
+/// gets all of its tones used instead of crushing highlights.
+///
+/// Images of a single lightness are left as they are.
+pub fn stretch_lightness(
+    img: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    palette_hex: &[&str],
+) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+    let to_oklab =
+        |[r, g, b, _]: [u8; 4]| -> Oklab { Srgb::new(r, g, b).into_format().into_color() };
+    let image_range = lightness_range(
+        img.pixels()
+            .filter(|pixel| pixel.0[3] != 0)
+            .map(|pixel| to_oklab(pixel.0)),
+    );
+    let palette_range = lightness_range(palette_as_oklab(palette_hex).into_iter());
+    let (Some((image_min, image_max)), Some((palette_min, palette_max))) =
+        (image_range, palette_range)
+    else {
+        return img.clone();
+    };
+    if image_max <= image_min {
+        return img.clone();
+    }
+
+    let scale = (palette_max - palette_min) / (image_max - image_min);
+    ImageBuffer::from_fn(img.width(), img.height(), |x, y| {
+        let pixel = img.get_pixel(x, y).0;
+        let mut color = to_oklab(pixel);
+        color.l = palette_min + (color.l - image_min) * scale;
+        let rgb: Srgb = color.into_color();
+        let rgb: Srgb<u8> = rgb.clamp().into_format();
+        Rgba([rgb.red, rgb.green, rgb.blue, pixel[3]])
+    })
+}
+
+/// Smallest and largest lightness of the colors, if there are any.
+fn lightness_range(colors: impl Iterator<Item = Oklab>) -> Option<(f32, f32)> {
+    colors.fold(None, |range, color| match range {
+        None => Some((color.l, color.l)),
+        Some((min, max)) => Some((min.min(color.l), max.max(color.l))),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn block_average_averages_in_linear_light() {
+        // black and white columns average to half the light, not to 128
+        let img = ImageBuffer::from_fn(4, 2, |x, _| {
+            let c = if x % 2 == 0 { 0 } else { 255 };
+            Rgba([c, c, c, 255])
+        });
+        let averaged = block_average(&img, 2);
+        for pixel in averaged.pixels() {
+            assert_eq!(pixel.0, [188, 188, 188, 255]);
+        }
+    }
+
+    #[test]
+    fn block_average_ignores_transparent_colors() {
+        let img = ImageBuffer::from_fn(2, 1, |x, _| {
+            if x == 0 {
+                Rgba([255, 0, 0, 255])
+            } else {
+                Rgba([0, 0, 255, 0])
+            }
+        });
+        let averaged = block_average(&img, 2);
+        assert_eq!(averaged.get_pixel(1, 0).0, [255, 0, 0, 128]);
+    }
+
+    #[test]
+    fn block_average_keeps_partial_blocks_apart() {
+        let img = ImageBuffer::from_fn(3, 1, |x, _| Rgba([x as u8 * 100, 0, 0, 255]));
+        let averaged = block_average(&img, 2);
+        assert_eq!(averaged.get_pixel(0, 0), averaged.get_pixel(1, 0));
+        assert_eq!(averaged.get_pixel(2, 0).0, [200, 0, 0, 255]);
+    }
+
+    #[test]
+    fn tone_map_brings_hdr_into_range() {
+        let img = ImageBuffer::from_fn(3, 1, |x, _| match x {
+            0 => Rgba([1.0, 0.0, -2.0, 1.5]),
+            1 => Rgba([100.0, 100.0, 100.0, 0.5]),
+            _ => Rgba([0.0, 0.0, 0.0, -1.0]),
+        });
+        let reinhard = tone_map(&img, ToneMap::Reinhard);
+        // 1.0 maps to half of the linear range, and negatives to black
+        assert_eq!(reinhard.get_pixel(0, 0).0, [188, 0, 0, 255]);
+        assert_eq!(reinhard.get_pixel(1, 0).0[3], 128);
+        assert_eq!(reinhard.get_pixel(2, 0).0, [0, 0, 0, 0]);
+        // Reinhard never clips, while ACES saturates
+        assert!(reinhard.get_pixel(1, 0).0[0] < 255);
+        assert_eq!(tone_map(&img, ToneMap::Aces).get_pixel(1, 0).0[0], 255);
+    }
+
+    #[test]
+    fn crop_to_aspect_keeps_a_centered_square() {
+        let img = ImageBuffer::from_fn(16, 9, |x, y| Rgba([x as u8, y as u8, 0, 255]));
+        let cropped = crop_to_aspect(&img, 1, 1);
+        assert_eq!(cropped.dimensions(), (9, 9));
+        // 7 columns are cut, 3 on the left and 4 on the right
+        assert_eq!(cropped.get_pixel(0, 0).0, [3, 0, 0, 255]);
+        assert_eq!(cropped.get_pixel(8, 8).0, [11, 8, 0, 255]);
+    }
+
+    #[test]
+    fn crop_to_aspect_cuts_tall_images_vertically() {
+        let img = ImageBuffer::from_fn(9, 16, |x, y| Rgba([x as u8, y as u8, 0, 255]));
+        let cropped = crop_to_aspect(&img, 16, 9);
+        assert_eq!(cropped.dimensions(), (9, 5));
+        assert_eq!(cropped.get_pixel(0, 0).0, [0, 5, 0, 255]);
+        // an image of the ratio already is left as is
+        assert_eq!(crop_to_aspect(&img, 9, 16), img);
+    }
+
+    #[test]
+    fn stretched_lightness_uses_more_of_the_palette() {
+        let palette_hex = ["000000", "555555", "aaaaaa", "ffffff"];
+        // a low-contrast ramp of grays
+        let img = ImageBuffer::from_fn(16, 1, |x, _| {
+            let c = 100 + x as u8 * 3;
+            Rgba([c, c, c, 255])
+        });
+        let levels = |img: &ImageBuffer<Rgba<u8>, Vec<u8>>| {
+            let mut colors: Vec<_> = crate::filter::remap(img, &palette_hex)
+                .unwrap()
+                .pixels()
+                .map(|pixel| pixel.0)
+                .collect();
+            colors.sort();
+            colors.dedup();
+            colors.len()
+        };
+        let stretched = stretch_lightness(&img, &palette_hex);
+        assert!(levels(&stretched) > levels(&img));
+        assert_eq!(levels(&stretched), 4);
+    }
+
+    #[test]
+    fn stretch_lightness_leaves_flat_images() {
+        let img = ImageBuffer::from_pixel(2, 2, Rgba([90, 40, 10, 255]));
+        assert_eq!(stretch_lightness(&img, &["000000", "ffffff"]), img);
+    }
+
+    #[test]
+    fn alpha_mask_replaces_only_alpha() {
+        let img = ImageBuffer::from_fn(2, 2, |x, y| Rgba([x as u8 * 50, y as u8 * 50, 7, 200]));
+        let mask = ImageBuffer::from_fn(2, 2, |x, y| Luma([(x + 2 * y) as u8 * 80]));
+        let masked = apply_alpha_mask(&img, &mask).unwrap();
+        for (x, y, pixel) in masked.enumerate_pixels() {
+            let [r, g, b, _] = img.get_pixel(x, y).0;
+            assert_eq!(pixel.0, [r, g, b, mask.get_pixel(x, y).0[0]]);
+        }
+        assert!(apply_alpha_mask(&img, &ImageBuffer::new(2, 3)).is_err());
+    }
+
+    #[test]
+    fn subsample_chroma_shares_chroma_and_keeps_lightness() {
+        let oklab = |pixel: &Rgba<u8>| -> Oklab {
+            let [r, g, b, _] = pixel.0;
+            Srgb::new(r, g, b).into_format::<f32>().into_color()
+        };
+        // a red and a gray in each block, then a lone odd column
+        let img = ImageBuffer::from_fn(3, 2, |x, y| match (x, y) {
+            (0, 0) => Rgba([200, 60, 60, 255]),
+            (2, _) => Rgba([60, 60, 200, 255]),
+            (_, 1) => Rgba([0, 0, 0, 0]),
+            _ => Rgba([120, 120, 120, 255]),
+        });
+        let subsampled = subsample_chroma(&img);
+        for (x, y, pixel) in subsampled.enumerate_pixels() {
+            let before = img.get_pixel(x, y);
+            assert_eq!(pixel.0[3], before.0[3]);
+            if before.0[3] > 0 {
+                assert!((oklab(pixel).l - oklab(before).l).abs() < 0.01);
+            }
+        }
+        // the transparent pixels don't tint the block, so the red and gray
+        // split their chroma
+        let (red, gray) = (
+            oklab(subsampled.get_pixel(0, 0)),
+            oklab(subsampled.get_pixel(1, 0)),
+        );
+        let original_red = oklab(img.get_pixel(0, 0));
+        assert!((red.a - gray.a).abs() < 0.01 && (red.b - gray.b).abs() < 0.01);
+        assert!((gray.a - original_red.a / 2.0).abs() < 0.01);
+        // a block of one color keeps it
+        assert_eq!(subsampled.get_pixel(2, 0), img.get_pixel(2, 0));
+    }
+}
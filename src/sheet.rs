@@ -0,0 +1,67 @@
+use image::{imageops, ImageBuffer, Rgba};
+
+/// Pack frames row by row into a grid sprite sheet with `columns` cells per
+/// row and `padding` transparent pixels between cells.
+///
+/// Cells are as large as the largest frame, and each frame sits in the
+/// top-left corner of its cell. Everything outside the frames is transparent.
+pub fn pack_sheet(
+    frames: &[ImageBuffer<Rgba<u8>, Vec<u8>>],
+    columns: u32,
+    padding: u32,
+) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+    let columns = columns.max(1);
+    let rows = (frames.len() as u32).div_ceil(columns);
+    let cell_width = frames.iter().map(|frame| frame.width()).max().unwrap_or(0);
+    let cell_height = frames.iter().map(|frame| frame.height()).max().unwrap_or(0);
+
+    let mut sheet = ImageBuffer::new(
+        columns * cell_width + (columns - 1) * padding,
+        rows * cell_height + rows.saturating_sub(1) * padding,
+    );
+    for (i, frame) in frames.iter().enumerate() {
+        let (column, row) = (i as u32 % columns, i as u32 / columns);
+        imageops::replace(
+            &mut sheet,
+            frame,
+            (column * (cell_width + padding)) as i64,
+            (row * (cell_height + padding)) as i64,
+        );
+    }
+    sheet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packs_frames_into_a_grid() {
+        let frames: Vec<_> = (0..4)
+            .map(|i| ImageBuffer::from_pixel(3, 2, Rgba([i * 50, 0, 0, 255])))
+            .collect();
+        let sheet = pack_sheet(&frames, 2, 1);
+        assert_eq!(sheet.dimensions(), (7, 5));
+        // frames go row by row, with transparent padding between them
+        assert_eq!(sheet.get_pixel(0, 0).0, [0, 0, 0, 255]);
+        assert_eq!(sheet.get_pixel(4, 0).0, [50, 0, 0, 255]);
+        assert_eq!(sheet.get_pixel(0, 3).0, [100, 0, 0, 255]);
+        assert_eq!(sheet.get_pixel(6, 4).0, [150, 0, 0, 255]);
+        assert_eq!(sheet.get_pixel(3, 0).0[3], 0);
+        assert_eq!(sheet.get_pixel(0, 2).0[3], 0);
+    }
+
+    #[test]
+    fn leaves_cells_of_smaller_and_missing_frames_transparent() {
+        let frames = [
+            ImageBuffer::from_pixel(2, 2, Rgba([255, 255, 255, 255])),
+            ImageBuffer::from_pixel(1, 1, Rgba([255, 255, 255, 255])),
+            ImageBuffer::from_pixel(2, 2, Rgba([255, 255, 255, 255])),
+        ];
+        let sheet = pack_sheet(&frames, 2, 0);
+        assert_eq!(sheet.dimensions(), (4, 4));
+        assert_eq!(sheet.get_pixel(2, 0).0[3], 255);
+        assert_eq!(sheet.get_pixel(3, 1).0[3], 0);
+        assert_eq!(sheet.get_pixel(3, 3).0[3], 0);
+    }
+}
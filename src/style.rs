@@ -0,0 +1,112 @@
+use crate::filter::{bayer_matrix, DitherMode, Options, ALPHA_DITHER, COLOR_DITHER, PALETTE_HEX};
+
+/// A complete look under a name: palette, threshold map, dither strengths
+/// and mode.
+#[derive(Clone, Copy, Debug)]
+pub struct Style {
+    pub name: &'static str,
+    pub palette_hex: &'static [&'static str],
+    /// Order of the Bayer threshold map, see [`bayer_matrix`].
+    pub bayer: u32,
+    pub color_dither: f32,
+    pub alpha_dither: f32,
+    pub dither_mode: DitherMode,
+}
+
+pub const STYLES: [Style; 4] = [
+    Style {
+        name: "default",
+        palette_hex: &PALETTE_HEX,
+        bayer: 1,
+        color_dither: COLOR_DITHER,
+        alpha_dither: ALPHA_DITHER,
+        dither_mode: DitherMode::CandidateSort,
+    },
+    Style {
+        name: "gameboy",
+        palette_hex: &["0f380f", "306230", "8bac0f", "9bbc0f"],
+        bayer: 2,
+        color_dither: 0.15,
+        alpha_dither: ALPHA_DITHER,
+        dither_mode: DitherMode::Ordered,
+    },
+    Style {
+        name: "c64",
+        palette_hex: &[
+            "000000", "ffffff", "880000", "aaffee", "cc44cc", "00cc55", "0000aa", "eeee77",
+            "dd8855", "664400", "ff7777", "333333", "777777", "aaff66", "0088ff", "bbbbbb",
+        ],
+        bayer: 1,
+        color_dither: 0.06,
+        alpha_dither: ALPHA_DITHER,
+        dither_mode: DitherMode::CandidateSort,
+    },
+    Style {
+        name: "pico8",
+        palette_hex: &[
+            "000000", "1d2b53", "7e2553", "008751", "ab5236", "5f574f", "c2c3c7", "fff1e8",
+            "ff004d", "ffa300", "ffec27", "00e436", "29adff", "83769c", "ff77a8", "ffccaa",
+        ],
+        bayer: 2,
+        color_dither: 0.05,
+        alpha_dither: ALPHA_DITHER,
+        dither_mode: DitherMode::CandidateSort,
+    },
+];
+
+impl Style {
+    /// The built-in style with this name.
+    pub fn find(name: &str) -> Option<&'static Style> {
+        STYLES.iter().find(|style| style.name == name)
+    }
+
+    /// Default options with everything the style bundles applied.
+    pub fn options(&self) -> Options<'static> {
+        Options {
+            threshold_map: bayer_matrix(self.bayer),
+            color_dither: self.color_dither,
+            alpha_dither: self.alpha_dither,
+            palette_hex: self.palette_hex,
+            dither_mode: self.dither_mode,
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::run_with_options;
+    use image::{ImageBuffer, Rgba};
+
+    #[test]
+    fn finds_styles_by_name() {
+        let gameboy = Style::find("gameboy").unwrap();
+        assert_eq!(
+            gameboy.palette_hex,
+            ["0f380f", "306230", "8bac0f", "9bbc0f"]
+        );
+        assert_eq!(gameboy.options().threshold_map.len(), 4);
+        assert!(Style::find("nes").is_none());
+    }
+
+    #[test]
+    fn styles_only_write_their_own_colors() {
+        let img = ImageBuffer::from_fn(16, 16, |x, y| {
+            Rgba([(x * 16) as u8, (y * 16) as u8, 128, 255])
+        });
+        for style in &STYLES {
+            let output = run_with_options(&img, &style.options()).unwrap();
+            for pixel in output.pixels() {
+                let [r, g, b, _] = pixel.0;
+                let hex = format!("{:02x}{:02x}{:02x}", r, g, b);
+                assert!(
+                    style.palette_hex.contains(&hex.as_str()),
+                    "{} {}",
+                    style.name,
+                    hex
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,109 @@
+//! Tests of the web filter core on wasm32, run under node with
+//! `cargo test --target wasm32-unknown-unknown --test web`, or in a browser
+//! with `wasm-pack test --headless --firefox`.
+#![cfg(target_arch = "wasm32")]
+
+use image::{ImageBuffer, Rgba};
+use pixel_filter::{filter::*, layout::*};
+use wasm_bindgen_test::*;
+
+/// RGBA bytes as read from a canvas: a gradient with a transparent column.
+fn canvas_bytes(width: u32, height: u32) -> Vec<u8> {
+    (0..height)
+        .flat_map(|y| {
+            (0..width).flat_map(move |x| {
+                let alpha = if x == 0 { 0 } else { 255 };
+                [(x * 255 / width) as u8, (y * 255 / height) as u8, 96, alpha]
+            })
+        })
+        .collect()
+}
+
+#[wasm_bindgen_test]
+fn bands_filter_like_a_single_run() {
+    let (width, height) = (7, BAND_ROWS * 2 + 3);
+    let mut sources = SourceCache::default();
+    sources
+        .store(width, height, canvas_bytes(width, height))
+        .unwrap();
+    let source = sources.get(false).unwrap().clone();
+
+    let parameters = Parameters {
+        color_dither: 0.5,
+        ..Default::default()
+    };
+    let mut output = ImageBuffer::new(width, height);
+    let mut row = Some(0);
+    while let Some(start) = row {
+        row = filter_band(&source, &parameters, start, &mut output).unwrap();
+    }
+
+    let options = Options {
+        color_dither: 0.5,
+        ..Default::default()
+    };
+    assert_eq!(output, run_with_options(&source, &options).unwrap());
+    assert_eq!(output.get_pixel(0, 0).0[3], 0);
+}
+
+#[wasm_bindgen_test]
+fn previews_are_filtered_from_the_downscaled_copy() {
+    let (width, height) = (PREVIEW_MAX_SIZE * 2, 4);
+    let mut sources = SourceCache::default();
+    sources
+        .store(width, height, canvas_bytes(width, height))
+        .unwrap();
+    let preview = sources.get(true).unwrap().clone();
+    assert_eq!(preview.dimensions(), (PREVIEW_MAX_SIZE, 2));
+
+    let mut output = ImageBuffer::new(PREVIEW_MAX_SIZE, 2);
+    let next = filter_band(&preview, &Parameters::default(), 0, &mut output).unwrap();
+    assert_eq!(next, None);
+    let displayed = displayed(&preview, &output, 1.0, false);
+    assert_eq!(*displayed, output);
+    assert!(output.pixels().all(|pixel: &Rgba<u8>| {
+        let hex = format!("{:02x}{:02x}{:02x}", pixel.0[0], pixel.0[1], pixel.0[2]);
+        effective_palette(&PALETTE_HEX).contains(&hex)
+    }));
+}
+
+#[wasm_bindgen_test]
+fn short_canvas_data_is_an_error() {
+    let mut sources = SourceCache::default();
+    assert!(sources.store(4, 4, vec![0; 4 * 4 * 4 - 1]).is_err());
+    assert!(sources.dimensions().is_none());
+}
+
+#[wasm_bindgen_test]
+async fn every_control_has_an_accessible_name() {
+    use std::time::Duration;
+    use wasm_bindgen::JsCast;
+    use web_sys::Element;
+
+    // the UI needs a DOM, so this only checks anything in a browser
+    let Some(document) = web_sys::window().and_then(|window| window.document()) else {
+        return;
+    };
+    let root = document.create_element("div").unwrap();
+    document.body().unwrap().append_child(&root).unwrap();
+    yew::Renderer::<App>::with_root(root.clone()).render();
+    yew::platform::time::sleep(Duration::from_millis(50)).await;
+
+    let controls = root
+        .query_selector_all("input, button, textarea, select")
+        .unwrap();
+    assert!(controls.length() > 0);
+    for i in 0..controls.length() {
+        let control: Element = controls.item(i).unwrap().dyn_into().unwrap();
+        let labelled = control
+            .get_attribute("aria-label")
+            .is_some_and(|label| !label.trim().is_empty())
+            || (!control.id().is_empty()
+                && root
+                    .query_selector(&format!("label[for=\"{}\"]", control.id()))
+                    .unwrap()
+                    .and_then(|label| label.text_content())
+                    .is_some_and(|text| !text.trim().is_empty()));
+        assert!(labelled, "{} has no accessible name", control.outer_html());
+    }
+}